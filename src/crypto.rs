@@ -0,0 +1,145 @@
+use std::convert::TryInto;
+
+use cashweb::auth_wrapper::{ParseError, ParsedAuthWrapper, SignatureScheme, VerifyError};
+use thiserror::Error;
+
+use crate::models::wrapper::AuthWrapper;
+
+/// Error associated with verification of authorization wrappers.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CryptoError {
+    #[error("failed to parse authorization wrapper: {0}")]
+    Parse(ParseError),
+    #[error("failed to verify authorization wrapper: {0}")]
+    Verify(VerifyError),
+}
+
+/// Signature schemes which are verified, other schemes are rejected as unsupported.
+pub const SUPPORTED_SIGNATURE_SCHEMES: [SignatureScheme; 1] = [SignatureScheme::Ecdsa];
+
+pub fn signature_scheme_name(scheme: SignatureScheme) -> &'static str {
+    match scheme {
+        SignatureScheme::Schnorr => "schnorr",
+        SignatureScheme::Ecdsa => "ecdsa",
+    }
+}
+
+/// Operations authorized by a signed deletion.
+///
+/// The operation is bound into the signed payload, so an authorization for one can't be replayed
+/// against another.
+pub const DELETE_PROFILE_OPERATION: &[u8] = b"delete-profile";
+pub const DELETE_MESSAGES_OPERATION: &[u8] = b"delete-messages";
+pub const DELETE_FEEDS_OPERATION: &[u8] = b"delete-feeds";
+
+/// Parse the payload of a signed deletion, the operation followed by a big-endian timestamp in
+/// milliseconds, returning the timestamp.
+///
+/// Deletions are only honoured if newer than the last one, so signed deletions can't be replayed.
+pub fn parse_deletion(payload: &[u8], operation: &[u8]) -> Option<i64> {
+    if !payload.starts_with(operation) {
+        return None;
+    }
+    let raw_timestamp = payload[operation.len()..].try_into().ok()?;
+    Some(i64::from_be_bytes(raw_timestamp))
+}
+
+/// Parse the authorization wrapper and verify its signature.
+///
+/// Parsing computes and checks the payload digest and dispatches on the signature scheme, only
+/// signatures in [`SUPPORTED_SIGNATURE_SCHEMES`] are verified.
+pub fn verify_auth_wrapper(wrapper: AuthWrapper) -> Result<ParsedAuthWrapper, CryptoError> {
+    let parsed_wrapper = wrapper.parse().map_err(CryptoError::Parse)?;
+    if !SUPPORTED_SIGNATURE_SCHEMES.contains(&parsed_wrapper.scheme) {
+        return Err(CryptoError::Verify(VerifyError::UnsupportedScheme));
+    }
+    parsed_wrapper.verify().map_err(CryptoError::Verify)?;
+    Ok(parsed_wrapper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cashweb::{
+        auth_wrapper::SignatureScheme,
+        secp256k1::{
+            key::{PublicKey, SecretKey},
+            Message, Secp256k1,
+        },
+    };
+    use ring::digest::{digest, SHA256};
+
+    fn sign_wrapper(payload: &[u8], scheme: SignatureScheme) -> AuthWrapper {
+        let context = Secp256k1::signing_only();
+        let private_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&context, &private_key);
+        let payload_digest = digest(&SHA256, payload);
+        let message = Message::from_slice(payload_digest.as_ref()).unwrap();
+        let signature = context.sign(&message, &private_key);
+
+        AuthWrapper {
+            public_key: public_key.serialize().to_vec(),
+            signature: signature.serialize_compact().to_vec(),
+            scheme: scheme as i32,
+            payload: payload.to_vec(),
+            payload_digest: payload_digest.as_ref().to_vec(),
+        }
+    }
+
+    #[test]
+    fn ecdsa_valid() {
+        let wrapper = sign_wrapper(b"profile", SignatureScheme::Ecdsa);
+        let parsed_wrapper = verify_auth_wrapper(wrapper).unwrap();
+        assert_eq!(parsed_wrapper.payload, b"profile".to_vec());
+    }
+
+    #[test]
+    fn ecdsa_invalid_signature() {
+        let wrapper = AuthWrapper {
+            payload: b"forged".to_vec(),
+            payload_digest: vec![],
+            ..sign_wrapper(b"profile", SignatureScheme::Ecdsa)
+        };
+        assert!(matches!(
+            verify_auth_wrapper(wrapper),
+            Err(CryptoError::Verify(VerifyError::InvalidSignature(_)))
+        ));
+    }
+
+    #[test]
+    fn fraudulent_digest() {
+        let wrapper = AuthWrapper {
+            payload: b"forged".to_vec(),
+            ..sign_wrapper(b"profile", SignatureScheme::Ecdsa)
+        };
+        assert_eq!(
+            verify_auth_wrapper(wrapper),
+            Err(CryptoError::Parse(ParseError::FraudulentDigest))
+        );
+    }
+
+    #[test]
+    fn deletion_payload() {
+        let payload = [DELETE_PROFILE_OPERATION, &100i64.to_be_bytes()].concat();
+        assert_eq!(
+            parse_deletion(&payload, DELETE_PROFILE_OPERATION),
+            Some(100)
+        );
+        assert_eq!(parse_deletion(&payload, b"delete-other"), None);
+        assert_eq!(
+            parse_deletion(DELETE_PROFILE_OPERATION, DELETE_PROFILE_OPERATION),
+            None
+        );
+        assert_eq!(parse_deletion(&[], DELETE_PROFILE_OPERATION), None);
+    }
+
+    #[test]
+    fn schnorr_unsupported() {
+        let wrapper = sign_wrapper(b"profile", SignatureScheme::Schnorr);
+        assert_eq!(
+            verify_auth_wrapper(wrapper),
+            Err(CryptoError::Verify(VerifyError::UnsupportedScheme))
+        );
+    }
+}
@@ -1,21 +1,105 @@
-use std::sync::Arc;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    convert::TryInto,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
 use cashweb::relay::*;
 use prost::Message as PMessage;
-use rocksdb::{Direction, Error as RocksError, IteratorMode, Options, DB};
+use rocksdb::{
+    checkpoint::Checkpoint, merge_operator::MergeOperands, BlockBasedOptions, Cache, ColumnFamily,
+    ColumnFamilyDescriptor, DBCompressionType, Direction, Error as RocksError, IteratorMode,
+    Options, WriteBatch, WriteOptions, DB,
+};
 
-use crate::models::wrapper::AuthWrapper;
+use crate::{
+    models::wrapper::AuthWrapper,
+    settings::{Compression, RocksDb},
+};
+
+#[cfg(feature = "monitoring")]
+use crate::monitoring;
 
 const DIGEST_LEN: usize = 4;
 const NAMESPACE_LEN: usize = 20 + 1;
 
+const ACK_NAMESPACE: u8 = b'a';
 const DIGEST_NAMESPACE: u8 = b'd';
 pub const FEED_NAMESPACE: u8 = b'f';
 pub const MESSAGE_NAMESPACE: u8 = b'm';
 const PROFILE_NAMESPACE: u8 = b'p';
+const DELETION_SUFFIX: u8 = b'x';
+
+const MESSAGES_CF: &str = "messages";
+const PROFILES_CF: &str = "profiles";
+const INDEX_CF: &str = "index";
+
+const COUNT_MERGE_OPERATOR: &str = "add_counts";
+
+/// Number of locks striped over addresses, see [`Database::lock_address`].
+const ADDRESS_LOCKS: usize = 64;
+/// Number of keys moved per write when migrating the legacy keyspace.
+const MIGRATION_BATCH_KEYS: usize = 1024;
+const INDEX_VERSION_KEY: &[u8] = b"version";
+const INDEX_VERSION: u8 = 1;
+const DERIVATION_INDEX_KEY: &[u8] = b"derivation_index";
 
 #[derive(Clone)]
-pub struct Database(Arc<DB>);
+pub struct Database {
+    db: Arc<DB>,
+    write_opts: Arc<WriteOptions>,
+    address_locks: Arc<Vec<Mutex<()>>>,
+}
+
+/// A serialized message to be stored under a public key hash.
+#[derive(Clone, Copy)]
+pub struct MessageEntry<'a> {
+    pub pubkey_hash: &'a [u8],
+    pub raw_message: &'a [u8],
+    pub digest: &'a [u8],
+}
+
+/// Outcome of a deletion authorized by a signed timestamp.
+#[derive(Debug, PartialEq)]
+pub enum SignedDeletion<T> {
+    /// Deleted, recording the timestamp in the same write.
+    Deleted(T),
+    /// Nothing matched, so the timestamp wasn't recorded.
+    NotFound,
+    /// A deletion at or after the timestamp was already recorded.
+    Outdated,
+}
+
+impl<T> SignedDeletion<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> SignedDeletion<U> {
+        match self {
+            Self::Deleted(deleted) => SignedDeletion::Deleted(f(deleted)),
+            Self::NotFound => SignedDeletion::NotFound,
+            Self::Outdated => SignedDeletion::Outdated,
+        }
+    }
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => Self::None,
+            Compression::Snappy => Self::Snappy,
+            Compression::Zlib => Self::Zlib,
+            Compression::Bz2 => Self::Bz2,
+            Compression::Lz4 => Self::Lz4,
+            Compression::Lz4hc => Self::Lz4hc,
+            Compression::Zstd => Self::Zstd,
+        }
+    }
+}
+
+/// Whether the error reports a corrupted database.
+pub fn is_corruption(err: &RocksError) -> bool {
+    err.as_ref().starts_with("Corruption")
+}
 
 pub fn msg_key(pubkey_hash: &[u8], timestamp: u64, digest: &[u8], namespace: u8) -> Vec<u8> {
     let raw_timestamp: [u8; 8] = timestamp.to_be_bytes();
@@ -33,12 +117,250 @@ pub fn msg_prefix(pubkey_hash: &[u8], timestamp: u64, namespace: u8) -> Vec<u8>
     [&pubkey_hash[..], &[namespace], &raw_timestamp].concat()
 }
 
+/// Key of the message count of a namespace in the index column family.
+///
+/// This is the prefix shared by the keys of the namespace's messages.
+fn count_key(pubkey_hash: &[u8], namespace: u8) -> Vec<u8> {
+    [pubkey_hash, &[namespace]].concat()
+}
+
+/// Key of the time of the last signed deletion in a namespace, in the index column family.
+fn deletion_key(pubkey_hash: &[u8], namespace: u8) -> Vec<u8> {
+    [pubkey_hash, &[namespace, DELETION_SUFFIX]].concat()
+}
+
+/// The time after which a key in the messages column family expires, if it does.
+///
+/// Digest keys map to the timestamp of their message, acks to the time of the ack which is never
+/// before the message.
+fn expiry_timestamp(key: &[u8], value: &[u8]) -> Option<u64> {
+    if key.len() <= NAMESPACE_LEN {
+        return None;
+    }
+    let namespace = key[NAMESPACE_LEN - 1];
+    let raw_timestamp = if namespace == DIGEST_NAMESPACE || namespace == ACK_NAMESPACE {
+        value
+    } else if namespace == MESSAGE_NAMESPACE || namespace == FEED_NAMESPACE {
+        &key[NAMESPACE_LEN..NAMESPACE_LEN + 8]
+    } else {
+        return None;
+    };
+    raw_timestamp.try_into().ok().map(u64::from_be_bytes)
+}
+
+fn decode_count(raw_count: &[u8]) -> i64 {
+    raw_count.try_into().map(i64::from_be_bytes).unwrap_or(0)
+}
+
+/// Merge operator summing the deltas applied to a message count.
+fn add_counts(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut MergeOperands,
+) -> Option<Vec<u8>> {
+    let count = operands.fold(existing.map_or(0, decode_count), |count, delta| {
+        count + decode_count(delta)
+    });
+    Some(count.to_be_bytes().to_vec())
+}
+
 impl Database {
     pub fn try_new(path: &str) -> Result<Self, RocksError> {
+        Self::try_new_tuned(path, &RocksDb::default())
+    }
+
+    /// Open the database, applying the tuning to the database and each column family.
+    pub fn try_new_tuned(path: &str, tuning: &RocksDb) -> Result<Self, RocksError> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        if let Some(max_open_files) = tuning.max_open_files {
+            opts.set_max_open_files(max_open_files);
+        }
+
+        // The block cache is shared between column families
+        let block_cache = tuning
+            .block_cache_size
+            .map(Cache::new_lru_cache)
+            .transpose()?;
+        let cf_opts = || {
+            let mut cf_opts = Options::default();
+            if let Some(write_buffer_size) = tuning.write_buffer_size {
+                cf_opts.set_write_buffer_size(write_buffer_size);
+            }
+            if let Some(compression) = tuning.compression {
+                cf_opts.set_compression_type(compression.into());
+            }
+            if let Some(block_cache) = &block_cache {
+                let mut block_opts = BlockBasedOptions::default();
+                block_opts.set_block_cache(block_cache);
+                cf_opts.set_block_based_table_factory(&block_opts);
+            }
+            cf_opts
+        };
+
+        let mut index_opts = cf_opts();
+        index_opts.set_merge_operator(COUNT_MERGE_OPERATOR, add_counts, Some(add_counts));
+
+        let cfs = vec![
+            ColumnFamilyDescriptor::new(MESSAGES_CF, cf_opts()),
+            ColumnFamilyDescriptor::new(PROFILES_CF, cf_opts()),
+            ColumnFamilyDescriptor::new(INDEX_CF, index_opts),
+        ];
+        let db = DB::open_cf_descriptors(&opts, &path, cfs)?;
+
+        // Syncing flushes the WAL to disk before each write returns, so acknowledged writes
+        // survive a machine crash rather than only a process crash
+        let mut write_opts = WriteOptions::default();
+        write_opts.set_sync(tuning.sync_writes);
+
+        let database = Database {
+            db: Arc::new(db),
+            write_opts: Arc::new(write_opts),
+            address_locks: Arc::new((0..ADDRESS_LOCKS).map(|_| Mutex::new(())).collect()),
+        };
+        database.migrate_default_cf()?;
+        database.build_index()?;
+        Ok(database)
+    }
+
+    /// Attempt to recover a corrupted database, salvaging as much data as possible.
+    pub fn repair(path: &str) -> Result<(), RocksError> {
+        DB::repair(&Options::default(), path)
+    }
+
+    /// Move keys from the single prefixed keyspace used by earlier versions into their column
+    /// families.
+    ///
+    /// Profiles are keyed by address payload alone while messages, feeds and digests keep their
+    /// keys. The default column family is left empty so this is a no-op after the first open.
+    ///
+    /// Keys are moved in batches of at most `MIGRATION_BATCH_KEYS`, each of which copies and
+    /// deletes its keys atomically, so an interrupted migration resumes on the next open.
+    fn migrate_default_cf(&self) -> Result<(), RocksError> {
+        let mut batch = WriteBatch::default();
+        let mut batch_keys = 0;
+        for (key, value) in self.db.iterator(IteratorMode::Start) {
+            if key.len() == NAMESPACE_LEN && key[NAMESPACE_LEN - 1] == PROFILE_NAMESPACE {
+                batch.put_cf(self.profiles_cf(), &key[..NAMESPACE_LEN - 1], value);
+            } else {
+                batch.put_cf(self.messages_cf(), &key, value);
+            }
+            batch.delete(key);
+
+            batch_keys += 1;
+            if batch_keys == MIGRATION_BATCH_KEYS {
+                self.db
+                    .write_opt(std::mem::take(&mut batch), &self.write_opts)?;
+                batch_keys = 0;
+            }
+        }
+        self.db.write_opt(batch, &self.write_opts)
+    }
+
+    /// Count the messages of each namespace, unless the index was already built.
+    ///
+    /// Counts are kept up to date by each write once built, so this only scans databases created
+    /// by earlier versions.
+    fn build_index(&self) -> Result<(), RocksError> {
+        if self
+            .db
+            .get_cf(self.index_cf(), INDEX_VERSION_KEY)?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let mut counts: HashMap<Vec<u8>, i64> = HashMap::new();
+        for (key, _) in self.db.iterator_cf(self.messages_cf(), IteratorMode::Start) {
+            if key.len() <= NAMESPACE_LEN {
+                continue;
+            }
+            let namespace = key[NAMESPACE_LEN - 1];
+            if namespace == MESSAGE_NAMESPACE || namespace == FEED_NAMESPACE {
+                *counts.entry(key[..NAMESPACE_LEN].to_vec()).or_default() += 1;
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        for (count_key, count) in counts {
+            batch.put_cf(self.index_cf(), count_key, count.to_be_bytes());
+        }
+        batch.put_cf(self.index_cf(), INDEX_VERSION_KEY, [INDEX_VERSION]);
+        self.db.write_opt(batch, &self.write_opts)
+    }
+
+    /// Adjust a message count within the batch.
+    fn add_count(&self, batch: &mut WriteBatch, count_key: &[u8], delta: i64) {
+        batch.merge_cf(self.index_cf(), count_key, delta.to_be_bytes());
+    }
+
+    /// Lock an address against concurrent read-modify-write updates.
+    ///
+    /// The locks are striped so unrelated addresses may share a lock. Hold the guard only while
+    /// reading and writing the address, and take any further addresses with
+    /// [`Database::lock_addresses`] instead.
+    pub fn lock_address(&self, addr: &[u8]) -> MutexGuard<'_, ()> {
+        self.lock_stripe(self.address_stripe(addr))
+    }
+
+    /// Lock several addresses at once, see [`Database::lock_address`].
+    ///
+    /// The stripes are taken in order so concurrent callers can't deadlock.
+    fn lock_addresses<'a>(
+        &self,
+        addrs: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = addrs
+            .into_iter()
+            .map(|addr| self.address_stripe(addr))
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| self.lock_stripe(stripe))
+            .collect()
+    }
+
+    fn address_stripe(&self, addr: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        hasher.finish() as usize % self.address_locks.len()
+    }
+
+    fn lock_stripe(&self, stripe: usize) -> MutexGuard<'_, ()> {
+        // The lock guards no data so a panic while held leaves nothing inconsistent
+        self.address_locks[stripe]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn messages_cf(&self) -> &ColumnFamily {
+        self.db.cf_handle(MESSAGES_CF).unwrap() // This is safe
+    }
+
+    fn profiles_cf(&self) -> &ColumnFamily {
+        self.db.cf_handle(PROFILES_CF).unwrap() // This is safe
+    }
+
+    fn index_cf(&self) -> &ColumnFamily {
+        self.db.cf_handle(INDEX_CF).unwrap() // This is safe
+    }
+
+    pub fn check(&self) -> Result<(), RocksError> {
+        self.db.get_cf(self.messages_cf(), []).map(|_| ())
+    }
 
-        DB::open(&opts, &path).map(Arc::new).map(Database)
+    /// Create a consistent checkpoint of the database at the given path, which must not exist.
+    ///
+    /// Files are hard linked where possible so writes are only briefly blocked while the memtable
+    /// is flushed.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("checkpoint");
+
+        Checkpoint::new(&self.db)?.create_checkpoint(path)
     }
 
     pub fn get_msg_key_by_digest(
@@ -49,25 +371,44 @@ impl Database {
     ) -> Result<Option<Vec<u8>>, RocksError> {
         let digest_key = [pubkey_hash, &[DIGEST_NAMESPACE], &digest].concat();
 
-        let opt_timestamp = self.0.get(digest_key)?;
+        let opt_timestamp = self.db.get_cf(self.messages_cf(), digest_key)?;
         Ok(opt_timestamp.map(|timestamp| {
             [pubkey_hash, &[namespace], &timestamp, &digest[..DIGEST_LEN]].concat()
         }))
     }
 
+    /// Remove a message by digest, authorized by a signed deletion at `deletion_time`.
     pub fn remove_message_by_digest(
         &self,
         pubkey_hash: &[u8],
         digest: &[u8],
         namespace: u8,
-    ) -> Result<Option<()>, RocksError> {
-        match self.get_msg_key_by_digest(pubkey_hash, digest, namespace)? {
-            Some(some) => {
-                self.0.delete(&some)?;
-                Ok(Some(()))
-            }
-            None => Ok(None),
+        deletion_time: i64,
+    ) -> Result<SignedDeletion<()>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("remove_message_by_digest");
+
+        // Hold the address so the count is adjusted against the stored messages
+        let _guard = self.lock_address(pubkey_hash);
+        if self.deletion_outdated(pubkey_hash, namespace, deletion_time)? {
+            return Ok(SignedDeletion::Outdated);
+        }
+        let msg_key = match self.get_msg_key_by_digest(pubkey_hash, digest, namespace)? {
+            Some(some) => some,
+            None => return Ok(SignedDeletion::NotFound),
+        };
+
+        // The digest key is shared between namespaces so is only removed with the message
+        let mut batch = WriteBatch::default();
+        if self.get_message_by_key(&msg_key)?.is_some() {
+            batch.delete_cf(self.messages_cf(), msg_key);
+            let digest_key = [pubkey_hash, &[DIGEST_NAMESPACE], digest].concat();
+            batch.delete_cf(self.messages_cf(), digest_key);
+            self.add_count(&mut batch, &count_key(pubkey_hash, namespace), -1);
         }
+        self.record_deletion(&mut batch, pubkey_hash, namespace, deletion_time);
+        self.db.write_opt(batch, &self.write_opts)?;
+        Ok(SignedDeletion::Deleted(()))
     }
 
     pub fn push_message(
@@ -78,23 +419,45 @@ impl Database {
         digest: &[u8],
         namespace: u8,
     ) -> Result<(), RocksError> {
-        // Create key
-        let raw_timestamp: [u8; 8] = timestamp.to_be_bytes();
-        let key = [
-            &pubkey_hash[..],
-            &[namespace],
-            &raw_timestamp,
-            &digest[..DIGEST_LEN],
-        ]
-        .concat();
-        self.0.put(key, raw_message)?;
+        let entry = MessageEntry {
+            pubkey_hash,
+            raw_message,
+            digest,
+        };
+        self.push_messages(timestamp, &[entry], namespace)
+    }
+
+    /// Push messages received at the same time in a single write, counting new messages.
+    pub fn push_messages(
+        &self,
+        timestamp: u64,
+        entries: &[MessageEntry],
+        namespace: u8,
+    ) -> Result<(), RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("push_message");
 
-        // Create digest key
-        let digest_key = [&pubkey_hash[..], &[DIGEST_NAMESPACE], &digest].concat();
+        // Hold the addresses until the write, so concurrent puts of the same message count it once
+        let _guards = self.lock_addresses(entries.iter().map(|entry| entry.pubkey_hash));
+
+        let raw_timestamp: [u8; 8] = timestamp.to_be_bytes();
+        let mut batch = WriteBatch::default();
+        let mut new_keys = HashSet::with_capacity(entries.len());
+        for entry in entries {
+            // Create key
+            let key = msg_key(entry.pubkey_hash, timestamp, entry.digest, namespace);
 
-        self.0.put(digest_key, raw_timestamp)?;
+            // Overwriting a stored message leaves the count unchanged
+            if self.get_message_by_key(&key)?.is_none() && new_keys.insert(key.clone()) {
+                self.add_count(&mut batch, &count_key(entry.pubkey_hash, namespace), 1);
+            }
+            batch.put_cf(self.messages_cf(), key, entry.raw_message);
 
-        Ok(())
+            // Create digest key
+            let digest_key = [entry.pubkey_hash, &[DIGEST_NAMESPACE], entry.digest].concat();
+            batch.put_cf(self.messages_cf(), digest_key, raw_timestamp);
+        }
+        self.db.write_opt(batch, &self.write_opts)
     }
 
     pub fn get_message_by_digest(
@@ -103,48 +466,98 @@ impl Database {
         digest: &[u8],
         namespace: u8,
     ) -> Result<Option<Vec<u8>>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("get_message_by_digest");
+
         match self.get_msg_key_by_digest(pubkey_hash, digest, namespace)? {
             Some(some) => self.get_message_by_key(&some),
             None => Ok(None),
         }
     }
 
+    /// Get the serialized messages with the given digests, omitting digests with no stored
+    /// message.
+    ///
+    /// The bundled RocksDB bindings don't expose `multi_get` so the keys are looked up in turn
+    /// against a single snapshot.
+    pub fn get_raw_messages_by_digests(
+        &self,
+        pubkey_hash: &[u8],
+        digests: &[Vec<u8>],
+        namespace: u8,
+    ) -> Result<Vec<Vec<u8>>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("get_raw_messages_by_digests");
+
+        let snapshot = self.db.snapshot();
+        let mut messages = Vec::with_capacity(digests.len());
+        for digest in digests {
+            let digest_key = [pubkey_hash, &[DIGEST_NAMESPACE], &digest].concat();
+            let timestamp = match snapshot.get_cf(self.messages_cf(), digest_key)? {
+                Some(some) => some,
+                None => continue,
+            };
+            let key = [pubkey_hash, &[namespace], &timestamp, &digest[..DIGEST_LEN]].concat();
+            if let Some(raw_message) = snapshot.get_cf(self.messages_cf(), key)? {
+                messages.push(raw_message);
+            }
+        }
+        Ok(messages)
+    }
+
     pub fn get_message_by_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, RocksError> {
-        self.0.get(key)
+        self.db.get_cf(self.messages_cf(), key)
     }
 
+    /// Get the messages in the namespace from the start key up to the end key.
+    ///
+    /// Message keys are `addr || namespace || timestamp || digest` with a big-endian timestamp, so
+    /// the messages column family is itself ordered by address then time and a range is a single
+    /// seek rather than a prefix scan. Counts are kept in the index column family.
     pub fn get_messages_range(
         &self,
         start_prefix: &[u8],
         opt_end_prefix: Option<&[u8]>,
-    ) -> Result<MessagePage, RocksError> {
+        opt_limit: Option<usize>,
+    ) -> Result<(MessagePage, bool), RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("get_messages_range");
+
         let namespace = &start_prefix[..NAMESPACE_LEN]; // addr || msg namespace byte
 
         // Check whether key is within namespace
         let in_namespace = |key: &[u8]| key[..NAMESPACE_LEN] == namespace[..];
 
-        // Init iterator
-        let iter = self
-            .0
-            .iterator(IteratorMode::From(&start_prefix, Direction::Forward));
+        // Check whether key is before end time
+        let before_end_key = |key: &[u8]| match opt_end_prefix {
+            Some(end_prefix) => key[NAMESPACE_LEN..] < end_prefix[NAMESPACE_LEN..],
+            None => true,
+        };
 
-        let messages: Vec<Message> = if let Some(end_prefix) = opt_end_prefix {
-            // Check whether key is before end time
-            let before_end_key = |key: &[u8]| key[NAMESPACE_LEN..] < end_prefix[NAMESPACE_LEN..];
+        // Take one more than the limit to detect whether more messages exist
+        let take_len = opt_limit.map_or(usize::MAX, |limit| limit.saturating_add(1));
 
-            // Take items inside namespace and before end time
-            iter.take_while(|(key, _)| in_namespace(key) && before_end_key(key))
-                .map(|(_, item)| {
-                    Message::decode(&item[..]).unwrap() // This panics if stored bytes are malformed
-                })
-                .collect()
-        } else {
-            // Take items inside namespace
-            iter.take_while(|(key, _)| in_namespace(key))
-                .map(|(_, item)| {
-                    Message::decode(&item[..]).unwrap() // This panics if stored bytes are malformed
-                })
-                .collect()
+        // Take items inside namespace and before end time
+        let mut messages: Vec<Message> = self
+            .db
+            .iterator_cf(
+                self.messages_cf(),
+                IteratorMode::From(&start_prefix, Direction::Forward),
+            )
+            .take_while(|(key, _)| in_namespace(key) && before_end_key(key))
+            .take(take_len)
+            .map(|(_, item)| {
+                Message::decode(&item[..]).unwrap() // This panics if stored bytes are malformed
+            })
+            .collect();
+
+        // Truncate to the limit
+        let has_more = match opt_limit {
+            Some(limit) if messages.len() > limit => {
+                messages.truncate(limit);
+                true
+            }
+            _ => false,
         };
 
         let mut message_page = MessagePage::default();
@@ -154,56 +567,346 @@ impl Database {
             message_page.start_digest = payload_digest.to_vec();
         }
         if let Some(message) = messages.last() {
-            message_page.start_time = message.received_time;
+            message_page.end_time = message.received_time;
             let payload_digest = message.digest().unwrap(); // This is safe
-            message_page.start_digest = payload_digest.to_vec();
+            message_page.end_digest = payload_digest.to_vec();
         }
         message_page.messages = messages;
-        Ok(message_page)
+        Ok((message_page, has_more))
     }
 
-    pub fn remove_messages_range(
+    /// Delete a range of messages, authorized by a signed deletion at `deletion_time`.
+    pub fn delete_messages_range(
         &self,
         start_prefix: &[u8],
         opt_end_prefix: Option<&[u8]>,
-    ) -> Result<(), RocksError> {
+        deletion_time: i64,
+    ) -> Result<SignedDeletion<u64>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("delete_messages_range");
+
         let namespace = &start_prefix[..NAMESPACE_LEN]; // addr || msg namespace byte
 
         // Check whether key is within namespace
         let in_namespace = |key: &[u8]| key[..NAMESPACE_LEN] == namespace[..];
 
-        // Init iterator
-        let iter = self
-            .0
-            .iterator(IteratorMode::From(&start_prefix, Direction::Forward));
+        // Check whether key is before end time
+        let before_end_key = |key: &[u8]| match opt_end_prefix {
+            Some(end_prefix) => key[NAMESPACE_LEN..] < end_prefix[NAMESPACE_LEN..],
+            None => true,
+        };
+
+        // Hold the address so the count is adjusted against the stored messages
+        let pubkey_hash = &namespace[..NAMESPACE_LEN - 1];
+        let _guard = self.lock_address(pubkey_hash);
+        if self.deletion_outdated(pubkey_hash, namespace[NAMESPACE_LEN - 1], deletion_time)? {
+            return Ok(SignedDeletion::Outdated);
+        }
+
+        // Take items inside namespace and before end time, along with their digest keys
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        for (key, item) in self
+            .db
+            .iterator_cf(
+                self.messages_cf(),
+                IteratorMode::From(&start_prefix, Direction::Forward),
+            )
+            .take_while(|(key, _)| in_namespace(key) && before_end_key(key))
+        {
+            // The digest of a malformed message is unknown, so only its key is removed
+            let opt_digest = Message::decode(&item[..])
+                .ok()
+                .and_then(|message| message.digest().ok());
+            if let Some(digest) = opt_digest {
+                // The digest key is shared between namespaces so is only removed with the
+                // message it points at, as in `remove_message_by_digest`
+                let digest_key = [pubkey_hash, &[DIGEST_NAMESPACE], &digest].concat();
+                let pointed_timestamp = self.db.get_cf(self.messages_cf(), &digest_key)?;
+                if pointed_timestamp.as_deref() == Some(&key[NAMESPACE_LEN..NAMESPACE_LEN + 8]) {
+                    batch.delete_cf(self.messages_cf(), digest_key);
+                }
+            }
+            batch.delete_cf(self.messages_cf(), key);
+            count += 1;
+        }
+        self.add_count(&mut batch, namespace, -(count as i64));
+        self.record_deletion(
+            &mut batch,
+            pubkey_hash,
+            namespace[NAMESPACE_LEN - 1],
+            deletion_time,
+        );
+        self.db.write_opt(batch, &self.write_opts)?;
+
+        Ok(SignedDeletion::Deleted(count))
+    }
+
+    /// Get the received time of the most recent message in the namespace.
+    pub fn get_last_message_time(
+        &self,
+        pubkey_hash: &[u8],
+        namespace: u8,
+    ) -> Result<Option<u64>, RocksError> {
+        let prefix = [pubkey_hash, &[namespace]].concat();
+        let upper_key = msg_key(pubkey_hash, u64::MAX, &[u8::MAX; DIGEST_LEN], namespace);
+
+        // The key of the last message in the namespace
+        let last_key = self
+            .db
+            .iterator_cf(
+                self.messages_cf(),
+                IteratorMode::From(&upper_key, Direction::Reverse),
+            )
+            .next()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(&prefix));
+        Ok(last_key.map(|key| {
+            let raw_timestamp = &key[NAMESPACE_LEN..NAMESPACE_LEN + 8];
+            u64::from_be_bytes(raw_timestamp.try_into().unwrap()) // This is safe
+        }))
+    }
+
+    pub fn count_messages(&self, pubkey_hash: &[u8], namespace: u8) -> Result<u64, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("count_messages");
+
+        let count = self
+            .db
+            .get_cf(self.index_cf(), count_key(pubkey_hash, namespace))?
+            .map_or(0, |raw_count| decode_count(&raw_count));
+        Ok(count.max(0) as u64)
+    }
+
+    fn property_int(&self, cf: Option<&ColumnFamily>, name: &str) -> Result<u64, RocksError> {
+        let value = match cf {
+            Some(cf) => self.db.property_int_value_cf(cf, name)?,
+            None => self.db.property_int_value(name)?,
+        };
+        Ok(value.unwrap_or(0))
+    }
+
+    /// Total size of the SST files across column families, in bytes.
+    pub fn total_size(&self) -> Result<u64, RocksError> {
+        let cfs = [
+            None,
+            Some(self.messages_cf()),
+            Some(self.profiles_cf()),
+            Some(self.index_cf()),
+        ];
+        cfs.iter().try_fold(0, |total, cf| {
+            Ok(total + self.property_int(*cf, "rocksdb.total-sst-files-size")?)
+        })
+    }
 
-        if let Some(end_prefix) = opt_end_prefix {
-            // Check whether key is before end time
-            let before_end_key = |key: &[u8]| key[NAMESPACE_LEN..] < end_prefix[NAMESPACE_LEN..];
+    /// Estimated number of keys in the messages column family.
+    ///
+    /// This includes the digest and acknowledgement keys alongside the messages.
+    pub fn estimate_message_keys(&self) -> Result<u64, RocksError> {
+        self.property_int(Some(self.messages_cf()), "rocksdb.estimate-num-keys")
+    }
 
-            // Take items inside namespace and before end time
-            let iter = iter.take_while(|(key, _)| in_namespace(key) && before_end_key(key));
+    /// Estimated number of stored profiles.
+    pub fn estimate_profile_keys(&self) -> Result<u64, RocksError> {
+        self.property_int(Some(self.profiles_cf()), "rocksdb.estimate-num-keys")
+    }
 
-            for (key, _) in iter {
-                self.0.delete(key)?;
+    /// The largest number of messages stored under a single address, across namespaces.
+    ///
+    /// This scans the message counts, one key per address and namespace.
+    pub fn largest_message_count(&self) -> Result<u64, RocksError> {
+        let mut largest = 0;
+        let mut current: Option<(Vec<u8>, i64)> = None;
+        for (key, value) in self.db.iterator_cf(self.index_cf(), IteratorMode::Start) {
+            if key.len() != NAMESPACE_LEN {
+                continue;
             }
-        } else {
-            // Take items inside namespace
-            let iter = iter.take_while(|(key, _)| in_namespace(key));
+            let count = decode_count(&value).max(0);
+            match &mut current {
+                Some((pubkey_hash, total)) if pubkey_hash[..] == key[..20] => *total += count,
+                _ => {
+                    if let Some((_, total)) = current {
+                        largest = largest.max(total);
+                    }
+                    current = Some((key[..20].to_vec(), count));
+                }
+            }
+        }
+        if let Some((_, total)) = current {
+            largest = largest.max(total);
+        }
+        Ok(largest as u64)
+    }
+
+    /// Get the next index to derive payment addresses from.
+    pub fn get_derivation_index(&self) -> Result<u32, RocksError> {
+        let index = self
+            .db
+            .get_cf(self.index_cf(), DERIVATION_INDEX_KEY)?
+            .map_or(0, |raw_index| {
+                u32::from_be_bytes(raw_index[..].try_into().unwrap()) // This is safe
+            });
+        Ok(index)
+    }
+
+    pub fn put_derivation_index(&self, index: u32) -> Result<(), RocksError> {
+        self.db.put_cf_opt(
+            self.index_cf(),
+            DERIVATION_INDEX_KEY,
+            index.to_be_bytes(),
+            &self.write_opts,
+        )
+    }
+
+    pub fn delete_expired_messages(&self, cutoff_timestamp: u64) -> Result<u64, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("delete_expired_messages");
 
-            for (key, _) in iter {
-                self.0.delete(key)?;
+        // Keys are ordered by address, so the expired keys are deleted an address at a time
+        let mut expired_keys: Vec<Box<[u8]>> = Vec::new();
+        let mut count = 0;
+        for (key, value) in self.db.iterator_cf(self.messages_cf(), IteratorMode::Start) {
+            match expiry_timestamp(&key, &value) {
+                Some(timestamp) if timestamp < cutoff_timestamp => {}
+                _ => continue,
             }
+            let same_address = expired_keys.first().map_or(true, |first_key| {
+                first_key[..NAMESPACE_LEN - 1] == key[..NAMESPACE_LEN - 1]
+            });
+            if !same_address {
+                count += self.delete_expired_keys(&expired_keys, cutoff_timestamp)?;
+                expired_keys.clear();
+            }
+            expired_keys.push(key);
+        }
+        count += self.delete_expired_keys(&expired_keys, cutoff_timestamp)?;
+
+        Ok(count)
+    }
+
+    /// Delete the expired keys of a single address, returning the number of messages deleted.
+    fn delete_expired_keys(
+        &self,
+        keys: &[Box<[u8]>],
+        cutoff_timestamp: u64,
+    ) -> Result<u64, RocksError> {
+        let pubkey_hash = match keys.first() {
+            Some(first_key) => &first_key[..NAMESPACE_LEN - 1],
+            None => return Ok(0),
         };
 
-        Ok(())
+        // Hold the address so the count is adjusted against the stored messages, which may have
+        // changed since they were read
+        let _guard = self.lock_address(pubkey_hash);
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        for key in keys {
+            let value = match self.db.get_cf(self.messages_cf(), key)? {
+                Some(some) => some,
+                None => continue,
+            };
+            match expiry_timestamp(key, &value) {
+                Some(timestamp) if timestamp < cutoff_timestamp => {}
+                _ => continue,
+            }
+            batch.delete_cf(self.messages_cf(), key);
+            let namespace = key[NAMESPACE_LEN - 1];
+            if namespace == MESSAGE_NAMESPACE || namespace == FEED_NAMESPACE {
+                self.add_count(&mut batch, &key[..NAMESPACE_LEN], -1);
+                count += 1;
+            }
+        }
+        self.db.write_opt(batch, &self.write_opts)?;
+
+        Ok(count)
+    }
+
+    /// Acknowledge a message, returning `None` if there is no such message.
+    pub fn ack_message(
+        &self,
+        pubkey_hash: &[u8],
+        digest: &[u8],
+        timestamp: u64,
+    ) -> Result<Option<()>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("ack_message");
+
+        if self
+            .get_message_by_digest(pubkey_hash, digest, MESSAGE_NAMESPACE)?
+            .is_none()
+        {
+            return Ok(None);
+        }
+
+        let ack_key = [pubkey_hash, &[ACK_NAMESPACE], digest].concat();
+        self.db.put_cf_opt(
+            self.messages_cf(),
+            ack_key,
+            timestamp.to_be_bytes(),
+            &self.write_opts,
+        )?;
+        Ok(Some(()))
+    }
+
+    /// Remove messages acknowledged before the cutoff, along with their digest and ack keys.
+    pub fn delete_acked_messages(&self, cutoff_timestamp: u64) -> Result<u64, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("delete_acked_messages");
+
+        let mut count = 0;
+        for (key, value) in self.db.iterator_cf(self.messages_cf(), IteratorMode::Start) {
+            if key.len() <= NAMESPACE_LEN || key[NAMESPACE_LEN - 1] != ACK_NAMESPACE {
+                continue;
+            }
+            let timestamp = u64::from_be_bytes(value[..].try_into().unwrap()); // This is safe
+            if timestamp >= cutoff_timestamp {
+                continue;
+            }
+
+            let pubkey_hash = &key[..NAMESPACE_LEN - 1];
+            let digest = &key[NAMESPACE_LEN..];
+
+            // Hold the address so the count is adjusted against the stored messages
+            let _guard = self.lock_address(pubkey_hash);
+            let mut batch = WriteBatch::default();
+            if let Some(msg_key) =
+                self.get_msg_key_by_digest(pubkey_hash, digest, MESSAGE_NAMESPACE)?
+            {
+                if self.get_message_by_key(&msg_key)?.is_some() {
+                    batch.delete_cf(self.messages_cf(), msg_key);
+                    self.add_count(&mut batch, &count_key(pubkey_hash, MESSAGE_NAMESPACE), -1);
+                    count += 1;
+                }
+                let digest_key = [pubkey_hash, &[DIGEST_NAMESPACE], digest].concat();
+                batch.delete_cf(self.messages_cf(), digest_key);
+            }
+            batch.delete_cf(self.messages_cf(), &key);
+            self.db.write_opt(batch, &self.write_opts)?;
+        }
+
+        Ok(count)
     }
 
     pub fn get_raw_profile(&self, addr: &[u8]) -> Result<Option<Vec<u8>>, RocksError> {
-        // Prefix key
-        let key = [addr, &[PROFILE_NAMESPACE]].concat();
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("get_raw_profile");
 
-        self.0.get(key)
+        self.db.get_cf(self.profiles_cf(), addr)
+    }
+
+    /// Get the profiles of the given addresses, in order.
+    ///
+    /// The keys are looked up in turn against a single snapshot, as in
+    /// [`Database::get_raw_messages_by_digests`].
+    pub fn get_raw_profiles(&self, addrs: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("get_raw_profiles");
+
+        let snapshot = self.db.snapshot();
+        addrs
+            .iter()
+            .map(|addr| snapshot.get_cf(self.profiles_cf(), addr))
+            .collect()
     }
 
     pub fn get_profile(&self, addr: &[u8]) -> Result<Option<AuthWrapper>, RocksError> {
@@ -215,10 +918,113 @@ impl Database {
     }
 
     pub fn put_profile(&self, addr: &[u8], raw_profile: &[u8]) -> Result<(), RocksError> {
-        // Prefix key
-        let key = [addr, &[PROFILE_NAMESPACE]].concat();
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("put_profile");
+
+        self.db
+            .put_cf_opt(self.profiles_cf(), addr, raw_profile, &self.write_opts)
+    }
+
+    /// List the address payloads with a stored profile, in key order, starting after the cursor.
+    pub fn list_profiles(
+        &self,
+        opt_cursor: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<Vec<u8>>, bool), RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("list_profiles");
+
+        // Start strictly after the cursor
+        let start_key = match opt_cursor {
+            Some(cursor) => [cursor, &[0]].concat(),
+            None => vec![],
+        };
+
+        // Take one more than the limit to detect whether more profiles exist
+        let mut addrs: Vec<Vec<u8>> = self
+            .db
+            .iterator_cf(
+                self.profiles_cf(),
+                IteratorMode::From(&start_key, Direction::Forward),
+            )
+            .map(|(key, _)| key.to_vec())
+            .take(limit.saturating_add(1))
+            .collect();
+
+        // Truncate to the limit
+        let has_more = addrs.len() > limit;
+        addrs.truncate(limit);
+
+        Ok((addrs, has_more))
+    }
+
+    /// Delete the profile, recording the time of the signed deletion.
+    ///
+    /// Callers check the deletion is newer than the profile while holding the address, see
+    /// [`Database::lock_address`].
+    pub fn delete_profile(&self, addr: &[u8], timestamp: i64) -> Result<Option<()>, RocksError> {
+        #[cfg(feature = "monitoring")]
+        let _timer = monitoring::db_timer("delete_profile");
+
+        match self.db.get_cf(self.profiles_cf(), addr)? {
+            Some(_) => {
+                let mut batch = WriteBatch::default();
+                batch.delete_cf(self.profiles_cf(), addr);
+                batch.put_cf(
+                    self.index_cf(),
+                    deletion_key(addr, PROFILE_NAMESPACE),
+                    timestamp.to_be_bytes(),
+                );
+                self.db.write_opt(batch, &self.write_opts)?;
+                Ok(Some(()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the time of the last signed deletion of the address's profile.
+    pub fn get_profile_deletion_time(&self, addr: &[u8]) -> Result<Option<i64>, RocksError> {
+        self.get_deletion_time(addr, PROFILE_NAMESPACE)
+    }
+
+    fn get_deletion_time(
+        &self,
+        pubkey_hash: &[u8],
+        namespace: u8,
+    ) -> Result<Option<i64>, RocksError> {
+        let opt_raw_timestamp = self
+            .db
+            .get_cf(self.index_cf(), deletion_key(pubkey_hash, namespace))?;
+        Ok(opt_raw_timestamp.map(|raw_timestamp| decode_count(&raw_timestamp)))
+    }
+
+    /// Whether a signed deletion of messages at or after the timestamp was already recorded, so
+    /// each signed deletion is only honoured once.
+    ///
+    /// The address must be locked until the deletion is recorded.
+    fn deletion_outdated(
+        &self,
+        pubkey_hash: &[u8],
+        namespace: u8,
+        timestamp: i64,
+    ) -> Result<bool, RocksError> {
+        let opt_last_timestamp = self.get_deletion_time(pubkey_hash, namespace)?;
+        Ok(opt_last_timestamp.map_or(false, |last_timestamp| timestamp <= last_timestamp))
+    }
 
-        self.0.put(key, raw_profile)
+    /// Record the time of a signed deletion of messages within the batch.
+    fn record_deletion(
+        &self,
+        batch: &mut WriteBatch,
+        pubkey_hash: &[u8],
+        namespace: u8,
+        timestamp: i64,
+    ) {
+        batch.put_cf(
+            self.index_cf(),
+            deletion_key(pubkey_hash, namespace),
+            timestamp.to_be_bytes(),
+        );
     }
 }
 
@@ -285,10 +1091,12 @@ mod tests {
             .unwrap()
             .is_some());
 
-        assert!(database
-            .remove_message_by_digest(&address_payload, digest.as_ref(), MESSAGE_NAMESPACE)
-            .unwrap()
-            .is_some());
+        assert_eq!(
+            database
+                .remove_message_by_digest(&address_payload, digest.as_ref(), MESSAGE_NAMESPACE, 1)
+                .unwrap(),
+            SignedDeletion::Deleted(())
+        );
 
         assert!(database
             .get_message_by_digest(&address_payload, digest.as_ref(), MESSAGE_NAMESPACE)
@@ -297,31 +1105,291 @@ mod tests {
     }
 
     #[test]
-    fn get_time_range() {
-        let database = Database::try_new("./test_dbs/get_time_range").unwrap();
+    fn get_digests() {
+        let database = Database::try_new("./test_dbs/get_digests").unwrap();
 
         let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
         let address_payload = addr.as_body();
 
-        let mut message = Message::default();
-        message.payload_digest = vec![0; 32];
-        let mut raw_message = Vec::with_capacity(message.encoded_len());
-        message.encode(&mut raw_message).unwrap();
-        let digest = digest(&SHA256, &raw_message);
+        let mut digests = Vec::new();
+        for received_time in 0..3 {
+            let message = Message {
+                received_time,
+                ..Default::default()
+            };
+            let mut raw_message = Vec::with_capacity(message.encoded_len());
+            message.encode(&mut raw_message).unwrap();
+            let digest = digest(&SHA256, &raw_message).as_ref().to_vec();
+            database
+                .push_message(
+                    &address_payload,
+                    received_time as u64,
+                    &raw_message[..],
+                    &digest,
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+            digests.push(digest);
+        }
 
-        // Put at 100 and 105
-        database
-            .push_message(
-                &address_payload,
-                100,
-                &raw_message[..],
-                digest.as_ref(),
-                MESSAGE_NAMESPACE,
-            )
+        // Query the first and last message along with a missing digest
+        let query = vec![digests[2].clone(), vec![0; 32], digests[0].clone()];
+        let raw_messages = database
+            .get_raw_messages_by_digests(&address_payload, &query, MESSAGE_NAMESPACE)
             .unwrap();
-        database
-            .push_message(
-                &address_payload,
+        let received_times: Vec<_> = raw_messages
+            .iter()
+            .map(|raw_message| Message::decode(&raw_message[..]).unwrap().received_time)
+            .collect();
+        assert_eq!(received_times, vec![2, 0]);
+    }
+
+    #[test]
+    fn largest_message_count() {
+        let path = "./test_dbs/largest_message_count";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let push = |pubkey_hash: &[u8], timestamp: u64, namespace: u8| {
+            let digest = digest(&SHA256, &timestamp.to_be_bytes());
+            database
+                .push_message(pubkey_hash, timestamp, &[], digest.as_ref(), namespace)
+                .unwrap();
+        };
+        push(&[1; 20], 0, MESSAGE_NAMESPACE);
+        push(&[2; 20], 1, MESSAGE_NAMESPACE);
+        push(&[2; 20], 2, FEED_NAMESPACE);
+        push(&[3; 20], 3, MESSAGE_NAMESPACE);
+
+        // Counts of each namespace are summed per address
+        assert_eq!(database.largest_message_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn delete_profile() {
+        let database = Database::try_new("./test_dbs/delete_profile").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let address_payload = addr.as_body();
+
+        let profile = AuthWrapper::default();
+        let mut raw_profile = Vec::with_capacity(profile.encoded_len());
+        profile.encode(&mut raw_profile).unwrap();
+
+        database
+            .put_profile(&address_payload, &raw_profile)
+            .unwrap();
+
+        assert!(database
+            .delete_profile(&address_payload, 100)
+            .unwrap()
+            .is_some());
+
+        assert!(database
+            .get_raw_profile(&address_payload)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            database
+                .get_profile_deletion_time(&address_payload)
+                .unwrap(),
+            Some(100)
+        );
+
+        assert!(database
+            .delete_profile(&address_payload, 200)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn record_deletion() {
+        let path = "./test_dbs/record_deletion";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+        database
+            .push_message(&[1; 20], 100, &[0], &[0; 32], MESSAGE_NAMESPACE)
+            .unwrap();
+        let prefix = msg_prefix(&[1; 20], 0, MESSAGE_NAMESPACE);
+
+        // Missing messages don't use up the deletion time
+        assert_eq!(
+            database
+                .remove_message_by_digest(&[1; 20], &[1; 32], MESSAGE_NAMESPACE, 100)
+                .unwrap(),
+            SignedDeletion::NotFound
+        );
+        assert_eq!(
+            database.delete_messages_range(&prefix, None, 100).unwrap(),
+            SignedDeletion::Deleted(1)
+        );
+
+        // Replayed and older deletions are refused, other namespaces are separate
+        assert_eq!(
+            database.delete_messages_range(&prefix, None, 100).unwrap(),
+            SignedDeletion::Outdated
+        );
+        assert_eq!(
+            database
+                .remove_message_by_digest(&[1; 20], &[0; 32], MESSAGE_NAMESPACE, 50)
+                .unwrap(),
+            SignedDeletion::Outdated
+        );
+        let feed_prefix = msg_prefix(&[1; 20], 0, FEED_NAMESPACE);
+        assert_eq!(
+            database
+                .delete_messages_range(&feed_prefix, None, 100)
+                .unwrap(),
+            SignedDeletion::Deleted(0)
+        );
+        assert_eq!(
+            database.delete_messages_range(&prefix, None, 200).unwrap(),
+            SignedDeletion::Deleted(0)
+        );
+
+        // Deletion times aren't counted as messages
+        assert_eq!(database.largest_message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn list_profiles() {
+        let database = Database::try_new("./test_dbs/list_profiles").unwrap();
+
+        // Put profiles and a message which shouldn't be listed
+        for addr in &[[1; 20], [2; 20], [3; 20]] {
+            database.put_profile(addr, &[0]).unwrap();
+        }
+        database
+            .push_message(&[1; 20], 100, &[0], &[0; 32], MESSAGE_NAMESPACE)
+            .unwrap();
+
+        let (addrs, has_more) = database.list_profiles(None, 2).unwrap();
+        assert_eq!(addrs, vec![vec![1; 20], vec![2; 20]]);
+        assert!(has_more);
+
+        let (addrs, has_more) = database.list_profiles(Some(&[2; 20]), 2).unwrap();
+        assert_eq!(addrs, vec![vec![3; 20]]);
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn delete_acked() {
+        let database = Database::try_new("./test_dbs/delete_acked").unwrap();
+
+        let entries = [[1; 32], [2; 32]];
+        for digest in &entries {
+            database
+                .push_message(&[1; 20], 100, &[0], digest, MESSAGE_NAMESPACE)
+                .unwrap();
+        }
+
+        // Only existing messages can be acknowledged
+        assert!(database
+            .ack_message(&[1; 20], &[3; 32], 200)
+            .unwrap()
+            .is_none());
+        assert!(database
+            .ack_message(&[1; 20], &[1; 32], 200)
+            .unwrap()
+            .is_some());
+
+        // Acks after the cutoff are kept
+        assert_eq!(database.delete_acked_messages(200).unwrap(), 0);
+        assert_eq!(database.delete_acked_messages(201).unwrap(), 1);
+        assert!(database
+            .get_msg_key_by_digest(&[1; 20], &[1; 32], MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_none());
+        assert!(database
+            .get_message_by_digest(&[1; 20], &[2; 32], MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn migrate_default_cf() {
+        let path = "./test_dbs/migrate_default_cf";
+        let _ = std::fs::remove_dir_all(path);
+
+        // Write keys in the legacy prefixed layout
+        {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            let legacy = DB::open(&opts, path).unwrap();
+            legacy
+                .put([&[1; 20][..], &[PROFILE_NAMESPACE]].concat(), [0])
+                .unwrap();
+            let key = msg_key(&[1; 20], 100, &[2; 32], MESSAGE_NAMESPACE);
+            legacy.put(key, [1]).unwrap();
+            let digest_key = [&[1; 20][..], &[DIGEST_NAMESPACE], &[2; 32]].concat();
+            legacy.put(digest_key, 100u64.to_be_bytes()).unwrap();
+
+            // Spill over several batches
+            for timestamp in 0..MIGRATION_BATCH_KEYS as u64 {
+                let key = msg_key(&[3; 20], timestamp, &[4; 32], FEED_NAMESPACE);
+                legacy.put(key, [2]).unwrap();
+            }
+        }
+
+        let database = Database::try_new(path).unwrap();
+        assert_eq!(database.get_raw_profile(&[1; 20]).unwrap(), Some(vec![0]));
+        assert_eq!(
+            database
+                .get_message_by_digest(&[1; 20], &[2; 32], MESSAGE_NAMESPACE)
+                .unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(
+            database
+                .db
+                .iterator_cf(database.messages_cf(), IteratorMode::Start)
+                .count(),
+            MIGRATION_BATCH_KEYS + 2
+        );
+        assert_eq!(database.db.iterator(IteratorMode::Start).count(), 0);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let database = Database::try_new("./test_dbs/checkpoint").unwrap();
+        database.put_profile(&[1; 20], &[0]).unwrap();
+
+        // Checkpoints can't overwrite existing directories
+        let backup_path = "./test_dbs/checkpoint_backup";
+        let _ = std::fs::remove_dir_all(backup_path);
+        database.checkpoint(backup_path).unwrap();
+        assert!(database.checkpoint(backup_path).is_err());
+
+        let backup = Database::try_new(backup_path).unwrap();
+        assert_eq!(backup.get_raw_profile(&[1; 20]).unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn get_time_range() {
+        let database = Database::try_new("./test_dbs/get_time_range").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let address_payload = addr.as_body();
+
+        let mut message = Message::default();
+        message.payload_digest = vec![0; 32];
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = digest(&SHA256, &raw_message);
+
+        // Put at 100 and 105
+        database
+            .push_message(
+                &address_payload,
+                100,
+                &raw_message[..],
+                digest.as_ref(),
+                MESSAGE_NAMESPACE,
+            )
+            .unwrap();
+        database
+            .push_message(
+                &address_payload,
                 105,
                 &raw_message[..],
                 digest.as_ref(),
@@ -332,7 +1400,11 @@ mod tests {
         // Check out of range [106, inf)
         let prefix = msg_prefix(&address_payload, 106, MESSAGE_NAMESPACE);
         assert_eq!(
-            database.get_messages_range(&prefix, None).unwrap().messages,
+            database
+                .get_messages_range(&prefix, None, None)
+                .unwrap()
+                .0
+                .messages,
             vec![]
         );
 
@@ -340,8 +1412,9 @@ mod tests {
         let prefix = msg_prefix(&address_payload, 100, MESSAGE_NAMESPACE);
         assert_eq!(
             database
-                .get_messages_range(&prefix, None)
+                .get_messages_range(&prefix, None, None)
                 .unwrap()
+                .0
                 .messages
                 .len(),
             2
@@ -352,8 +1425,9 @@ mod tests {
         let prefix_end = msg_prefix(&address_payload, 101, MESSAGE_NAMESPACE);
         assert_eq!(
             database
-                .get_messages_range(&prefix, Some(&prefix_end))
+                .get_messages_range(&prefix, Some(&prefix_end), None)
                 .unwrap()
+                .0
                 .messages
                 .len(),
             1
@@ -364,11 +1438,449 @@ mod tests {
         let prefix_end = msg_prefix(&address_payload, 105, MESSAGE_NAMESPACE);
         assert_eq!(
             database
-                .get_messages_range(&prefix, Some(&prefix_end))
+                .get_messages_range(&prefix, Some(&prefix_end), None)
                 .unwrap()
+                .0
                 .messages
                 .len(),
             0
         )
     }
+
+    #[test]
+    fn count_messages() {
+        let database = Database::try_new("./test_dbs/count_messages").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let address_payload = addr.as_body();
+
+        let message = Message {
+            payload_digest: vec![0; 32],
+            ..Default::default()
+        };
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = digest(&SHA256, &raw_message);
+
+        // Put three messages and one feed
+        for timestamp in 100..103 {
+            database
+                .push_message(
+                    address_payload,
+                    timestamp,
+                    &raw_message[..],
+                    digest.as_ref(),
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+        }
+        database
+            .push_message(
+                address_payload,
+                100,
+                &raw_message[..],
+                digest.as_ref(),
+                FEED_NAMESPACE,
+            )
+            .unwrap();
+
+        assert_eq!(
+            database
+                .count_messages(address_payload, MESSAGE_NAMESPACE)
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            database
+                .count_messages(address_payload, FEED_NAMESPACE)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn count_index() {
+        let path = "./test_dbs/count_index";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let push_message = |timestamp: u64| {
+            let message = Message {
+                received_time: timestamp as i64,
+                payload: vec![timestamp as u8],
+                ..Default::default()
+            };
+            let mut raw_message = Vec::with_capacity(message.encoded_len());
+            message.encode(&mut raw_message).unwrap();
+            let digest = message.digest().unwrap();
+            database
+                .push_message(
+                    &[1; 20],
+                    timestamp,
+                    &raw_message[..],
+                    &digest,
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+            digest
+        };
+        let count = || {
+            database
+                .count_messages(&[1; 20], MESSAGE_NAMESPACE)
+                .unwrap()
+        };
+
+        // Pushing a stored message again doesn't change the count
+        let first_digest = push_message(100);
+        let second_digest = push_message(200);
+        push_message(100);
+        assert_eq!(count(), 2);
+
+        // Removing a message removes its digest key
+        database
+            .remove_message_by_digest(&[1; 20], &first_digest, MESSAGE_NAMESPACE, 1)
+            .unwrap();
+        assert_eq!(count(), 1);
+        assert!(database
+            .get_msg_key_by_digest(&[1; 20], &first_digest, MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_none());
+
+        let prefix = msg_prefix(&[1; 20], 0, MESSAGE_NAMESPACE);
+        assert_eq!(
+            database.delete_messages_range(&prefix, None, 2).unwrap(),
+            SignedDeletion::Deleted(1)
+        );
+        assert_eq!(count(), 0);
+        assert!(database
+            .get_msg_key_by_digest(&[1; 20], &second_digest, MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_none());
+
+        // Counts are rebuilt for databases without an index
+        push_message(300);
+        let index_cf = database.index_cf();
+        database.db.delete_cf(index_cf, INDEX_VERSION_KEY).unwrap();
+        database
+            .db
+            .delete_cf(index_cf, count_key(&[1; 20], MESSAGE_NAMESPACE))
+            .unwrap();
+        assert_eq!(count(), 0);
+        database.build_index().unwrap();
+        assert_eq!(count(), 1);
+    }
+
+    #[test]
+    fn count_index_concurrent() {
+        let path = "./test_dbs/count_index_concurrent";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let message = Message {
+            received_time: 100,
+            ..Default::default()
+        };
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = message.digest().unwrap();
+
+        // Concurrent puts of the same message count it once
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let database = database.clone();
+                let raw_message = raw_message.clone();
+                std::thread::spawn(move || {
+                    database
+                        .push_message(&[1; 20], 100, &raw_message, &digest, MESSAGE_NAMESPACE)
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(
+            database
+                .count_messages(&[1; 20], MESSAGE_NAMESPACE)
+                .unwrap(),
+            1
+        );
+
+        // Expiring and removing the same message concurrently uncounts it once
+        database
+            .push_message(&[2; 20], 100, &raw_message, &digest, MESSAGE_NAMESPACE)
+            .unwrap();
+        let remove_handle = {
+            let database = database.clone();
+            std::thread::spawn(move || {
+                database
+                    .remove_message_by_digest(&[1; 20], &digest, MESSAGE_NAMESPACE, 1)
+                    .unwrap()
+            })
+        };
+        let expire_handles: Vec<_> = (0..4)
+            .map(|_| {
+                let database = database.clone();
+                std::thread::spawn(move || database.delete_expired_messages(200).unwrap())
+            })
+            .collect();
+        remove_handle.join().unwrap();
+        for handle in expire_handles {
+            handle.join().unwrap();
+        }
+        for addr in &[[1; 20], [2; 20]] {
+            // Read the stored count, which `count_messages` would clamp at zero
+            let raw_count = database
+                .db
+                .get_cf(database.index_cf(), count_key(addr, MESSAGE_NAMESPACE))
+                .unwrap()
+                .unwrap();
+            assert_eq!(decode_count(&raw_count), 0);
+        }
+    }
+
+    #[test]
+    fn delete_range_keeps_other_digest_keys() {
+        let path = "./test_dbs/delete_range_keeps_other_digest_keys";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let message = Message {
+            received_time: 100,
+            ..Default::default()
+        };
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = message.digest().unwrap();
+
+        // The same message stored again later moves its digest key to the later copy
+        database
+            .push_message(&[1; 20], 100, &raw_message, &digest, MESSAGE_NAMESPACE)
+            .unwrap();
+        database
+            .push_message(&[1; 20], 300, &raw_message, &digest, MESSAGE_NAMESPACE)
+            .unwrap();
+
+        // Malformed messages are removed without panicking
+        let malformed_key = msg_key(&[1; 20], 150, &[0xff; DIGEST_LEN], MESSAGE_NAMESPACE);
+        database
+            .db
+            .put_cf(database.messages_cf(), malformed_key, [0xff])
+            .unwrap();
+
+        let start_prefix = msg_prefix(&[1; 20], 0, MESSAGE_NAMESPACE);
+        let end_prefix = msg_prefix(&[1; 20], 200, MESSAGE_NAMESPACE);
+        assert_eq!(
+            database
+                .delete_messages_range(&start_prefix, Some(&end_prefix), 1)
+                .unwrap(),
+            SignedDeletion::Deleted(2)
+        );
+        assert!(database
+            .get_message_by_digest(&[1; 20], &digest, MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn get_limited_range() {
+        let database = Database::try_new("./test_dbs/get_limited_range").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let address_payload = addr.as_body();
+
+        let message = Message {
+            payload_digest: vec![0; 32],
+            ..Default::default()
+        };
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = digest(&SHA256, &raw_message);
+
+        // Put at 100, 101 and 102
+        for timestamp in 100..103 {
+            database
+                .push_message(
+                    address_payload,
+                    timestamp,
+                    &raw_message[..],
+                    digest.as_ref(),
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+        }
+
+        // Check limit below count
+        let prefix = msg_prefix(address_payload, 100, MESSAGE_NAMESPACE);
+        let (message_page, has_more) = database.get_messages_range(&prefix, None, Some(2)).unwrap();
+        assert_eq!(message_page.messages.len(), 2);
+        assert!(has_more);
+
+        // Check limit equal to count
+        let (message_page, has_more) = database.get_messages_range(&prefix, None, Some(3)).unwrap();
+        assert_eq!(message_page.messages.len(), 3);
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn delete_time_range() {
+        let database = Database::try_new("./test_dbs/delete_time_range").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let address_payload = addr.as_body();
+
+        let message = Message {
+            payload_digest: vec![0; 32],
+            ..Default::default()
+        };
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = digest(&SHA256, &raw_message);
+
+        // Put at 100, 101 and 102
+        for timestamp in 100..103 {
+            database
+                .push_message(
+                    address_payload,
+                    timestamp,
+                    &raw_message[..],
+                    digest.as_ref(),
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+        }
+
+        // Delete [100, 102)
+        let prefix = msg_prefix(address_payload, 100, MESSAGE_NAMESPACE);
+        let prefix_end = msg_prefix(address_payload, 102, MESSAGE_NAMESPACE);
+        assert_eq!(
+            database
+                .delete_messages_range(&prefix, Some(&prefix_end), 1)
+                .unwrap(),
+            SignedDeletion::Deleted(2)
+        );
+
+        // Check only 102 remains
+        let (message_page, _) = database.get_messages_range(&prefix, None, None).unwrap();
+        assert_eq!(message_page.messages.len(), 1);
+    }
+
+    #[test]
+    fn delete_expired() {
+        let database = Database::try_new("./test_dbs/delete_expired").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let address_payload = addr.as_body();
+
+        // Put at 100 and 105
+        let mut digests = Vec::new();
+        for timestamp in &[100, 105] {
+            let message = Message {
+                payload_digest: vec![0; 32],
+                received_time: *timestamp as i64,
+                ..Default::default()
+            };
+            let mut raw_message = Vec::with_capacity(message.encoded_len());
+            message.encode(&mut raw_message).unwrap();
+            let digest = digest(&SHA256, &raw_message);
+            database
+                .push_message(
+                    address_payload,
+                    *timestamp,
+                    &raw_message[..],
+                    digest.as_ref(),
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+            digests.push(digest);
+        }
+
+        // Expire messages before 103
+        assert_eq!(database.delete_expired_messages(103).unwrap(), 1);
+
+        // Check only 105 remains
+        assert!(database
+            .get_msg_key_by_digest(address_payload, digests[0].as_ref(), MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_none());
+        assert!(database
+            .get_message_by_digest(address_payload, digests[1].as_ref(), MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn open_tuned() {
+        let tuning = RocksDb {
+            write_buffer_size: Some(1024 * 1024),
+            max_open_files: Some(64),
+            block_cache_size: Some(1024 * 1024),
+            compression: Some(Compression::None),
+            sync_writes: true,
+        };
+        let database = Database::try_new_tuned("./test_dbs/open_tuned", &tuning).unwrap();
+
+        let message = Message::default();
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let digest = digest(&SHA256, &raw_message);
+        database
+            .push_message(
+                &[1; 20],
+                100,
+                &raw_message[..],
+                digest.as_ref(),
+                MESSAGE_NAMESPACE,
+            )
+            .unwrap();
+        assert!(database
+            .get_message_by_digest(&[1; 20], digest.as_ref(), MESSAGE_NAMESPACE)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn last_message_time() {
+        let path = "./test_dbs/last_message_time";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+        assert_eq!(
+            database
+                .get_last_message_time(&[1; 20], MESSAGE_NAMESPACE)
+                .unwrap(),
+            None
+        );
+
+        for timestamp in &[100, 300, 200] {
+            let message = Message {
+                received_time: *timestamp as i64,
+                ..Default::default()
+            };
+            let mut raw_message = Vec::with_capacity(message.encoded_len());
+            message.encode(&mut raw_message).unwrap();
+            let digest = digest(&SHA256, &raw_message);
+            database
+                .push_message(
+                    &[1; 20],
+                    *timestamp,
+                    &raw_message[..],
+                    digest.as_ref(),
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            database
+                .get_last_message_time(&[1; 20], MESSAGE_NAMESPACE)
+                .unwrap(),
+            Some(300)
+        );
+        assert_eq!(
+            database
+                .get_last_message_time(&[1; 20], FEED_NAMESPACE)
+                .unwrap(),
+            None
+        );
+    }
 }
@@ -0,0 +1,250 @@
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use cashweb::{
+    bitcoin::{
+        bip32::{ChildNumber, DeriveError, ExtendedPublicKey, PublicKey, SecpError},
+        Network,
+    },
+    secp256k1::{Secp256k1, VerifyOnly},
+};
+use ring::digest::{digest, SHA256};
+use rocksdb::Error as RocksError;
+use thiserror::Error;
+
+use crate::{db::Database, stamps::pubkey_hash};
+
+const XPUB_LEN: usize = 78;
+const CHECKSUM_LEN: usize = 4;
+
+const MAINNET_XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TESTNET_XPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+#[derive(Debug, Error)]
+pub enum XpubError {
+    #[error("failed to decode base58: {0}")]
+    Base58(bs58::decode::Error),
+    #[error("expected extended public key of length {}, found {0}", XPUB_LEN)]
+    Length(usize),
+    #[error("invalid checksum")]
+    Checksum,
+    #[error("extended public key is not for the {0:?} network")]
+    MismatchedNetwork(Network),
+    #[error("invalid public key: {0}")]
+    PublicKey(SecpError),
+}
+
+/// Parse a base58check encoded extended public key for the network.
+pub fn parse_xpub(xpub: &str, network: Network) -> Result<ExtendedPublicKey, XpubError> {
+    let raw_xpub = bs58::decode(xpub).into_vec().map_err(XpubError::Base58)?;
+    if raw_xpub.len() != XPUB_LEN + CHECKSUM_LEN {
+        return Err(XpubError::Length(
+            raw_xpub.len().saturating_sub(CHECKSUM_LEN),
+        ));
+    }
+    let (payload, checksum) = raw_xpub.split_at(XPUB_LEN);
+    let payload_digest = digest(&SHA256, digest(&SHA256, payload).as_ref());
+    if &payload_digest.as_ref()[..CHECKSUM_LEN] != checksum {
+        return Err(XpubError::Checksum);
+    }
+
+    // version || depth || fingerprint || child number || chain code || public key
+    let expected_version = match network {
+        Network::Mainnet => MAINNET_XPUB_VERSION,
+        Network::Testnet | Network::Regtest => TESTNET_XPUB_VERSION,
+    };
+    if payload[..4] != expected_version {
+        return Err(XpubError::MismatchedNetwork(network));
+    }
+    let chain_code: [u8; 32] = payload[13..45].try_into().unwrap(); // This is safe
+    let public_key = PublicKey::from_slice(&payload[45..]).map_err(XpubError::PublicKey)?;
+    Ok(ExtendedPublicKey::new_master(public_key, chain_code))
+}
+
+#[derive(Debug, Error)]
+pub enum DerivationError {
+    #[error("failed to persist derivation index: {0}")]
+    Database(#[from] RocksError),
+    #[error("derivation indexes exhausted")]
+    Exhausted,
+    #[error("failed to derive public key: {0}")]
+    Derive(DeriveError),
+}
+
+/// An address handed out in a payment request which hasn't been paid.
+#[derive(Debug)]
+struct Outstanding {
+    addr_payload: Vec<u8>,
+    expires: Instant,
+}
+
+/// Derives a fresh address from an extended public key for each payment request.
+///
+/// The index following each derived address is persisted before the address is returned, so
+/// addresses are never reused across restarts. Addresses of requests which expired unpaid are
+/// handed out again before deriving new ones, so unauthenticated requests can't grow the gap
+/// between used addresses beyond what is outstanding at once.
+pub struct AddressDeriver {
+    xpub: ExtendedPublicKey,
+    context: Secp256k1<VerifyOnly>,
+    database: Database,
+    next_index: Mutex<u32>,
+    request_timeout: Duration,
+    outstanding: Mutex<VecDeque<Outstanding>>,
+}
+
+impl fmt::Debug for AddressDeriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AddressDeriver")
+            .field("xpub", &self.xpub)
+            .field("next_index", &self.next_index)
+            .field("request_timeout", &self.request_timeout)
+            .finish()
+    }
+}
+
+impl AddressDeriver {
+    /// Create a deriver continuing from the persisted index, reusing addresses of payment requests
+    /// unpaid after `request_timeout`.
+    pub fn new(
+        xpub: ExtendedPublicKey,
+        database: Database,
+        request_timeout: Duration,
+    ) -> Result<Self, RocksError> {
+        let next_index = database.get_derivation_index()?;
+        Ok(Self {
+            xpub,
+            context: Secp256k1::verification_only(),
+            database,
+            next_index: Mutex::new(next_index),
+            request_timeout,
+            outstanding: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Get the address payload for a new payment request, reusing the address of a request
+    /// which expired unpaid if there is one.
+    pub fn next_address(&self) -> Result<Vec<u8>, DerivationError> {
+        let now = Instant::now();
+        let expires = now + self.request_timeout;
+
+        // Requests expire in the order they were made
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if outstanding
+            .front()
+            .map_or(false, |front| front.expires < now)
+        {
+            let mut expired = outstanding.pop_front().unwrap(); // This is safe
+            expired.expires = expires;
+            let addr_payload = expired.addr_payload.clone();
+            outstanding.push_back(expired);
+            return Ok(addr_payload);
+        }
+
+        let addr_payload = self.derive_next()?;
+        outstanding.push_back(Outstanding {
+            addr_payload: addr_payload.clone(),
+            expires,
+        });
+        Ok(addr_payload)
+    }
+
+    /// Mark the address as paid, so it is never handed out again.
+    pub fn mark_paid(&self, addr_payload: &[u8]) {
+        self.outstanding
+            .lock()
+            .unwrap()
+            .retain(|outstanding| outstanding.addr_payload != addr_payload);
+    }
+
+    /// Derive the address payload at the next index.
+    fn derive_next(&self) -> Result<Vec<u8>, DerivationError> {
+        let mut next_index = self.next_index.lock().unwrap();
+        let index = *next_index;
+        let child_number =
+            ChildNumber::from_normal_index(index).map_err(|_| DerivationError::Exhausted)?;
+        self.database.put_derivation_index(index + 1)?;
+        *next_index = index + 1;
+
+        let child = self
+            .xpub
+            .derive_public_child(&self.context, child_number)
+            .map_err(DerivationError::Derive)?;
+        Ok(pubkey_hash(&child.get_public_key().serialize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP32 test vector 1, chain m
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn parse_extended_key() {
+        let xpub = parse_xpub(XPUB, Network::Mainnet).unwrap();
+        assert_eq!(
+            hex::encode(xpub.get_public_key().serialize()),
+            "0339a36013301597daef41fbe593a02cc513d0b55527ec2df1050e2e8ff49c85c2"
+        );
+        assert!(matches!(
+            parse_xpub(XPUB, Network::Testnet),
+            Err(XpubError::MismatchedNetwork(_))
+        ));
+
+        let mut corrupted = XPUB.to_string();
+        corrupted.pop();
+        corrupted.push('9');
+        assert!(matches!(
+            parse_xpub(&corrupted, Network::Mainnet),
+            Err(XpubError::Checksum)
+        ));
+    }
+
+    #[test]
+    fn persist_index() {
+        let path = "./test_dbs/persist_index";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+        let xpub = parse_xpub(XPUB, Network::Mainnet).unwrap();
+
+        let deriver = AddressDeriver::new(xpub, database.clone(), Duration::from_secs(60)).unwrap();
+        let first = deriver.next_address().unwrap();
+        let second = deriver.next_address().unwrap();
+        assert_ne!(first, second);
+
+        // A restarted deriver continues from the persisted index
+        let deriver = AddressDeriver::new(xpub, database, Duration::from_secs(60)).unwrap();
+        let third = deriver.next_address().unwrap();
+        assert_ne!(third, first);
+        assert_ne!(third, second);
+    }
+
+    #[test]
+    fn reuse_expired() {
+        let path = "./test_dbs/reuse_expired";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+        let xpub = parse_xpub(XPUB, Network::Mainnet).unwrap();
+
+        let deriver =
+            AddressDeriver::new(xpub, database.clone(), Duration::from_millis(100)).unwrap();
+        let first = deriver.next_address().unwrap();
+        let second = deriver.next_address().unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+
+        // Unpaid addresses are reused once their requests expire, oldest first
+        deriver.mark_paid(&first);
+        assert_eq!(deriver.next_address().unwrap(), second);
+        let third = deriver.next_address().unwrap();
+        assert_ne!(third, first);
+        assert_ne!(third, second);
+        assert_eq!(database.get_derivation_index().unwrap(), 3);
+    }
+}
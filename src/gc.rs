@@ -0,0 +1,64 @@
+use std::{
+    convert::TryFrom,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    task,
+    time::{interval, Duration},
+};
+use tracing::{error, info};
+
+use crate::db::Database;
+
+/// Periodically remove messages older than `ttl` milliseconds and messages acknowledged more than
+/// `acked_ttl` milliseconds ago.
+pub async fn collect_expired(
+    database: Database,
+    opt_ttl: Option<u64>,
+    opt_acked_ttl: Option<u64>,
+    sweep_interval: u64,
+) {
+    let mut sweep = interval(Duration::from_millis(sweep_interval));
+    loop {
+        sweep.tick().await;
+
+        let now = u64::try_from(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_millis(),
+        )
+        .expect("we're in the distant future");
+
+        if let Some(ttl) = opt_ttl {
+            let cutoff_timestamp = now.saturating_sub(ttl);
+            let database_inner = database.clone();
+            match task::spawn_blocking(move || {
+                database_inner.delete_expired_messages(cutoff_timestamp)
+            })
+            .await
+            .unwrap()
+            {
+                Ok(count) => info!(message = "removed expired messages", count),
+                Err(err) => error!(message = "failed to remove expired messages", error = %err),
+            }
+        }
+
+        if let Some(acked_ttl) = opt_acked_ttl {
+            let cutoff_timestamp = now.saturating_sub(acked_ttl);
+            let database_inner = database.clone();
+            match task::spawn_blocking(move || {
+                database_inner.delete_acked_messages(cutoff_timestamp)
+            })
+            .await
+            .unwrap()
+            {
+                Ok(count) => info!(message = "removed acknowledged messages", count),
+                Err(err) => {
+                    error!(message = "failed to remove acknowledged messages", error = %err)
+                }
+            }
+        }
+    }
+}
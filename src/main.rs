@@ -1,44 +1,55 @@
 #[macro_use]
 extern crate clap;
 
+pub mod crypto;
 pub mod db;
+pub mod derivation;
+pub mod gc;
 pub mod models;
 pub mod net;
+pub mod node;
+pub mod proxy;
+pub mod selftest;
 pub mod settings;
+pub mod stamps;
 
 #[cfg(feature = "monitoring")]
 pub mod monitoring;
 
-use std::{env, sync::Arc, time::Duration};
+use std::{env, path::Path, process, sync::Arc, time::Duration};
 
-use cashweb::{
-    payments::{preprocess_payment, wallet::Wallet},
-    token::schemes::hmac_bearer::HmacScheme,
-};
+use cashweb::{payments::wallet::Wallet, token::schemes::hmac_bearer::HmacScheme};
 use dashmap::DashMap;
 use futures::prelude::*;
 use lazy_static::lazy_static;
 use serde::Deserialize;
-use tracing::info;
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, EnvFilter};
-use warp::{
-    http::{header, Method},
-    Filter,
-};
+use warp::{http::header, Filter, Rejection};
 
 #[cfg(feature = "monitoring")]
 use prometheus::{Encoder, TextEncoder};
 
-use cashweb::bitcoin_client::BitcoinClient;
 use db::{Database, FEED_NAMESPACE, MESSAGE_NAMESPACE};
-use net::{payments, protection};
-use settings::Settings;
+use net::{
+    payments,
+    protection::{self, Scope},
+};
+use settings::{LogFormat, MintToken, Settings};
 
 const DASHMAP_CAPACITY: usize = 2048;
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
 
+const ADMIN_PATH: &str = "admin";
+const BACKUP_PATH: &str = "backup";
+const HEALTH_PATH: &str = "health";
+const INFO_PATH: &str = "info";
 const PROFILES_PATH: &str = "profiles";
 const WS_PATH: &str = "ws";
 const MESSAGES_PATH: &str = "messages";
+const BATCH_PATH: &str = "batch";
+const ACK_PATH: &str = "ack";
+const QUERY_PATH: &str = "query";
 const PAYLOADS_PATH: &str = "payloads";
 const FEEDS_PATH: &str = "feeds";
 pub const PAYMENTS_PATH: &str = "payments";
@@ -53,23 +64,182 @@ pub struct QueryAccessToken {
     access_token: Option<String>,
 }
 
+/// Print a signed token for the `token` subcommand.
+fn mint_token(mint_token: &MintToken) {
+    let addr = match net::address_decode(&mint_token.address) {
+        Ok(addr) => addr,
+        Err(err) => {
+            eprintln!("invalid address: {}", err);
+            process::exit(1);
+        }
+    };
+    let key =
+        hex::decode(&SETTINGS.payments.hmac_secret).expect("unable to interpret hmac key as hex");
+    let token_scheme = HmacScheme::new(&key);
+    let ttl = mint_token
+        .ttl
+        .map(|ttl| ttl.saturating_mul(1_000))
+        .unwrap_or(SETTINGS.payments.token_ttl);
+    let token = protection::construct_token(&token_scheme, addr.as_body(), mint_token.scope, ttl);
+    println!("{}", token);
+}
+
 #[tokio::main]
 async fn main() {
+    if let Some(mint_token_args) = &SETTINGS.mint_token {
+        mint_token(mint_token_args);
+        return;
+    }
+
     if env::var_os("RUST_LOG").is_none() {
         env::set_var("RUST_LOG", "info");
     }
-    let subscriber = fmt::Subscriber::builder()
-        .with_env_filter(EnvFilter::from_default_env())
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("no global subscriber has been set");
+    let subscriber = fmt::Subscriber::builder().with_env_filter(EnvFilter::from_default_env());
+    match SETTINGS.log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(subscriber.finish()),
+        LogFormat::Json => tracing::subscriber::set_global_default(subscriber.json().finish()),
+    }
+    .expect("no global subscriber has been set");
 
     info!(message = "starting", version = crate_version!());
 
+    // Check the static directory exists
+    if SETTINGS.serve_static && !Path::new(&SETTINGS.static_dir).is_dir() {
+        error!(
+            message = "static directory does not exist",
+            path = %SETTINGS.static_dir
+        );
+        process::exit(1);
+    }
+
     // Database state
     info!(message = "opening database", path = %SETTINGS.db_path);
-    let db = Database::try_new(&SETTINGS.db_path).expect("failed to open database");
+    let db = match Database::try_new_tuned(&SETTINGS.db_path, &SETTINGS.rocksdb) {
+        Ok(db) => db,
+        Err(err) if db::is_corruption(&err) && SETTINGS.repair_db => {
+            warn!(message = "database is corrupted, attempting repair", error = %err);
+            if let Err(err) = Database::repair(&SETTINGS.db_path) {
+                error!(
+                    message = "failed to repair database",
+                    path = %SETTINGS.db_path,
+                    error = %err
+                );
+                process::exit(1);
+            }
+            info!("repaired database");
+            match Database::try_new_tuned(&SETTINGS.db_path, &SETTINGS.rocksdb) {
+                Ok(db) => db,
+                Err(err) => {
+                    error!(
+                        message = "failed to open repaired database",
+                        path = %SETTINGS.db_path,
+                        error = %err
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+        Err(err) => {
+            if db::is_corruption(&err) {
+                error!(
+                    message = "database is corrupted, restart with --repair to attempt recovery",
+                    path = %SETTINGS.db_path,
+                    error = %err
+                );
+            } else {
+                error!(
+                    message = "failed to open database",
+                    path = %SETTINGS.db_path,
+                    error = %err
+                );
+            }
+            process::exit(1);
+        }
+    };
+
+    // Expired message collection
+    if SETTINGS.messages.ttl.is_some() || SETTINGS.messages.acked_ttl.is_some() {
+        info!(
+            message = "spawning message collector",
+            ttl = ?SETTINGS.messages.ttl,
+            acked_ttl = ?SETTINGS.messages.acked_ttl,
+            interval = SETTINGS.messages.gc_interval
+        );
+        tokio::spawn(gc::collect_expired(
+            db.clone(),
+            SETTINGS.messages.ttl,
+            SETTINGS.messages.acked_ttl,
+            SETTINGS.messages.gc_interval,
+        ));
+    }
+
+    // Payment address derivation
+    let address_deriver = SETTINGS.payments.xpub.as_ref().map(|xpub_str| {
+        let xpub = match derivation::parse_xpub(xpub_str, SETTINGS.network) {
+            Ok(xpub) => xpub,
+            Err(err) => {
+                error!(message = "failed to parse payments xpub", error = %err);
+                process::exit(1);
+            }
+        };
+        // Payments are checked against an expiry in whole seconds, so allow for the rounding
+        // before reusing the address of an unpaid request
+        let request_timeout =
+            Duration::from_millis(SETTINGS.payments.timeout) + Duration::from_secs(1);
+        match derivation::AddressDeriver::new(xpub, db.clone(), request_timeout) {
+            Ok(address_deriver) => {
+                info!(message = "deriving payment addresses", deriver = ?address_deriver);
+                Arc::new(address_deriver)
+            }
+            Err(err) => {
+                error!(message = "failed to load derivation index", error = %err);
+                process::exit(1);
+            }
+        }
+    });
+
+    // Storage gauges are sampled rather than scanned on each scrape
+    #[cfg(feature = "monitoring")]
+    tokio::spawn(monitoring::sample_storage(db.clone()));
+
     let db_state = warp::any().map(move || db.clone());
 
+    // Rate limiter state
+    info!(
+        message = "constructing rate limiter",
+        window = SETTINGS.rate_limits.window,
+        address_limit = SETTINGS.rate_limits.address_limit,
+        ip_limit = SETTINGS.rate_limits.ip_limit
+    );
+    let rate_limit_window = Duration::from_millis(SETTINGS.rate_limits.window);
+    let rate_limiter = Arc::new(net::RateLimiter::new(
+        rate_limit_window,
+        SETTINGS.rate_limits.address_limit,
+        SETTINGS.rate_limits.ip_limit,
+    ));
+    let rate_limiter_sweep = rate_limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(rate_limit_window);
+        loop {
+            interval.tick().await;
+            rate_limiter_sweep.sweep();
+        }
+    });
+    let rate_limiter_state = warp::any().map(move || rate_limiter.clone());
+
+    // Client IP, read from forwarding headers set by trusted proxies
+    if !SETTINGS.trusted_proxies.is_empty() {
+        info!(
+            message = "trusting forwarding headers from proxies",
+            trusted_proxies = ?SETTINGS.trusted_proxies
+        );
+    }
+    let client_ip = warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(|remote_addr, headers: header::HeaderMap| {
+            net::client_ip(remote_addr, &headers, &SETTINGS.trusted_proxies)
+        });
+
     // Message broadcast state
     info!("constructing message bus");
     let message_bus = Arc::new(DashMap::with_capacity(DASHMAP_CAPACITY));
@@ -86,21 +256,69 @@ async fn main() {
         timeout = SETTINGS.payments.timeout
     );
     let wallet = Wallet::new(Duration::from_millis(SETTINGS.payments.timeout));
+    let idempotency_ttl = Duration::from_millis(SETTINGS.payments.timeout);
+    let idempotency_cache = Arc::new(net::IdempotencyCache::new(idempotency_ttl));
+    let idempotency_cache_sweep = idempotency_cache.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(idempotency_ttl);
+        loop {
+            interval.tick().await;
+            idempotency_cache_sweep.sweep();
+        }
+    });
+    let idempotency_cache_state = warp::any().map(move || idempotency_cache.clone());
+    if SETTINGS.payments.dry_run {
+        warn!(
+            message = "payments dry run is active, payments are NOT broadcast and tokens are issued for free",
+            network = %SETTINGS.network.to_string()
+        );
+    }
     let wallet_state = warp::any().map(move || wallet.clone());
 
     // Bitcoin client state
     info!(message = "constructing bitcoin client", address = %SETTINGS.bitcoin_rpc.address);
-    let bitcoin_client = BitcoinClient::new(
-        SETTINGS.bitcoin_rpc.address.clone(),
-        SETTINGS.bitcoin_rpc.username.clone(),
-        SETTINGS.bitcoin_rpc.password.clone(),
-    );
+    let bitcoin_client = node::new_client();
+
+    // Check the nodes are on the configured network
+    for client in bitcoin_client.clients().iter() {
+        match node::with_retry(|| node::get_blockchain_info(client)).await {
+            Ok(blockchain_info) => {
+                if blockchain_info.network() != Some(SETTINGS.network) {
+                    error!(
+                        message = "node network does not match configured network",
+                        chain = %blockchain_info.chain,
+                        network = %SETTINGS.network.to_string()
+                    );
+                    process::exit(1);
+                }
+            }
+            Err(err) => warn!(message = "failed to check node network", error = %err),
+        }
+    }
+
+    let output_source = match address_deriver {
+        Some(address_deriver) => payments::OutputSource::Derived(address_deriver),
+        None => payments::OutputSource::Node(bitcoin_client.clone()),
+    };
+    let output_source_state = warp::any().map(move || output_source.clone());
     let bitcoin_client_state = warp::any().map(move || bitcoin_client.clone());
 
-    // Address string converter
-    let addr_base = warp::path::param().and_then(|addr_str: String| async move {
-        net::address_decode(&addr_str).map_err(warp::reject::custom)
-    });
+    // Address string converter, rejecting moderated addresses
+    let moderation = match net::Moderation::from_settings(&SETTINGS.moderation) {
+        Ok(ok) => Arc::new(ok),
+        Err(err) => {
+            error!(message = "failed to load moderation lists", error = %err);
+            process::exit(1);
+        }
+    };
+    let moderation_state = warp::any().map(move || moderation.clone());
+    let addr_base = warp::path::param().and(moderation_state.clone()).and_then(
+        |addr_str: String, moderation: Arc<net::Moderation>| async move {
+            let addr = net::address_decode(&addr_str).map_err(warp::reject::custom)?;
+            moderation.check(&addr).map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>(addr)
+        },
+    );
 
     // Token generator
     let key =
@@ -109,96 +327,232 @@ async fn main() {
     let token_scheme_state = warp::any().map(move || token_scheme.clone());
 
     // Protection
-    let addr_protected = addr_base
-        .clone()
-        .and(warp::header::headers_cloned())
-        .and(warp::query())
-        .and(token_scheme_state.clone())
-        .and(wallet_state.clone())
-        .and(bitcoin_client_state.clone())
-        .and_then(
-            move |addr, headers, query: QueryAccessToken, token_scheme, wallet, bitcoin| {
-                protection::pop_protection(
-                    addr,
-                    headers,
-                    query.access_token,
-                    token_scheme,
-                    wallet,
-                    bitcoin,
-                )
-                .map_err(warp::reject::custom)
-            },
+    let addr_protected = |scope: Scope, token_fee: u64| {
+        addr_base
+            .clone()
+            .and(warp::header::headers_cloned())
+            .and(warp::query())
+            .and(token_scheme_state.clone())
+            .and(wallet_state.clone())
+            .and(output_source_state.clone())
+            .and_then(
+                move |addr, headers, query: QueryAccessToken, token_scheme, wallet, source| {
+                    protection::pop_protection(
+                        addr,
+                        scope,
+                        headers,
+                        query.access_token,
+                        token_fee,
+                        token_scheme,
+                        wallet,
+                        source,
+                    )
+                    .map_err(warp::reject::custom)
+                },
+            )
+    };
+
+    // Admin protection
+    let admin_protected = warp::header::headers_cloned()
+        .and_then(|headers| net::admin_protection(headers).map_err(warp::reject::custom))
+        .untuple_one();
+
+    // Fees
+    let message_fee = payments::token_fee(Scope::Messages);
+    let feed_fee = payments::token_fee(Scope::Feeds);
+    let profile_fee = payments::token_fee(Scope::Profiles);
+
+    // Anti-spam mode
+    if SETTINGS.stamps.require_stamp {
+        info!(
+            message = "stamps are required",
+            min_stamp_value = SETTINGS.stamps.min_stamp_value
         );
+    } else {
+        warn!(
+            message = "stamps are NOT required, message uploads are only rate limited",
+            address_limit = SETTINGS.rate_limits.address_limit,
+            ip_limit = SETTINGS.rate_limits.ip_limit
+        );
+    }
 
     info!("constructing handlers");
 
+    // Request bodies must be received within the timeout
+    let body_timeout = Duration::from_millis(SETTINGS.limits.body_timeout);
+
     // Message handlers
     let messages_get = warp::path(MESSAGES_PATH)
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Messages, message_fee))
         .and(warp::get())
         .and(warp::query())
+        .and(warp::header::headers_cloned())
         .and(db_state.clone())
-        .and_then(move |addr, query, db| {
-            net::get_messages(addr, query, db, MESSAGE_NAMESPACE).map_err(warp::reject::custom)
+        .and_then(move |addr, query, headers, db| {
+            net::get_messages(addr, query, headers, db, MESSAGE_NAMESPACE)
+                .map_err(warp::reject::custom)
+        });
+    let messages_head = warp::path(MESSAGES_PATH)
+        .and(addr_protected(Scope::Messages, message_fee))
+        .and(warp::head())
+        .and(db_state.clone())
+        .and_then(move |addr, db| {
+            net::count_messages(addr, db, MESSAGE_NAMESPACE).map_err(warp::reject::custom)
         });
     let messages_put = warp::path(MESSAGES_PATH)
-        .and(addr_base)
+        .and(addr_base.clone())
         .and(warp::put())
+        .and(client_ip.clone())
+        .and(rate_limiter_state.clone())
+        .and_then(move |addr, client_ip, rate_limiter| {
+            net::rate_limit(addr, client_ip, rate_limiter).map_err(warp::reject::custom)
+        })
         .and(warp::body::content_length_limit(
             SETTINGS.limits.message_size,
         ))
-        .and(warp::body::bytes())
+        .and(net::body_bytes(body_timeout))
         .and(db_state.clone())
         .and(bitcoin_client_state.clone())
         .and(msg_bus_state.clone())
-        .and_then(move |addr, body, db, bitcoin_client, msg_bus| {
-            net::put_message(addr, body, db, bitcoin_client, msg_bus, MESSAGE_NAMESPACE)
-                .map_err(warp::reject::custom)
+        .and(moderation_state.clone())
+        .and_then(move |addr, body, db, bitcoin_client, msg_bus, moderation| {
+            net::put_message(
+                addr,
+                body,
+                db,
+                bitcoin_client,
+                msg_bus,
+                moderation,
+                MESSAGE_NAMESPACE,
+            )
+            .map_err(warp::reject::custom)
+        });
+    let messages_put_batch = warp::path(MESSAGES_PATH)
+        .and(addr_base.clone())
+        .and(warp::path(BATCH_PATH))
+        .and(warp::put())
+        .and(client_ip.clone())
+        .and(rate_limiter_state.clone())
+        .and_then(move |addr, client_ip, rate_limiter| {
+            net::rate_limit(addr, client_ip, rate_limiter).map_err(warp::reject::custom)
+        })
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.message_size,
+        ))
+        .and(net::body_bytes(body_timeout))
+        .and(db_state.clone())
+        .and(bitcoin_client_state.clone())
+        .and(msg_bus_state.clone())
+        .and(moderation_state.clone())
+        .and_then(move |addr, body, db, bitcoin_client, msg_bus, moderation| {
+            net::put_messages_batch(
+                addr,
+                body,
+                db,
+                bitcoin_client,
+                msg_bus,
+                moderation,
+                MESSAGE_NAMESPACE,
+            )
+            .map_err(warp::reject::custom)
         });
     let messages_delete = warp::path(MESSAGES_PATH)
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Messages, message_fee))
         .and(warp::delete())
         .and(warp::query())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.profile_size,
+        ))
+        .and(net::body_bytes(body_timeout))
+        .and(db_state.clone())
+        .and_then(move |addr, query, body, db| {
+            net::remove_messages(addr, query, body, db, MESSAGE_NAMESPACE)
+                .map_err(warp::reject::custom)
+        });
+    let messages_ack = warp::path(MESSAGES_PATH)
+        .and(addr_protected(Scope::Messages, message_fee))
+        .and(warp::path::param())
+        .and(warp::path(ACK_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.profile_size,
+        ))
+        .and(net::body_bytes(body_timeout))
+        .and(db_state.clone())
+        .and_then(move |addr, digest, body, db| {
+            net::ack_message(addr, digest, body, db).map_err(warp::reject::custom)
+        });
+    let messages_query = warp::path(MESSAGES_PATH)
+        .and(addr_protected(Scope::Messages, message_fee))
+        .and(warp::path(QUERY_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.profile_size,
+        ))
+        .and(net::body_json(body_timeout))
         .and(db_state.clone())
         .and_then(move |addr, query, db| {
-            net::remove_messages(addr, query, db, MESSAGE_NAMESPACE).map_err(warp::reject::custom)
+            net::query_messages(addr, query, db, MESSAGE_NAMESPACE).map_err(warp::reject::custom)
         });
 
     // Feed handlers
     let feeds_get = warp::path(FEEDS_PATH)
-        .and(addr_base)
+        .and(addr_base.clone())
         .and(warp::get())
         .and(warp::query())
+        .and(warp::header::headers_cloned())
         .and(db_state.clone())
-        .and_then(move |addr, query, db| {
-            net::get_messages(addr, query, db, FEED_NAMESPACE).map_err(warp::reject::custom)
+        .and_then(move |addr, query, headers, db| {
+            net::get_messages(addr, query, headers, db, FEED_NAMESPACE)
+                .map_err(warp::reject::custom)
         });
     let feeds_put = warp::path(FEEDS_PATH)
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Feeds, feed_fee))
         .and(warp::put())
+        .and(client_ip.clone())
+        .and(rate_limiter_state)
+        .and_then(move |addr, client_ip, rate_limiter| {
+            net::rate_limit(addr, client_ip, rate_limiter).map_err(warp::reject::custom)
+        })
         .and(warp::body::content_length_limit(
             SETTINGS.limits.message_size,
         ))
-        .and(warp::body::bytes())
+        .and(net::body_bytes(body_timeout))
         .and(db_state.clone())
         .and(bitcoin_client_state.clone())
         .and(msg_bus_state.clone())
-        .and_then(move |addr, body, db, bitcoin_client, msg_bus| {
-            net::put_message(addr, body, db, bitcoin_client, msg_bus, FEED_NAMESPACE)
-                .map_err(warp::reject::custom)
+        .and(moderation_state.clone())
+        .and_then(move |addr, body, db, bitcoin_client, msg_bus, moderation| {
+            net::put_message(
+                addr,
+                body,
+                db,
+                bitcoin_client,
+                msg_bus,
+                moderation,
+                FEED_NAMESPACE,
+            )
+            .map_err(warp::reject::custom)
         });
     let feeds_delete = warp::path(FEEDS_PATH)
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Feeds, feed_fee))
         .and(warp::delete())
         .and(warp::query())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.profile_size,
+        ))
+        .and(net::body_bytes(body_timeout))
         .and(db_state.clone())
-        .and_then(move |addr, query, db| {
-            net::remove_messages(addr, query, db, FEED_NAMESPACE).map_err(warp::reject::custom)
+        .and_then(move |addr, query, body, db| {
+            net::remove_messages(addr, query, body, db, FEED_NAMESPACE)
+                .map_err(warp::reject::custom)
         });
 
     // Payload handlers
     let payloads_get = warp::path(PAYLOADS_PATH)
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Messages, message_fee))
         .and(warp::get())
         .and(warp::query())
         .and(db_state.clone())
@@ -209,101 +563,244 @@ async fn main() {
     // Websocket handlers
     let websocket_messages = warp::path(WS_PATH)
         .and(warp::path(MESSAGES_PATH))
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Messages, message_fee))
         .and(warp::ws())
         .and(msg_bus_state.clone())
         .map(net::upgrade_ws);
 
     let websocket_feeds = warp::path(WS_PATH)
         .and(warp::path(FEEDS_PATH))
-        .and(addr_base)
+        .and(addr_base.clone())
         .and(warp::ws())
         .and(feed_bus_state)
         .map(net::upgrade_ws);
 
     let websocket_messages_fallback = warp::path(WS_PATH)
-        .and(addr_protected.clone())
+        .and(addr_protected(Scope::Messages, message_fee))
         .and(warp::ws())
         .and(msg_bus_state.clone())
         .map(net::upgrade_ws);
 
+    // Admin handlers
+    let admin_backup = warp::path(ADMIN_PATH)
+        .and(warp::path(BACKUP_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_protected)
+        .and(db_state.clone())
+        .and_then(move |db| net::backup_database(db).map_err(warp::reject::custom));
+
     // Profile handlers
+    let profiles_list = warp::path(PROFILES_PATH)
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(admin_protected)
+        .and(warp::query())
+        .and(db_state.clone())
+        .and_then(move |query, db| net::list_profiles(query, db).map_err(warp::reject::custom));
+    let profiles_query = warp::path(PROFILES_PATH)
+        .and(warp::path(QUERY_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.profile_size,
+        ))
+        .and(net::body_json(body_timeout))
+        .and(db_state.clone())
+        .and(moderation_state.clone())
+        .and_then(move |query, db, moderation| {
+            net::query_profiles(query, db, moderation).map_err(warp::reject::custom)
+        });
     let profile_get = warp::path(PROFILES_PATH)
-        .and(addr_base)
+        .and(addr_base.clone())
         .and(warp::get())
+        .and(warp::header::headers_cloned())
         .and(db_state.clone())
-        .and_then(move |addr, db| net::get_profile(addr, db).map_err(warp::reject::custom));
+        .and_then(move |addr, headers, db| {
+            net::get_profile(addr, headers, db).map_err(warp::reject::custom)
+        });
     let profile_put = warp::path(PROFILES_PATH)
-        .and(addr_protected)
+        .and(addr_protected(Scope::Profiles, profile_fee))
         .and(warp::put())
+        .and(warp::header::headers_cloned())
         .and(warp::body::content_length_limit(
             SETTINGS.limits.profile_size,
         ))
-        .and(warp::body::bytes())
-        .and(db_state)
+        .and(net::body_bytes(body_timeout))
+        .and(db_state.clone())
+        .and_then(move |addr, headers, body, db| {
+            net::put_profile(addr, headers, body, db).map_err(warp::reject::custom)
+        });
+    let profile_delete = warp::path(PROFILES_PATH)
+        .and(addr_protected(Scope::Profiles, profile_fee))
+        .and(warp::delete())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.profile_size,
+        ))
+        .and(net::body_bytes(body_timeout))
+        .and(db_state.clone())
         .and_then(move |addr, body, db| {
-            net::put_profile(addr, body, db).map_err(warp::reject::custom)
+            net::delete_profile(addr, body, db).map_err(warp::reject::custom)
         });
 
     // Payment handler
     let payments = warp::path(PAYMENTS_PATH)
         .and(warp::post())
-        .and(warp::header::headers_cloned())
-        .and(warp::body::content_length_limit(
-            SETTINGS.limits.profile_size,
+        .and(payments::payment_body(
+            SETTINGS.limits.payment_size,
+            body_timeout,
         ))
-        .and(warp::body::bytes())
-        .and_then(move |headers, body| {
-            preprocess_payment(headers, body)
-                .map_err(payments::PaymentError::Preprocess)
-                .map_err(warp::reject::custom)
-        })
         .and(wallet_state.clone())
         .and(bitcoin_client_state.clone())
+        .and(output_source_state.clone())
         .and(token_scheme_state)
+        .and(warp::header::optional(IDEMPOTENCY_KEY_HEADER))
+        .and(idempotency_cache_state)
         .and_then(
-            move |payment, wallet, bitcoin_client, token_state| async move {
-                net::process_payment(payment, wallet, bitcoin_client, token_state)
-                    .await
-                    .map_err(warp::reject::custom)
+            move |payment,
+                  wallet,
+                  bitcoin_client,
+                  output_source,
+                  token_state,
+                  idempotency_key,
+                  idempotency_cache| async move {
+                let result = net::idempotent_payment(
+                    idempotency_key,
+                    idempotency_cache,
+                    payment,
+                    |payment| {
+                        net::process_payment(
+                            payment,
+                            wallet,
+                            bitcoin_client,
+                            output_source,
+                            token_state,
+                        )
+                    },
+                )
+                .await;
+
+                #[cfg(feature = "monitoring")]
+                monitoring::observe_payment(result.is_ok());
+
+                result.map_err(warp::reject::custom)
             },
         );
 
+    // Health handler
+    let health = warp::path(HEALTH_PATH)
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(db_state)
+        .and(bitcoin_client_state.clone())
+        .and_then(net::get_health);
+
+    // Info handler
+    let raw_info = bytes::Bytes::from(serde_json::to_vec(&net::Info::new()).unwrap()); // This is safe
+    let info = warp::path(INFO_PATH)
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || raw_info.clone()))
+        .and_then(net::get_info);
+
     // Root handler
+    let static_enabled = warp::any()
+        .and_then(|| async {
+            if SETTINGS.serve_static {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
     let root = warp::path::end()
         .and(warp::get())
-        .and(warp::fs::file("./static/index.html"));
+        .and(static_enabled)
+        .and(warp::fs::file(
+            Path::new(&SETTINGS.static_dir).join("index.html"),
+        ));
 
     // CORs
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec![Method::GET, Method::PUT, Method::POST, Method::DELETE])
-        .allow_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE])
+    let allowed_origins = &SETTINGS.cors.allowed_origins;
+    let cors = if allowed_origins.iter().any(|origin| origin == "*") {
+        warp::cors().allow_any_origin()
+    } else {
+        warp::cors().allow_origins(allowed_origins.iter().map(String::as_str))
+    };
+    let cors = cors
+        .allow_methods(SETTINGS.cors.allowed_methods.iter().map(String::as_str))
+        .allow_headers(SETTINGS.cors.allowed_headers.iter().map(String::as_str))
         .expose_headers(vec![
             header::AUTHORIZATION,
             header::ACCEPT,
             header::LOCATION,
+            header::ETAG,
         ])
+        .expose_header(net::HAS_MORE_HEADER)
+        .expose_header(net::TRUNCATED_HEADER)
+        .expose_header(net::MESSAGE_COUNT_HEADER)
+        .expose_header(net::STAMP_TXID_HEADER)
+        .expose_header(net::REQUEST_ID_HEADER)
         .build();
 
     // Init REST API
-    let rest_api = root
+    let routes = root
+        .or(health)
+        .or(info)
         .or(payments)
         .or(websocket_messages)
         .or(websocket_feeds)
         .or(websocket_messages_fallback)
         .or(messages_get)
+        .or(messages_head)
         .or(messages_delete)
+        .or(messages_ack)
+        .or(messages_query)
+        .or(messages_put_batch)
         .or(messages_put)
         .or(feeds_get)
         .or(feeds_delete)
         .or(feeds_put)
         .or(payloads_get)
+        .or(admin_backup)
+        .or(profiles_list)
+        .or(profiles_query)
         .or(profile_get)
         .or(profile_put)
-        .recover(net::handle_rejection)
+        .or(profile_delete)
+        .recover(net::handle_rejection);
+    let rest_api = warp::header::headers_cloned()
+        .map(net::resolve_request_id)
+        .and(routes)
+        .map(net::with_request_id)
+        .and(warp::header::optional(header::ACCEPT_ENCODING.as_str()))
+        .and_then(net::compress)
         .with(cors)
-        .with(warp::trace::request());
+        .with(warp::log::custom(net::access_log))
+        .with(warp::trace(net::request_span));
+
+    // Exercise the routes on an ephemeral port instead of serving
+    if SETTINGS.selftest {
+        let (relay_addr, server) = warp::serve(rest_api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let result = selftest::run(relay_addr).await;
+        let _ = std::fs::remove_dir_all(&SETTINGS.db_path);
+        match result {
+            Ok(()) => info!("self-test passed"),
+            Err(err) => {
+                error!(message = "self-test failed", error = %err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Serve over TLS if a certificate and key are configured
+    let tls_paths = SETTINGS
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.cert_path.as_ref().zip(tls.key_path.as_ref()));
+    info!(tls = tls_paths.is_some());
 
     // If monitoring is enabled
     #[cfg(feature = "monitoring")]
@@ -311,15 +808,39 @@ async fn main() {
         info!(monitoring = true);
 
         // Init Prometheus server
-        let prometheus_server = warp::path("metrics").map(monitoring::export);
+        let metrics_protected = warp::any()
+            .and_then(|| async {
+                if SETTINGS.admin.protect_metrics {
+                    Err(warp::reject::not_found())
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one()
+            .or(admin_protected)
+            .unify();
+        let prometheus_server = warp::path("metrics")
+            .and(metrics_protected)
+            .map(monitoring::export)
+            .recover(net::handle_rejection);
         let prometheus_task = warp::serve(prometheus_server).run(SETTINGS.bind_prom);
 
         let rest_api = rest_api.with(warp::log::custom(monitoring::measure));
-        let rest_api_task = warp::serve(rest_api).run(SETTINGS.bind);
+        let rest_api_server = warp::serve(rest_api);
 
         // Spawn servers
         tokio::spawn(prometheus_task);
-        tokio::spawn(rest_api_task).await.unwrap(); // Unrecoverable
+        if let Some((cert_path, key_path)) = tls_paths {
+            let rest_api_task = rest_api_server
+                .tls()
+                .cert_path(cert_path)
+                .key_path(key_path)
+                .run(SETTINGS.bind);
+            tokio::spawn(rest_api_task).await.unwrap(); // Unrecoverable
+        } else {
+            let rest_api_task = rest_api_server.run(SETTINGS.bind);
+            tokio::spawn(rest_api_task).await.unwrap(); // Unrecoverable
+        }
     }
 
     // If monitoring is disabled
@@ -327,7 +848,17 @@ async fn main() {
     {
         info!(monitoring = false);
 
-        let rest_api_task = warp::serve(rest_api).run(SETTINGS.bind);
-        tokio::spawn(rest_api_task).await.unwrap(); // Unrecoverable
+        let rest_api_server = warp::serve(rest_api);
+        if let Some((cert_path, key_path)) = tls_paths {
+            let rest_api_task = rest_api_server
+                .tls()
+                .cert_path(cert_path)
+                .key_path(key_path)
+                .run(SETTINGS.bind);
+            tokio::spawn(rest_api_task).await.unwrap(); // Unrecoverable
+        } else {
+            let rest_api_task = rest_api_server.run(SETTINGS.bind);
+            tokio::spawn(rest_api_task).await.unwrap(); // Unrecoverable
+        }
     }
 }
@@ -1,10 +1,16 @@
+use std::time::Duration;
+
 use lazy_static::lazy_static;
-use prometheus::{CounterVec, HistogramVec};
+use prometheus::{CounterVec, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec};
+use tokio::{task, time::interval};
 use warp::filters::log::Info;
 
 use prometheus_static_metric::make_static_metric;
 
-use crate::*;
+use crate::{db::Database, stamps::StampError, *};
+
+/// How often the storage gauges are refreshed, as reading them scans the message counts.
+const STORAGE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
 
 make_static_metric! {
     pub label_enum Method {
@@ -94,6 +100,60 @@ lazy_static! {
     )
     .unwrap();
     pub static ref HTTP_ELAPSED: RequestDurationHistogram = RequestDurationHistogram::from(&HTTP_ELAPSED_VEC);
+
+    // Message counter
+    pub static ref MESSAGES_TOTAL: IntCounterVec = prometheus::register_int_counter_vec!(
+        "messages_total",
+        "Total number of messages stored or retrieved.",
+        &["operation"]
+    )
+    .unwrap();
+
+    // Payment counter
+    pub static ref PAYMENTS_TOTAL: IntCounterVec = prometheus::register_int_counter_vec!(
+        "payments_total",
+        "Total number of processed payments.",
+        &["result"]
+    )
+    .unwrap();
+
+    // Stamp rejection counter
+    pub static ref STAMP_REJECTIONS_TOTAL: IntCounterVec = prometheus::register_int_counter_vec!(
+        "stamp_rejections_total",
+        "Total number of rejected stamps.",
+        &["reason"]
+    )
+    .unwrap();
+
+    // Database operation duration
+    pub static ref DB_ELAPSED: HistogramVec = prometheus::register_histogram_vec!(
+        "db_operation_duration_seconds",
+        "Histogram of database operation times.",
+        &["operation"]
+    )
+    .unwrap();
+
+    // Database size on disk
+    pub static ref DB_SIZE: IntGauge = prometheus::register_int_gauge!(
+        "db_size_bytes",
+        "Total size of the database SST files."
+    )
+    .unwrap();
+
+    // Database key counts
+    pub static ref DB_KEYS: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "db_keys_estimate",
+        "Estimated number of keys per category.",
+        &["category"]
+    )
+    .unwrap();
+
+    // Largest number of messages stored under an address, a count rather than a size
+    pub static ref DB_LARGEST_ADDRESS_MESSAGE_COUNT: IntGauge = prometheus::register_int_gauge!(
+        "db_largest_address_message_count",
+        "Largest number of messages stored under a single address, across namespaces."
+    )
+    .unwrap();
 }
 
 pub fn measure(info: Info) {
@@ -111,6 +171,83 @@ pub fn measure(info: Info) {
         .observe(duration_secs as f64);
 }
 
+pub fn observe_messages(operation: &str, count: usize) {
+    MESSAGES_TOTAL
+        .with_label_values(&[operation])
+        .inc_by(count as i64);
+}
+
+pub fn observe_payment(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    PAYMENTS_TOTAL.with_label_values(&[result]).inc();
+}
+
+pub fn observe_stamp_rejection(err: &StampError) {
+    let reason = match err {
+        StampError::Decode(_) => "decode",
+        StampError::MissingOutput => "missing_output",
+        StampError::DegenerateCombination => "degenerate_combination",
+        StampError::ChildNumberOverflow => "child_number_overflow",
+        StampError::UnsupportedStampType => "unsupported_stamp_type",
+        StampError::NoneType => "none_type",
+        StampError::InsufficientValue(..) => "insufficient_value",
+        StampError::Rejected(_) => "mempool_reject",
+    };
+    STAMP_REJECTIONS_TOTAL.with_label_values(&[reason]).inc();
+}
+
+pub fn observe_stamp_tx_rejection() {
+    STAMP_REJECTIONS_TOTAL
+        .with_label_values(&["tx_reject"])
+        .inc();
+}
+
+pub fn db_timer(operation: &str) -> HistogramTimer {
+    DB_ELAPSED.with_label_values(&[operation]).start_timer()
+}
+
+/// Refresh the storage gauges from the database every `STORAGE_SAMPLE_INTERVAL`, rather than on
+/// each scrape.
+pub async fn sample_storage(database: Database) {
+    let mut sample = interval(STORAGE_SAMPLE_INTERVAL);
+    loop {
+        sample.tick().await;
+
+        // Scanning the message counts blocks on the database
+        let database = database.clone();
+        task::spawn_blocking(move || observe_storage(&database))
+            .await
+            .unwrap(); // This is safe
+    }
+}
+
+/// Refresh the storage gauges from the database.
+///
+/// This scans the message counts, one key per address and namespace, so blocks on the database.
+fn observe_storage(database: &Database) {
+    let stats = database.total_size().and_then(|total_size| {
+        Ok((
+            total_size,
+            database.estimate_message_keys()?,
+            database.estimate_profile_keys()?,
+            database.largest_message_count()?,
+        ))
+    });
+    match stats {
+        Ok((total_size, message_keys, profile_keys, largest_message_count)) => {
+            DB_SIZE.set(total_size as i64);
+            DB_KEYS
+                .with_label_values(&["messages"])
+                .set(message_keys as i64);
+            DB_KEYS
+                .with_label_values(&["profiles"])
+                .set(profile_keys as i64);
+            DB_LARGEST_ADDRESS_MESSAGE_COUNT.set(largest_message_count as i64);
+        }
+        Err(err) => tracing::error!(message = "failed to read storage stats", error = %err),
+    }
+}
+
 pub fn export() -> Vec<u8> {
     let metric_families = prometheus::gather();
 
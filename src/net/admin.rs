@@ -0,0 +1,164 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rocksdb::Error as RocksError;
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tokio::task;
+use warp::{
+    http::{
+        header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE},
+        Response,
+    },
+    hyper::Body,
+    reject::Reject,
+};
+
+use super::IntoResponse;
+use crate::{db::Database, SETTINGS};
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("admin endpoints are disabled")]
+    Disabled,
+    #[error("missing admin token")]
+    MissingToken,
+    #[error("invalid admin token")]
+    InvalidToken,
+}
+
+impl Reject for AdminError {}
+
+impl IntoResponse for AdminError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Disabled => 404,
+            _ => 401,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Disabled => "ADMIN_DISABLED",
+            Self::MissingToken => "MISSING_ADMIN_TOKEN",
+            Self::InvalidToken => "INVALID_ADMIN_TOKEN",
+        }
+    }
+}
+
+/// Check the bearer token against the API key.
+fn check_admin_token(header_map: &HeaderMap, api_key: &str) -> Result<(), AdminError> {
+    let token = header_map
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+        .ok_or(AdminError::MissingToken)?;
+
+    if bool::from(token.as_bytes().ct_eq(api_key.as_bytes())) {
+        Ok(())
+    } else {
+        Err(AdminError::InvalidToken)
+    }
+}
+
+/// Require the configured admin API key, admin endpoints are disabled if no key is configured.
+pub async fn admin_protection(header_map: HeaderMap) -> Result<(), AdminError> {
+    let api_key = SETTINGS
+        .admin
+        .api_key
+        .as_ref()
+        .ok_or(AdminError::Disabled)?;
+    check_admin_token(&header_map, api_key)
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("backups are disabled")]
+    Disabled,
+    #[error("failed to create checkpoint: {0}")]
+    Checkpoint(#[from] RocksError),
+}
+
+impl Reject for BackupError {}
+
+impl IntoResponse for BackupError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Disabled => 404,
+            Self::Checkpoint(_) => 500,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Disabled => "BACKUP_DISABLED",
+            Self::Checkpoint(_) => "CHECKPOINT",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Backup {
+    path: String,
+}
+
+/// Checkpoint the database into a new directory, named by the current time, within the backup
+/// directory.
+pub async fn backup_database(database: Database) -> Result<Response<Body>, BackupError> {
+    let backup_dir = SETTINGS
+        .admin
+        .backup_dir
+        .as_ref()
+        .ok_or(BackupError::Disabled)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let backup_path: PathBuf = [backup_dir, &timestamp.to_string()].iter().collect();
+
+    // Create checkpoint
+    let checkpoint_path = backup_path.clone();
+    task::spawn_blocking(move || database.checkpoint(checkpoint_path))
+        .await
+        .unwrap()?;
+
+    let backup = Backup {
+        path: backup_path.to_string_lossy().into_owned(),
+    };
+
+    // Respond
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&backup).unwrap())) // This is safe
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use warp::http::HeaderValue;
+
+    #[test]
+    fn admin_token() {
+        let mut header_map = HeaderMap::new();
+        assert!(matches!(
+            check_admin_token(&header_map, "secret"),
+            Err(AdminError::MissingToken)
+        ));
+
+        header_map.insert(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert!(matches!(
+            check_admin_token(&header_map, "secret"),
+            Err(AdminError::InvalidToken)
+        ));
+
+        header_map.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(check_admin_token(&header_map, "secret").is_ok());
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::prelude::*;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use warp::{
+    reject::{self, Reject},
+    Filter, Rejection,
+};
+
+use super::IntoResponse;
+
+#[derive(Debug, Error)]
+pub enum BodyError {
+    #[error("request body not received within {0:?}")]
+    Timeout(Duration),
+    #[error("failed to read request body: {0}")]
+    Read(warp::Error),
+    #[error("failed to deserialize request body: {0}")]
+    Deserialize(serde_json::Error),
+}
+
+impl Reject for BodyError {}
+
+impl IntoResponse for BodyError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Timeout(_) => 408,
+            _ => 400,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Timeout(_) => "BODY_TIMEOUT",
+            Self::Read(_) => "BODY_READ",
+            Self::Deserialize(_) => "BODY_DESERIALIZE",
+        }
+    }
+}
+
+/// Buffer the request body, failing if it isn't fully received within the timeout.
+///
+/// This bounds how long a client dribbling bytes can hold a connection, complementing the
+/// content length limits.
+pub fn body_bytes(timeout: Duration) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::stream().and_then(move |body_stream| async move {
+        let buffer = body_stream.try_fold(BytesMut::new(), |mut buffer, chunk| async move {
+            buffer.put(chunk);
+            Ok(buffer)
+        });
+        match tokio::time::timeout(timeout, buffer).await {
+            Ok(Ok(buffer)) => Ok(buffer.freeze()),
+            Ok(Err(err)) => Err(reject::custom(BodyError::Read(err))),
+            Err(_) => Err(reject::custom(BodyError::Timeout(timeout))),
+        }
+    })
+}
+
+/// Buffer the request body within the timeout and deserialize it from JSON.
+pub fn body_json<T: DeserializeOwned + Send>(
+    timeout: Duration,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    body_bytes(timeout).and_then(|body: Bytes| async move {
+        serde_json::from_slice(&body)
+            .map_err(BodyError::Deserialize)
+            .map_err(reject::custom)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use crate::net::handle_rejection;
+
+    #[tokio::test]
+    async fn slow_body() {
+        let filter = body_bytes(Duration::from_millis(50))
+            .map(|_| warp::reply())
+            .recover(handle_rejection);
+        let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Promise a body but only send part of it
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PUT / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+
+        let mut status_line = [0; 12];
+        stream.read_exact(&mut status_line).await.unwrap();
+        assert_eq!(&status_line, b"HTTP/1.1 408");
+    }
+}
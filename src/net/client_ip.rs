@@ -0,0 +1,132 @@
+use std::net::{IpAddr, SocketAddr};
+
+use warp::http::header::{HeaderMap, FORWARDED};
+
+pub const X_FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// Parse a node of the `Forwarded` header, such as `192.0.2.60`, `"192.0.2.60:80"` or
+/// `"[2001:db8::17]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Addresses listed by the proxies, from the original client to the most recent proxy.
+///
+/// The `Forwarded` header takes precedence over `X-Forwarded-For`. Entries which aren't IP
+/// addresses, such as `unknown` or obfuscated identifiers, are `None`.
+fn forwarded_chain(header_map: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        header_map
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let mut pair = pair.splitn(2, '=');
+                    let name = pair.next()?.trim();
+                    if name.eq_ignore_ascii_case("for") {
+                        Some(parse_node(pair.next()?))
+                    } else {
+                        None
+                    }
+                })?
+            })
+            .collect();
+    }
+
+    values(X_FORWARDED_FOR_HEADER)
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// Resolve the client's IP address.
+///
+/// Forwarding headers are only read when the immediate peer is a trusted proxy. The chain is then
+/// walked from the most recent hop, skipping trusted proxies, and the first untrusted address is
+/// the client. If the chain ends in an entry which isn't an address, the last trusted hop is used.
+pub fn client_ip(
+    remote_addr: Option<SocketAddr>,
+    header_map: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = remote_addr?.ip();
+    if !trusted_proxies.contains(&client) {
+        return Some(client);
+    }
+
+    for hop in forwarded_chain(header_map).into_iter().rev() {
+        match hop {
+            Some(ip) if trusted_proxies.contains(&ip) => client = ip,
+            Some(ip) => return Some(ip),
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    const PROXY: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn peer(ip: IpAddr) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip, 4000))
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+        header_map.insert(name, value.parse().unwrap());
+        header_map
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let spoofed = headers(X_FORWARDED_FOR_HEADER, "203.0.113.7");
+        let peer_ip: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(client_ip(peer(peer_ip), &spoofed, &[PROXY]), Some(peer_ip));
+        assert_eq!(client_ip(peer(PROXY), &spoofed, &[]), Some(PROXY));
+    }
+
+    #[test]
+    fn trusted_peer() {
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        // Spoofed entries to the left of the client are ignored
+        let x_forwarded_for = headers(X_FORWARDED_FOR_HEADER, "10.0.0.1, 203.0.113.7");
+        assert_eq!(
+            client_ip(peer(PROXY), &x_forwarded_for, &[PROXY]),
+            Some(client)
+        );
+
+        let forwarded = headers(
+            "forwarded",
+            "for=\"[2001:db8::17]:4711\", for=203.0.113.7;proto=https, for=127.0.0.1",
+        );
+        assert_eq!(client_ip(peer(PROXY), &forwarded, &[PROXY]), Some(client));
+
+        let unknown = headers("forwarded", "for=unknown");
+        assert_eq!(client_ip(peer(PROXY), &unknown, &[PROXY]), Some(PROXY));
+        assert_eq!(
+            client_ip(peer(PROXY), &HeaderMap::new(), &[PROXY]),
+            Some(PROXY)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::{convert::Infallible, io::Write};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use warp::{
+    http::{
+        header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, VARY},
+        Response, StatusCode,
+    },
+    hyper::Body,
+};
+
+use crate::SETTINGS;
+
+/// Bodies smaller than this aren't worth compressing.
+const MIN_COMPRESSION_LEN: usize = 1024;
+
+/// Content coding of a compressed response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, raw: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(raw)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(raw)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Choose the encoding from the `Accept-Encoding` header, preferring gzip.
+///
+/// Codings with a zero quality value are refused, other quality values are ignored. A coding
+/// named explicitly takes precedence over `*`.
+pub fn accepted_encoding(accept_encoding: &str) -> Option<Encoding> {
+    // Each coding along with whether it was refused
+    let codings: Vec<(&str, bool)> = accept_encoding
+        .split(',')
+        .map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap().trim(); // This is safe
+            let refused = params.any(|param| {
+                let param = param.trim();
+                param.starts_with("q=") && param[2..].parse::<f32>().ok() == Some(0.)
+            });
+            (name, refused)
+        })
+        .collect();
+    let accepts = |name: &str| {
+        let explicit = codings
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(name));
+        let wildcard = codings.iter().find(|(coding, _)| *coding == "*");
+        match explicit.or(wildcard) {
+            Some((_, refused)) => !refused,
+            None => false,
+        }
+    };
+
+    if accepts(Encoding::Gzip.as_str()) {
+        Some(Encoding::Gzip)
+    } else if accepts(Encoding::Deflate.as_str()) {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Compress the response body if the client accepts it.
+///
+/// Upgrades, partial content and bodies which are already encoded are passed through. Responses
+/// vary on `Accept-Encoding` whenever compression is enabled, and the entity tags of compressed
+/// bodies are weakened as the bytes differ from the uncompressed representation.
+pub async fn compress(
+    response: Response<Body>,
+    accept_encoding: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    if !SETTINGS.compression {
+        return Ok(response);
+    }
+    let status = response.status();
+    if status == StatusCode::SWITCHING_PROTOCOLS
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || response.headers().contains_key(CONTENT_ENCODING)
+    {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let encoding = match accept_encoding.as_deref().and_then(accepted_encoding) {
+        Some(some) => some,
+        None => return Ok(Response::from_parts(parts, body)),
+    };
+    let raw_body = match warp::hyper::body::to_bytes(body).await {
+        Ok(ok) => ok,
+        Err(_) => return Ok(Response::from_parts(parts, Body::empty())),
+    };
+    if raw_body.len() < MIN_COMPRESSION_LEN {
+        return Ok(Response::from_parts(parts, Body::from(raw_body)));
+    }
+
+    // Compressing large bodies is CPU bound
+    let encoded = tokio::task::spawn_blocking(move || encoding.encode(&raw_body))
+        .await
+        .unwrap(); // This is safe
+    match encoded {
+        Ok(encoded_body) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.insert(
+                CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            if let Some(weak_etag) = parts.headers.get(ETAG).and_then(weaken_etag) {
+                parts.headers.insert(ETAG, weak_etag);
+            }
+            Ok(Response::from_parts(parts, Body::from(encoded_body)))
+        }
+        Err(_) => Ok(Response::from_parts(parts, Body::empty())),
+    }
+}
+
+/// Weaken a strong entity tag, returning `None` if it is already weak.
+fn weaken_etag(etag: &HeaderValue) -> Option<HeaderValue> {
+    if etag.as_bytes().starts_with(b"W/") {
+        return None;
+    }
+    HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    #[test]
+    fn accept_encoding() {
+        assert_eq!(accepted_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            accepted_encoding("deflate, gzip;q=1.0"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            accepted_encoding("gzip;q=0, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(accepted_encoding("*"), Some(Encoding::Gzip));
+        assert_eq!(accepted_encoding("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(accepted_encoding("gzip;q=0, deflate;q=0, *"), None);
+        assert_eq!(accepted_encoding("deflate, *;q=0"), Some(Encoding::Deflate));
+        assert_eq!(accepted_encoding("br, identity"), None);
+    }
+
+    #[tokio::test]
+    async fn compress_large_body() {
+        let raw_body = vec![7; 4 * MIN_COMPRESSION_LEN];
+        let response = Response::builder()
+            .header(ETAG, "\"tag\"")
+            .body(Body::from(raw_body.clone()))
+            .unwrap();
+        let response = compress(response, Some("gzip, deflate".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        assert_eq!(response.headers()[ETAG], "W/\"tag\"");
+
+        let encoded_body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert!(encoded_body.len() < raw_body.len());
+        let mut decoded_body = Vec::new();
+        GzDecoder::new(&encoded_body[..])
+            .read_to_end(&mut decoded_body)
+            .unwrap();
+        assert_eq!(decoded_body, raw_body);
+
+        // Clients which don't ask for compression get the raw body, which caches must not serve
+        // to those which do
+        let response = Response::builder()
+            .header(ETAG, "\"tag\"")
+            .body(Body::from(raw_body.clone()))
+            .unwrap();
+        let response = compress(response, None).await.unwrap();
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        assert_eq!(response.headers()[ETAG], "\"tag\"");
+    }
+}
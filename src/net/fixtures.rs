@@ -0,0 +1,67 @@
+use bitcoincash_addr::Address;
+use bytes::Bytes;
+use cashweb::{
+    auth_wrapper::SignatureScheme,
+    secp256k1::{
+        key::{PublicKey, SecretKey},
+        Message as SecpMessage, Secp256k1,
+    },
+};
+use prost::Message as _;
+use ring::digest::{digest, SHA256};
+
+use super::{address_decode, address_encode};
+use crate::{
+    models::wrapper::AuthWrapper,
+    node::{direct_client, NodeClient},
+    stamps::pubkey_hash,
+};
+
+/// A client of a node which can't be reached, for handlers which shouldn't call it.
+pub fn unreachable_node() -> NodeClient {
+    NodeClient::new(vec![direct_client(
+        "http://127.0.0.1:1".to_string(),
+        "user".to_string(),
+        "password".to_string(),
+    )])
+}
+
+fn signer_secret_key() -> SecretKey {
+    SecretKey::from_slice(&[1; 32]).unwrap()
+}
+
+/// The serialized public key of the key signing test payloads.
+pub fn signer_public_key() -> Vec<u8> {
+    PublicKey::from_secret_key(&Secp256k1::signing_only(), &signer_secret_key())
+        .serialize()
+        .to_vec()
+}
+
+/// The address of the key signing test payloads.
+pub fn signer_address() -> Address {
+    address_decode(&address_encode(pubkey_hash(&signer_public_key()))).unwrap()
+}
+
+/// Sign the payload, returning the serialized authorization wrapper.
+pub fn sign_payload(payload: Vec<u8>) -> Bytes {
+    let context = Secp256k1::signing_only();
+    let payload_digest = digest(&SHA256, &payload);
+    let message = SecpMessage::from_slice(payload_digest.as_ref()).unwrap();
+    let signature = context.sign(&message, &signer_secret_key());
+    let wrapper = AuthWrapper {
+        public_key: signer_public_key(),
+        signature: signature.serialize_compact().to_vec(),
+        scheme: SignatureScheme::Ecdsa as i32,
+        payload,
+        payload_digest: payload_digest.as_ref().to_vec(),
+    };
+
+    let mut raw_wrapper = Vec::with_capacity(wrapper.encoded_len());
+    wrapper.encode(&mut raw_wrapper).unwrap();
+    raw_wrapper.into()
+}
+
+/// Sign a deletion for the operation at the timestamp.
+pub fn sign_deletion(operation: &[u8], timestamp: i64) -> Bytes {
+    sign_payload([operation, &timestamp.to_be_bytes()].concat())
+}
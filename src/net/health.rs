@@ -0,0 +1,118 @@
+use std::{convert::Infallible, time::Duration};
+
+use serde::Serialize;
+use tokio::time::timeout;
+use tracing::warn;
+use warp::{
+    http::{header::CONTENT_TYPE, Response},
+    hyper::Body,
+};
+
+use crate::{
+    db::Database,
+    node::{self, NodeClient},
+};
+
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Overall health of the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    /// The node is unreachable, so payments and stamped messages fail but reads are served.
+    Degraded,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: Status,
+    database: bool,
+    node: bool,
+    ready: bool,
+}
+
+pub async fn get_health(
+    database: Database,
+    bitcoin_client: NodeClient,
+) -> Result<Response<Body>, Infallible> {
+    // Check database
+    let database = match tokio::task::spawn_blocking(move || database.check()).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            warn!(message = "database health check failed", error = %err);
+            false
+        }
+        Err(err) => {
+            warn!(message = "database health check panicked", error = %err);
+            false
+        }
+    };
+
+    // Check any bitcoin node can be reached
+    let check_nodes = async {
+        let mut result = Ok(());
+        for client in bitcoin_client.clients().iter() {
+            result = node::get_blockchain_info(client).await.map(|_| ());
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    };
+    let node = match timeout(NODE_TIMEOUT, check_nodes).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(err)) => {
+            warn!(message = "node health check failed", error = %err);
+            false
+        }
+        Err(_) => {
+            warn!("node health check timed out");
+            false
+        }
+    };
+
+    // The relay can serve reads without the node
+    let status = match (database, node) {
+        (true, true) => Status::Ok,
+        (true, false) => Status::Degraded,
+        (false, _) => Status::Down,
+    };
+    let health = Health {
+        status,
+        database,
+        node,
+        ready: database,
+    };
+    let status = if health.ready { 200 } else { 503 };
+    let body = serde_json::to_vec(&health).unwrap(); // This is safe
+
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::net::fixtures::unreachable_node;
+
+    #[tokio::test]
+    async fn node_unreachable() {
+        let database = Database::try_new("./test_dbs/node_unreachable").unwrap();
+        let bitcoin_client = unreachable_node();
+
+        let response = get_health(database, bitcoin_client).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let health: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["node"], false);
+    }
+}
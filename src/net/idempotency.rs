@@ -0,0 +1,208 @@
+use std::time::{Duration, Instant};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use warp::{
+    http::{HeaderMap, Response, StatusCode},
+    hyper::{body::Bytes, Body},
+};
+
+/// A response which has been buffered so it can be replayed.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    /// Buffer a response, returning it alongside a copy to be cached.
+    pub async fn buffer(
+        response: Response<Body>,
+    ) -> Result<(Self, Response<Body>), warp::hyper::Error> {
+        let (parts, body) = response.into_parts();
+        let body = warp::hyper::body::to_bytes(body).await?;
+        let cached_response = Self {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        };
+        Ok((
+            cached_response,
+            Response::from_parts(parts, Body::from(body)),
+        ))
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+#[derive(Debug)]
+enum Slot {
+    Pending,
+    Complete(CachedResponse),
+}
+
+/// A reserved key, bound to a fingerprint of the request which reserved it.
+#[derive(Debug)]
+struct Record {
+    created: Instant,
+    fingerprint: Vec<u8>,
+    slot: Slot,
+}
+
+/// The outcome of reserving an idempotency key.
+#[derive(Debug)]
+pub enum Reservation {
+    /// The key is new and the request should be processed.
+    Reserved,
+    /// A request with the key is still being processed.
+    InProgress,
+    /// A request with the key has completed with this response.
+    Complete(CachedResponse),
+    /// The key was reserved by a different request.
+    Mismatch,
+}
+
+/// Remembers responses by idempotency key for a fixed lifetime.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    ttl: Duration,
+    slots: DashMap<String, Record>,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: DashMap::new(),
+        }
+    }
+
+    /// Reserve a key for the request with the fingerprint, unless a request with the key is in
+    /// progress or has completed.
+    ///
+    /// Only the request which reserved the key may have its response replayed.
+    pub fn reserve(&self, key: &str, fingerprint: &[u8]) -> Reservation {
+        let now = Instant::now();
+        let record = Record {
+            created: now,
+            fingerprint: fingerprint.to_vec(),
+            slot: Slot::Pending,
+        };
+        match self.slots.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let existing = entry.get();
+                if now.duration_since(existing.created) >= self.ttl {
+                    entry.insert(record);
+                    return Reservation::Reserved;
+                }
+                if existing.fingerprint != fingerprint {
+                    return Reservation::Mismatch;
+                }
+                match &existing.slot {
+                    Slot::Pending => Reservation::InProgress,
+                    Slot::Complete(cached_response) => {
+                        Reservation::Complete(cached_response.clone())
+                    }
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(record);
+                Reservation::Reserved
+            }
+        }
+    }
+
+    /// Record the response for a reserved key.
+    pub fn complete(&self, key: &str, cached_response: CachedResponse) {
+        if let Some(mut record) = self.slots.get_mut(key) {
+            record.slot = Slot::Complete(cached_response);
+        }
+    }
+
+    /// Release a reserved key so the request can be retried.
+    pub fn release(&self, key: &str) {
+        self.slots.remove(key);
+    }
+
+    /// Remove keys which have expired.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.slots
+            .retain(|_, record| now.duration_since(record.created) < self.ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_response() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        assert!(matches!(cache.reserve("key", b"a"), Reservation::Reserved));
+        assert!(matches!(
+            cache.reserve("key", b"a"),
+            Reservation::InProgress
+        ));
+
+        let response = Response::builder()
+            .header("authorization", "POP token")
+            .body(Body::from("ack"))
+            .unwrap();
+        let (cached_response, _) = CachedResponse::buffer(response).await.unwrap();
+        cache.complete("key", cached_response);
+
+        let replayed = match cache.reserve("key", b"a") {
+            Reservation::Complete(cached_response) => cached_response.to_response(),
+            reservation => panic!("unexpected reservation {:?}", reservation),
+        };
+        assert_eq!(replayed.headers()["authorization"], "POP token");
+        let body = warp::hyper::body::to_bytes(replayed.into_body())
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"ack");
+
+        // Failed requests can be retried
+        assert!(matches!(
+            cache.reserve("other", b"a"),
+            Reservation::Reserved
+        ));
+        cache.release("other");
+        assert!(matches!(
+            cache.reserve("other", b"a"),
+            Reservation::Reserved
+        ));
+    }
+
+    #[tokio::test]
+    async fn mismatched_request() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        assert!(matches!(cache.reserve("key", b"a"), Reservation::Reserved));
+        assert!(matches!(cache.reserve("key", b"b"), Reservation::Mismatch));
+
+        // A different request never receives the completed response
+        let (cached_response, _) = CachedResponse::buffer(Response::new(Body::from("ack")))
+            .await
+            .unwrap();
+        cache.complete("key", cached_response);
+        assert!(matches!(cache.reserve("key", b"b"), Reservation::Mismatch));
+        assert!(matches!(
+            cache.reserve("key", b"a"),
+            Reservation::Complete(_)
+        ));
+    }
+
+    #[test]
+    fn expire_keys() {
+        let cache = IdempotencyCache::new(Duration::from_millis(0));
+        assert!(matches!(cache.reserve("key", b"a"), Reservation::Reserved));
+        assert!(matches!(cache.reserve("key", b"b"), Reservation::Reserved));
+        cache.sweep();
+        assert!(cache.slots.is_empty());
+    }
+}
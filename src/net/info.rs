@@ -0,0 +1,129 @@
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use serde::Serialize;
+use warp::{
+    http::{header::CONTENT_TYPE, Response},
+    hyper::Body,
+};
+
+use crate::{
+    crypto::{signature_scheme_name, SUPPORTED_SIGNATURE_SCHEMES},
+    stamps::{stamp_type_name, SUPPORTED_STAMP_TYPES},
+    SETTINGS,
+};
+
+/// Version of the relay's HTTP API, incremented on breaking changes.
+const API_VERSION: u32 = 1;
+/// Oldest API version whose clients are still served.
+const MIN_API_VERSION: u32 = 1;
+/// Version of the BIP70 payment details in payment requests.
+const PAYMENT_DETAILS_VERSION: u32 = 1;
+/// Oldest payment details version clients must parse to pay for tokens.
+const MIN_PAYMENT_DETAILS_VERSION: u32 = 1;
+
+/// Oldest protocol versions the relay still supports, so clients can degrade gracefully.
+#[derive(Debug, Serialize)]
+pub struct MinVersions {
+    api: u32,
+    payment_details: u32,
+}
+
+/// Parameters messages' stamps are checked against.
+#[derive(Debug, Serialize)]
+pub struct StampParameters {
+    required: bool,
+    min_stamp_value: u64,
+    fee_rate_multiplier: Option<f64>,
+    broadcast: bool,
+}
+
+/// Static information about the relay used by clients to check compatibility.
+#[derive(Debug, Serialize)]
+pub struct Info {
+    version: &'static str,
+    api_version: u32,
+    network: String,
+    features: Vec<&'static str>,
+    signature_schemes: Vec<&'static str>,
+    stamp_types: Vec<&'static str>,
+    stamps: StampParameters,
+    payment_details_version: u32,
+    min_versions: MinVersions,
+}
+
+impl Info {
+    pub fn new() -> Self {
+        let features = vec![
+            #[cfg(feature = "monitoring")]
+            "monitoring",
+        ];
+
+        Self {
+            version: crate_version!(),
+            api_version: API_VERSION,
+            network: SETTINGS.network.to_string(),
+            features,
+            signature_schemes: SUPPORTED_SIGNATURE_SCHEMES
+                .iter()
+                .copied()
+                .map(signature_scheme_name)
+                .collect(),
+            stamp_types: SUPPORTED_STAMP_TYPES
+                .iter()
+                .copied()
+                .map(stamp_type_name)
+                .collect(),
+            stamps: StampParameters {
+                required: SETTINGS.stamps.require_stamp,
+                min_stamp_value: SETTINGS.stamps.min_stamp_value,
+                fee_rate_multiplier: SETTINGS.stamps.fee_rate_multiplier,
+                broadcast: SETTINGS.stamps.require_stamp && SETTINGS.stamps.broadcast,
+            },
+            payment_details_version: PAYMENT_DETAILS_VERSION,
+            min_versions: MinVersions {
+                api: MIN_API_VERSION,
+                payment_details: MIN_PAYMENT_DETAILS_VERSION,
+            },
+        }
+    }
+}
+
+impl Default for Info {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn get_info(raw_info: Bytes) -> Result<Response<Body>, Infallible> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(raw_info))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialized_info() {
+        let info = serde_json::to_value(Info::new()).unwrap();
+        assert_eq!(info["version"], crate_version!());
+        assert_eq!(info["api_version"], API_VERSION);
+        assert_eq!(info["network"], SETTINGS.network.to_string());
+        assert_eq!(info["signature_schemes"], serde_json::json!(["ecdsa"]));
+        assert_eq!(
+            info["stamp_types"],
+            serde_json::json!(["message_commitment"])
+        );
+        assert_eq!(info["payment_details_version"], PAYMENT_DETAILS_VERSION);
+
+        // Clients compare their versions against the minimums
+        assert_eq!(info["min_versions"]["api"], MIN_API_VERSION);
+        assert_eq!(
+            info["min_versions"]["payment_details"],
+            MIN_PAYMENT_DETAILS_VERSION
+        );
+    }
+}
@@ -1,40 +1,77 @@
 use std::{
     convert::TryFrom,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use bitcoincash_addr::Address;
 use bytes::Bytes;
 use cashweb::{
-    bitcoin_client::{BitcoinClient, HttpClient, HttpError, NodeError},
-    relay::{stamp::StampError, *},
+    bitcoin::transaction::transaction_id,
+    bitcoin_client::{HttpError, NodeError},
+    relay::{stamp::Stamp, *},
 };
-use futures::future;
 use hex::FromHexError;
+use httpdate::{fmt_http_date, parse_http_date};
 use prost::Message as _;
 use ring::digest::{digest, SHA256};
 use ripemd160::{Digest, Ripemd160};
 use rocksdb::Error as RocksError;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::warn;
-use warp::{http::Response, hyper::Body, reject::Reject};
+use warp::{
+    http::{
+        header::{
+            HeaderMap, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, IF_MODIFIED_SINCE,
+            LAST_MODIFIED, RANGE, VARY,
+        },
+        Response,
+    },
+    hyper::Body,
+    reject::Reject,
+};
 
-use super::{ws::MessageBus, IntoResponse};
+use super::{
+    negotiate, ws::MessageBus, IntoResponse, JsonMessage, JsonMessagePage, Moderation,
+    ModerationError, NotAcceptable, Representation,
+};
 use crate::{
-    db::{self, Database},
+    crypto::{
+        parse_deletion, verify_auth_wrapper, CryptoError, DELETE_FEEDS_OPERATION,
+        DELETE_MESSAGES_OPERATION,
+    },
+    db::{self, Database, SignedDeletion, FEED_NAMESPACE},
+    models::wrapper::AuthWrapper,
+    node::{self, NodeClient},
+    stamps::{self, StampError},
     SETTINGS,
 };
 
-#[derive(Debug, Deserialize)]
+#[cfg(feature = "monitoring")]
+use crate::monitoring;
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Query {
     start_digest: Option<String>,
     end_digest: Option<String>,
     start_time: Option<u64>,
     end_time: Option<u64>,
     digest: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Digests of the messages to fetch, hex encoded.
+#[derive(Debug, Deserialize)]
+pub struct DigestQuery {
+    digests: Vec<String>,
 }
 
+pub const HAS_MORE_HEADER: &str = "X-Has-More";
+pub const TRUNCATED_HEADER: &str = "X-Truncated";
+pub const MESSAGE_COUNT_HEADER: &str = "X-Message-Count";
+pub const STAMP_TXID_HEADER: &str = "X-Stamp-Txid";
+
 #[derive(Debug, Error)]
 pub enum GetMessageError {
     #[error("failed to read from database: {0}")]
@@ -43,8 +80,12 @@ pub enum GetMessageError {
     DigestDecode(FromHexError),
     #[error("destination malformed")]
     DestinationMalformed,
+    #[error("destination does not match the address")]
+    DestinationMismatch,
     #[error("message not found")]
     NotFound,
+    #[error("stored message is malformed: {0}")]
+    Malformed(prost::DecodeError),
     #[error("both start time and digest given")]
     StartBothGiven,
     #[error("failed to decode start digest: {0}")]
@@ -59,6 +100,10 @@ pub enum GetMessageError {
     EndDigestMalformed(FromHexError),
     #[error("end digest not found")]
     EndDigestNotFound,
+    #[error("expected at most {0} digests, found {1}")]
+    TooManyDigests(usize, usize),
+    #[error(transparent)]
+    NotAcceptable(#[from] NotAcceptable),
 }
 
 impl From<RocksError> for GetMessageError {
@@ -72,11 +117,32 @@ impl Reject for GetMessageError {}
 impl IntoResponse for GetMessageError {
     fn to_status(&self) -> u16 {
         match self {
-            Self::DB(_) => 500,
+            Self::DB(_) | Self::Malformed(_) => 500,
             Self::NotFound => 404,
+            Self::NotAcceptable(_) => 406,
             _ => 400,
         }
     }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::DB(_) => "DATABASE",
+            Self::DigestDecode(_) => "DIGEST_DECODE",
+            Self::DestinationMalformed => "DESTINATION_MALFORMED",
+            Self::DestinationMismatch => "DESTINATION_MISMATCH",
+            Self::NotFound => "MESSAGE_NOT_FOUND",
+            Self::Malformed(_) => "MALFORMED_MESSAGE",
+            Self::StartBothGiven => "START_BOTH_GIVEN",
+            Self::StartDigestMalformed(_) => "START_DIGEST_MALFORMED",
+            Self::StartDigestNotFound => "START_DIGEST_NOT_FOUND",
+            Self::MissingStart => "MISSING_START",
+            Self::EndBothGiven => "END_BOTH_GIVEN",
+            Self::EndDigestMalformed(_) => "END_DIGEST_MALFORMED",
+            Self::EndDigestNotFound => "END_DIGEST_NOT_FOUND",
+            Self::TooManyDigests(..) => "TOO_MANY_DIGESTS",
+            Self::NotAcceptable(_) => "NOT_ACCEPTABLE",
+        }
+    }
 }
 
 fn get_unix_now() -> u64 {
@@ -89,6 +155,84 @@ fn get_unix_now() -> u64 {
     .expect("we're in the distant future")
 }
 
+/// Requested page size, capped at the configured maximum page size and at the maximum number of
+/// messages per response.
+///
+/// Also returns whether the response cap, rather than the client, bounds the page.
+fn page_limit(query: &Query) -> (usize, bool) {
+    let max_messages = SETTINGS.limits.max_messages_per_response;
+    match query
+        .limit
+        .map(|limit| limit.min(SETTINGS.limits.max_page_size as usize))
+    {
+        Some(limit) if limit <= max_messages => (limit, false),
+        _ => (max_messages, true),
+    }
+}
+
+/// Parse a single byte range from the `Range` header, resolved against the body length.
+///
+/// Returns `None` when the header is absent, malformed or lists several ranges, in which case the
+/// whole body is served, and `Some(None)` when the range can't be satisfied.
+fn byte_range(header_map: &HeaderMap, body_len: usize) -> Option<Option<(usize, usize)>> {
+    let range_str = header_map.get(RANGE)?.to_str().ok()?;
+    let spec = range_str.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start_str, end_str) = spec.split_at(spec.find('-')?);
+    let end_str = &end_str[1..];
+    let range = match (start_str.trim(), end_str.trim()) {
+        ("", suffix) => {
+            let suffix_len: usize = suffix.parse().ok()?;
+            if suffix_len == 0 {
+                None
+            } else {
+                Some((body_len.saturating_sub(suffix_len), body_len))
+            }
+        }
+        (start, "") => {
+            let start: usize = start.parse().ok()?;
+            Some((start, body_len))
+        }
+        (start, end) => {
+            let start: usize = start.parse().ok()?;
+            let end: usize = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            Some((start, end.saturating_add(1).min(body_len)))
+        }
+    };
+    Some(range.filter(|(start, _)| *start < body_len))
+}
+
+/// Respond with the body, or the requested part of it.
+fn ranged_response(header_map: &HeaderMap, content_type: &str, body: Vec<u8>) -> Response<Body> {
+    let builder = Response::builder()
+        .header(ACCEPT_RANGES, "bytes")
+        .header(CONTENT_TYPE, content_type)
+        .header(VARY, "accept");
+    let body_len = body.len();
+    match byte_range(header_map, body_len) {
+        None => builder.body(Body::from(body)).unwrap(),
+        Some(Some((start, end))) => builder
+            .status(206)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, body_len),
+            )
+            .body(Body::from(body[start..end].to_vec()))
+            .unwrap(),
+        Some(None) => builder
+            .status(416)
+            .header(CONTENT_RANGE, format!("bytes */{}", body_len))
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
 fn construct_prefixes(
     addr_payload: &[u8],
     query: Query,
@@ -142,16 +286,20 @@ pub async fn get_payloads(
         let raw_message = database
             .get_message_by_digest(&address_payload, &raw_digest[..], namespace)?
             .ok_or(GetMessageError::NotFound)?;
-        let message = Message::decode(&raw_message[..]).unwrap(); // This is safe
+        let message = Message::decode(&raw_message[..]).map_err(GetMessageError::Malformed)?;
         return Ok(Response::builder()
             .body(Body::from(message.payload))
             .unwrap());
     }
 
+    let (limit, capped) = page_limit(&query);
     let (start_prefix, end_prefix) =
         construct_prefixes(&address_payload, query, &database, namespace)?;
-    let message_page =
-        database.get_messages_range(&start_prefix, end_prefix.as_ref().map(|v| &v[..]))?;
+    let (message_page, has_more) = database.get_messages_range(
+        &start_prefix,
+        end_prefix.as_ref().map(|v| &v[..]),
+        Some(limit),
+    )?;
     let payload_page = message_page.into_payload_page();
 
     // Serialize messages
@@ -160,67 +308,370 @@ pub async fn get_payloads(
 
     // Respond
     Ok(Response::builder()
+        .header(HAS_MORE_HEADER, has_more.to_string())
+        .header(TRUNCATED_HEADER, (capped && has_more).to_string())
         .body(Body::from(raw_payload_page))
         .unwrap()) // TODO: Headers
 }
 
+pub async fn count_messages(
+    addr: Address,
+    database: Database,
+    namespace: u8,
+) -> Result<Response<Body>, GetMessageError> {
+    let count = database.count_messages(addr.as_body(), namespace)?;
+
+    Ok(Response::builder()
+        .header(MESSAGE_COUNT_HEADER, count.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
 pub async fn get_messages(
     addr: Address,
     query: Query,
+    header_map: HeaderMap,
     database: Database,
     namespace: u8,
 ) -> Result<Response<Body>, GetMessageError> {
+    let representation = negotiate(&header_map)?;
+
     // Extract address payload
     let address_payload = addr.as_body();
 
     // If digest query then get single message
     if let Some(digest) = query.digest {
         let raw_digest = hex::decode(digest).map_err(GetMessageError::DigestDecode)?;
-        let message = database
+        let raw_message = database
             .get_message_by_digest(&address_payload, &raw_digest[..], namespace)?
             .ok_or(GetMessageError::NotFound)?;
-        return Ok(Response::builder().body(Body::from(message)).unwrap());
+
+        #[cfg(feature = "monitoring")]
+        monitoring::observe_messages("get", 1);
+
+        let body = match representation {
+            Representation::Protobuf => raw_message,
+            Representation::Json => {
+                let message =
+                    Message::decode(&raw_message[..]).map_err(GetMessageError::Malformed)?;
+                serde_json::to_vec(&JsonMessage::from(message)).unwrap() // This is safe
+            }
+        };
+        return Ok(ranged_response(
+            &header_map,
+            representation.content_type(),
+            body,
+        ));
     }
 
+    // Validate the range before answering from the client's cache
+    let (limit, capped) = page_limit(&query);
     let (start_prefix, end_prefix) =
         construct_prefixes(&address_payload, query, &database, namespace)?;
-    let message_set =
-        database.get_messages_range(&start_prefix, end_prefix.as_ref().map(|v| &v[..]))?;
+
+    // Check whether a message arrived since the client last polled
+    let last_modified = last_modified(&database, address_payload, namespace)?;
+    if let Some(last_modified) = last_modified {
+        if not_modified_since(&header_map, last_modified) {
+            return Ok(Response::builder()
+                .status(304)
+                .header(LAST_MODIFIED, fmt_http_date(last_modified))
+                .header(VARY, "accept")
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+
+    let (message_set, has_more) = database.get_messages_range(
+        &start_prefix,
+        end_prefix.as_ref().map(|v| &v[..]),
+        Some(limit),
+    )?;
+
+    #[cfg(feature = "monitoring")]
+    monitoring::observe_messages("get", message_set.messages.len());
+
+    // Serialize messages
+    let raw_message_page = match representation {
+        Representation::Protobuf => {
+            let mut raw_message_page = Vec::with_capacity(message_set.encoded_len());
+            message_set.encode(&mut raw_message_page).unwrap();
+            raw_message_page
+        }
+        Representation::Json => {
+            serde_json::to_vec(&JsonMessagePage::from(message_set)).unwrap() // This is safe
+        }
+    };
+
+    // Respond
+    let mut builder = Response::builder()
+        .header(HAS_MORE_HEADER, has_more.to_string())
+        .header(TRUNCATED_HEADER, (capped && has_more).to_string())
+        .header(CONTENT_TYPE, representation.content_type())
+        .header(VARY, "accept");
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(LAST_MODIFIED, fmt_http_date(last_modified));
+    }
+    Ok(builder.body(Body::from(raw_message_page)).unwrap())
+}
+
+/// The time of the most recent message, truncated to seconds as in HTTP dates.
+///
+/// Messages received in the current second are omitted, as a later message in the same second
+/// would share the date.
+fn last_modified(
+    database: &Database,
+    address_payload: &[u8],
+    namespace: u8,
+) -> Result<Option<SystemTime>, RocksError> {
+    let now_secs = get_unix_now() / 1000;
+    let last_modified = database
+        .get_last_message_time(address_payload, namespace)?
+        .map(|timestamp| timestamp / 1000)
+        .filter(|secs| *secs < now_secs)
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    Ok(last_modified)
+}
+
+/// Check whether the `If-Modified-Since` header is no earlier than the last modification.
+fn not_modified_since(header_map: &HeaderMap, last_modified: SystemTime) -> bool {
+    header_map
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_http_date(value).ok())
+        .map(|if_modified_since| last_modified <= if_modified_since)
+        .unwrap_or(false)
+}
+
+/// Get the messages with the given digests, omitting those which aren't found.
+pub async fn query_messages(
+    addr: Address,
+    query: DigestQuery,
+    database: Database,
+    namespace: u8,
+) -> Result<Response<Body>, GetMessageError> {
+    let max_digests = SETTINGS.limits.max_page_size as usize;
+    if query.digests.len() > max_digests {
+        return Err(GetMessageError::TooManyDigests(
+            max_digests,
+            query.digests.len(),
+        ));
+    }
+
+    let raw_digests = query
+        .digests
+        .iter()
+        .map(hex::decode)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(GetMessageError::DigestDecode)?;
+    let raw_messages =
+        database.get_raw_messages_by_digests(addr.as_body(), &raw_digests, namespace)?;
+    let messages = raw_messages
+        .iter()
+        .map(|raw_message| Message::decode(&raw_message[..]))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(GetMessageError::Malformed)?;
+    let message_set = MessageSet { messages };
+
+    #[cfg(feature = "monitoring")]
+    monitoring::observe_messages("get", message_set.messages.len());
 
     // Serialize messages
-    let mut raw_message_page = Vec::with_capacity(message_set.encoded_len());
-    message_set.encode(&mut raw_message_page).unwrap();
+    let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+    message_set.encode(&mut raw_message_set).unwrap();
 
     // Respond
     Ok(Response::builder()
-        .body(Body::from(raw_message_page))
-        .unwrap()) // TODO: Headers
+        .body(Body::from(raw_message_set))
+        .unwrap())
+}
+
+#[derive(Debug, Error)]
+pub enum DeleteMessagesError {
+    #[error("failed to delete from database: {0}")]
+    DB(RocksError),
+    #[error(transparent)]
+    Query(#[from] GetMessageError),
+    #[error("failed to decode authorization wrapper: {0}")]
+    WrapperDecode(prost::DecodeError),
+    #[error(transparent)]
+    Auth(CryptoError),
+    #[error("expected deletion payload")]
+    UnexpectedPayload,
+    #[error("public key does not match address")]
+    UnexpectedPublicKey,
+    #[error("deletion is outdated")]
+    Outdated,
+}
+
+impl From<RocksError> for DeleteMessagesError {
+    fn from(err: RocksError) -> Self {
+        Self::DB(err)
+    }
+}
+
+impl Reject for DeleteMessagesError {}
+
+impl IntoResponse for DeleteMessagesError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::DB(_) => 500,
+            Self::Query(err) => err.to_status(),
+            _ => 400,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::DB(_) => "DATABASE",
+            Self::Query(err) => err.to_code(),
+            Self::WrapperDecode(_) => "WRAPPER_DECODE",
+            Self::Auth(CryptoError::Parse(_)) => "WRAPPER_PARSE",
+            Self::Auth(CryptoError::Verify(_)) => "WRAPPER_VERIFY",
+            Self::UnexpectedPayload => "UNEXPECTED_PAYLOAD",
+            Self::UnexpectedPublicKey => "UNEXPECTED_PUBLIC_KEY",
+            Self::Outdated => "DELETION_OUTDATED",
+        }
+    }
 }
 
 pub async fn remove_messages(
     addr: Address,
     query: Query,
+    wrapper_raw: Bytes,
     database: Database,
     namespace: u8,
-) -> Result<Response<Body>, GetMessageError> {
+) -> Result<Response<Body>, DeleteMessagesError> {
+    // Decode authorization wrapper
+    let wrapper = AuthWrapper::decode(wrapper_raw).map_err(DeleteMessagesError::WrapperDecode)?;
+
+    // Verify signatures
+    let parsed_wrapper = verify_auth_wrapper(wrapper).map_err(DeleteMessagesError::Auth)?;
+
+    // Only a timestamped deletion of this namespace authorizes deletion
+    let operation = if namespace == FEED_NAMESPACE {
+        DELETE_FEEDS_OPERATION
+    } else {
+        DELETE_MESSAGES_OPERATION
+    };
+    let timestamp = parse_deletion(&parsed_wrapper.payload, operation)
+        .ok_or(DeleteMessagesError::UnexpectedPayload)?;
+
+    // Check the signer owns the address
+    let raw_public_key = parsed_wrapper.public_key.serialize();
+    let pubkey_hash = Ripemd160::digest(digest(&SHA256, &raw_public_key).as_ref());
+    if addr.as_body() != &pubkey_hash[..] {
+        return Err(DeleteMessagesError::UnexpectedPublicKey);
+    }
+
     // Convert address
     let address_payload = addr.as_body();
 
-    // If digest query then get single message
-    if let Some(digest) = query.digest {
+    // Each deletion must be newer than the last, so it can't be replayed against messages
+    // received since. The time is only recorded with a deletion, once the query is validated.
+    let deletion = if let Some(digest) = query.digest {
         let raw_digest = hex::decode(digest).map_err(GetMessageError::DigestDecode)?;
         database
-            .remove_message_by_digest(&address_payload, &raw_digest[..], namespace)?
-            .ok_or(GetMessageError::NotFound)?;
-        return Ok(Response::builder().body(Body::empty()).unwrap());
+            .remove_message_by_digest(&address_payload, &raw_digest[..], namespace, timestamp)?
+            .map(|_| 1)
+    } else {
+        let (start_prefix, end_prefix) =
+            construct_prefixes(&address_payload, query, &database, namespace)?;
+        database.delete_messages_range(
+            &start_prefix,
+            end_prefix.as_ref().map(|v| &v[..]),
+            timestamp,
+        )?
+    };
+    let count = match deletion {
+        SignedDeletion::Deleted(count) => count,
+        SignedDeletion::NotFound => return Err(GetMessageError::NotFound.into()),
+        SignedDeletion::Outdated => return Err(DeleteMessagesError::Outdated),
+    };
+
+    // Respond
+    Ok(Response::builder()
+        .body(Body::from(count.to_string()))
+        .unwrap())
+}
+
+#[derive(Debug, Error)]
+pub enum AckMessageError {
+    #[error("failed to write to database: {0}")]
+    DB(#[from] RocksError),
+    #[error("failed to decode digest: {0}")]
+    DigestDecode(FromHexError),
+    #[error("not found")]
+    NotFound,
+    #[error("failed to decode authorization wrapper: {0}")]
+    WrapperDecode(prost::DecodeError),
+    #[error(transparent)]
+    Auth(CryptoError),
+    #[error("expected payload to be the message digest")]
+    UnexpectedPayload,
+    #[error("public key does not match address")]
+    UnexpectedPublicKey,
+}
+
+impl Reject for AckMessageError {}
+
+impl IntoResponse for AckMessageError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::DB(_) => 500,
+            Self::NotFound => 404,
+            _ => 400,
+        }
     }
 
-    let (start_prefix, end_prefix) =
-        construct_prefixes(&address_payload, query, &database, namespace)?;
-    database.remove_messages_range(&start_prefix, end_prefix.as_ref().map(|v| &v[..]))?;
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::DB(_) => "DATABASE",
+            Self::DigestDecode(_) => "DIGEST_DECODE",
+            Self::NotFound => "NOT_FOUND",
+            Self::WrapperDecode(_) => "WRAPPER_DECODE",
+            Self::Auth(CryptoError::Parse(_)) => "WRAPPER_PARSE",
+            Self::Auth(CryptoError::Verify(_)) => "WRAPPER_VERIFY",
+            Self::UnexpectedPayload => "UNEXPECTED_PAYLOAD",
+            Self::UnexpectedPublicKey => "UNEXPECTED_PUBLIC_KEY",
+        }
+    }
+}
+
+/// Acknowledge a message, authorized by a wrapper over the message digest signed by the owner of
+/// the address.
+pub async fn ack_message(
+    addr: Address,
+    digest_str: String,
+    wrapper_raw: Bytes,
+    database: Database,
+) -> Result<Response<Body>, AckMessageError> {
+    let raw_digest = hex::decode(digest_str).map_err(AckMessageError::DigestDecode)?;
+
+    // Decode authorization wrapper
+    let wrapper = AuthWrapper::decode(wrapper_raw).map_err(AckMessageError::WrapperDecode)?;
+
+    // Verify signatures
+    let parsed_wrapper = verify_auth_wrapper(wrapper).map_err(AckMessageError::Auth)?;
+
+    // Bind the acknowledgement to the message
+    if parsed_wrapper.payload != raw_digest {
+        return Err(AckMessageError::UnexpectedPayload);
+    }
+
+    // Check the signer owns the address
+    let raw_public_key = parsed_wrapper.public_key.serialize();
+    let pubkey_hash = Ripemd160::digest(digest(&SHA256, &raw_public_key).as_ref());
+    if addr.as_body() != &pubkey_hash[..] {
+        return Err(AckMessageError::UnexpectedPublicKey);
+    }
+
+    database
+        .ack_message(addr.as_body(), &raw_digest, get_unix_now())?
+        .ok_or(AckMessageError::NotFound)?;
 
     // Respond
-    Ok(Response::builder().body(Body::empty()).unwrap()) // TODO: Headers
+    Ok(Response::builder().body(Body::empty()).unwrap())
 }
 
 #[derive(Debug, Error)]
@@ -229,6 +680,8 @@ pub enum PutMessageError {
     DB(RocksError),
     #[error("destination malformed")]
     DestinationMalformed,
+    #[error("destination does not match the address")]
+    DestinationMismatch,
     #[error("failed to decode message: {0}")]
     MessagesDecode(prost::DecodeError),
     #[error("failed to parse message: {0}")]
@@ -239,6 +692,8 @@ pub enum PutMessageError {
     StampVerify(StampError),
     #[error("failed to broadcast stamp: {0}")]
     StampBroadcast(HttpError),
+    #[error(transparent)]
+    Moderated(ModerationError),
 }
 
 impl From<RocksError> for PutMessageError {
@@ -254,21 +709,264 @@ impl IntoResponse for PutMessageError {
         match self {
             Self::DB(_) => 500,
             Self::StampVerify(_) => 400,
-            Self::StampBroadcast(err) => match err {
-                NodeError::Rpc(_) => 400,
-                _ => 500,
-            },
+            Self::StampBroadcast(err) => node::error_status(err),
+            Self::Moderated(err) => err.to_status(),
             _ => 400,
         }
     }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::DB(_) => "DATABASE",
+            Self::DestinationMalformed => "DESTINATION_MALFORMED",
+            Self::DestinationMismatch => "DESTINATION_MISMATCH",
+            Self::MessagesDecode(_) => "MESSAGES_DECODE",
+            Self::MessageParsing(ParseError::Digest(DigestError::FraudulentDigest)) => {
+                "FRAUDULENT_DIGEST"
+            }
+            Self::MessageParsing(_) => "MESSAGE_PARSE",
+            Self::PayloadDecode(_) => "PAYLOAD_DECODE",
+            Self::StampVerify(_) => "STAMP_VERIFY",
+            Self::StampBroadcast(_) => "STAMP_BROADCAST",
+            Self::Moderated(err) => err.to_code(),
+        }
+    }
+}
+
+/// A message which passed verification and whose stamp has been broadcast.
+struct VerifiedMessage {
+    source_pubkey_hash: Vec<u8>,
+    destination_pubkey_hash: Vec<u8>,
+    payload_digest: [u8; 32],
+    raw_message: Vec<u8>,
+    raw_message_ws: Vec<u8>,
+    is_self_send: bool,
+    stamp_txids: Vec<String>,
+}
+
+impl VerifiedMessage {
+    fn entries(&self) -> [db::MessageEntry<'_>; 2] {
+        [
+            db::MessageEntry {
+                pubkey_hash: &self.source_pubkey_hash,
+                raw_message: &self.raw_message,
+                digest: &self.payload_digest,
+            },
+            db::MessageEntry {
+                pubkey_hash: &self.destination_pubkey_hash,
+                raw_message: &self.raw_message,
+                digest: &self.payload_digest,
+            },
+        ]
+    }
+}
+
+/// The minimum stamp value, scaled by the node's fee rate if configured.
+async fn min_stamp_value(stamp: &Stamp, bitcoin_client: &NodeClient) -> u64 {
+    let static_minimum = SETTINGS.stamps.min_stamp_value;
+    let multiplier = match SETTINGS.stamps.fee_rate_multiplier {
+        Some(some) => some,
+        None => return static_minimum,
+    };
+
+    let fee_rate_ttl = Duration::from_millis(SETTINGS.stamps.fee_rate_ttl);
+    match bitcoin_client.fee_rate(fee_rate_ttl).await {
+        Ok(fee_rate) => {
+            let tx_size = stamp
+                .stamp_outpoints
+                .iter()
+                .map(|stamp_outpoint| stamp_outpoint.stamp_tx.len())
+                .sum();
+            stamps::fee_rate_minimum(tx_size, fee_rate, multiplier).max(static_minimum)
+        }
+        Err(err) => {
+            warn!(message = "failed to estimate fee rate, using static minimum", error = %err);
+            static_minimum
+        }
+    }
+}
+
+/// Ask the node whether the stamp transaction would be accepted, surfacing its reject reason.
+///
+/// Stamps already in the mempool, such as those broadcast by the client or by an earlier attempt,
+/// pass. Nodes without the `testmempoolaccept` method skip the precheck.
+async fn precheck_stamp_tx(
+    stamp_tx: &[u8],
+    bitcoin_client: &NodeClient,
+) -> Result<(), PutMessageError> {
+    let acceptance = match bitcoin_client
+        .call(|client| async move { node::test_accept(&client, stamp_tx).await })
+        .await
+    {
+        Ok(acceptance) => acceptance,
+        Err(NodeError::Rpc(err)) if err.code == node::RPC_METHOD_NOT_FOUND => return Ok(()),
+        Err(err) => return Err(PutMessageError::StampBroadcast(err)),
+    };
+    if acceptance.is_accepted() {
+        return Ok(());
+    }
+
+    let err = StampError::Rejected(acceptance.reject_reason.unwrap_or_default());
+    #[cfg(feature = "monitoring")]
+    monitoring::observe_stamp_rejection(&err);
+    Err(PutMessageError::StampVerify(err))
+}
+
+async fn verify_message(
+    addr: &Address,
+    mut message: Message,
+    timestamp: u64,
+    bitcoin_client: &NodeClient,
+    moderation: &Moderation,
+    namespace: u8,
+) -> Result<VerifiedMessage, PutMessageError> {
+    // Set received time
+    message.received_time = timestamp as i64;
+
+    // Stampless relays accept messages without a stamp, store an empty one so they still parse
+    let require_stamp = SETTINGS.stamps.require_stamp;
+    if !require_stamp && message.stamp.is_none() {
+        message.stamp = Some(Stamp::default());
+    }
+
+    // Get sender public key
+    let source_pubkey = &message.source_public_key;
+    let destination_pubkey = &message.destination_public_key;
+    let source_pubkey_hash = Ripemd160::digest(digest(&SHA256, &source_pubkey).as_ref());
+    let destination_pubkey_hash = Ripemd160::digest(digest(&SHA256, &destination_pubkey).as_ref());
+
+    // Check the URL address, which rate limits are keyed on, is party to the message. Messages
+    // are put to their destination while feeds are put by their owner.
+    let addr_payload = addr.as_body();
+    let is_party = addr_payload == &destination_pubkey_hash[..]
+        || (namespace == FEED_NAMESPACE && addr_payload == &source_pubkey_hash[..]);
+    if !is_party {
+        return Err(PutMessageError::DestinationMismatch);
+    }
+
+    // Moderated keys can neither send nor receive messages
+    for pubkey_hash in &[&source_pubkey_hash, &destination_pubkey_hash] {
+        moderation
+            .check_payload(&pubkey_hash[..])
+            .map_err(PutMessageError::Moderated)?;
+    }
+
+    // Serialze message which is stored in database
+    let encoded_length = message.encoded_len();
+    let mut raw_message = Vec::with_capacity(encoded_length);
+    message.encode(&mut raw_message).unwrap(); // This is safe
+
+    // TODO: Parse does not enforce there is *ACTUALLY* a payload, only that there is a
+    // payload digest. If the client is putting a message without a payload and only
+    // a payload digest, there won't be any way to recover it and it'll create downstream
+    // errors.
+    //
+    // This needs to be fixed.
+    let parsed_message = message.parse().map_err(PutMessageError::MessageParsing)?;
+
+    let is_self_send = destination_pubkey_hash == source_pubkey_hash;
+
+    // If sender is not self then check stamp
+    if require_stamp && !is_self_send {
+        let min_stamp_value = min_stamp_value(&parsed_message.stamp, bitcoin_client).await;
+        stamps::verify_stamp(
+            &parsed_message.stamp,
+            &parsed_message.payload_digest,
+            &parsed_message.destination_public_key,
+            min_stamp_value,
+        )
+        .map_err(|err| {
+            #[cfg(feature = "monitoring")]
+            monitoring::observe_stamp_rejection(&err);
+            PutMessageError::StampVerify(err)
+        })?;
+    }
+
+    // Try broadcast stamp transactions, otherwise compute their txids locally
+    let stamp_txids = if require_stamp && SETTINGS.stamps.broadcast {
+        // Broadcast in order so that stamp transactions may spend their predecessors
+        let stamp_outpoints = &parsed_message.stamp.stamp_outpoints;
+        let mut stamp_txids = Vec::with_capacity(stamp_outpoints.len());
+        for stamp_outpoint in stamp_outpoints {
+            let stamp_tx = &stamp_outpoint.stamp_tx;
+            precheck_stamp_tx(stamp_tx, bitcoin_client).await?;
+            let stamp_txid = bitcoin_client
+                .call(|client| async move { client.send_tx(stamp_tx).await })
+                .await
+                .map_err(|err| {
+                    // Count stamps rejected by the node
+                    #[cfg(feature = "monitoring")]
+                    {
+                        if let NodeError::Rpc(_) = err {
+                            monitoring::observe_stamp_tx_rejection();
+                        }
+                    }
+                    PutMessageError::StampBroadcast(err)
+                })?;
+            stamp_txids.push(stamp_txid);
+        }
+        stamp_txids
+    } else {
+        parsed_message
+            .stamp
+            .stamp_outpoints
+            .iter()
+            .map(|stamp_outpoint| hex::encode(transaction_id(&stamp_outpoint.stamp_tx)))
+            .collect()
+    };
+
+    let payload_digest = parsed_message.payload_digest;
+
+    // If serialized payload too long then remove it
+    let raw_message_ws =
+        if parsed_message.payload.len() > SETTINGS.websocket.truncation_length as usize {
+            let mut pruned_message = parsed_message.into_message();
+            pruned_message.payload = Vec::with_capacity(0);
+            let mut pruned_raw_message = Vec::with_capacity(encoded_length);
+            pruned_message.encode(&mut pruned_raw_message).unwrap(); // This is safe
+            pruned_raw_message
+        } else {
+            raw_message.clone()
+        };
+
+    Ok(VerifiedMessage {
+        source_pubkey_hash: source_pubkey_hash.to_vec(),
+        destination_pubkey_hash: destination_pubkey_hash.to_vec(),
+        payload_digest,
+        raw_message,
+        raw_message_ws,
+        is_self_send,
+        stamp_txids,
+    })
+}
+
+fn notify_message(msg_bus: &MessageBus, message: VerifiedMessage) {
+    // Send to source
+    if !message.is_self_send {
+        if let Some(sender) = msg_bus.get(&message.source_pubkey_hash) {
+            if let Err(err) = sender.send(message.raw_message_ws.clone()) {
+                warn!(message = "failed to broadcast to source", error = ?err);
+                // TODO: Make prettier
+            }
+        }
+    }
+
+    // Send to destination
+    if let Some(sender) = msg_bus.get(&message.destination_pubkey_hash) {
+        if let Err(err) = sender.send(message.raw_message_ws) {
+            warn!(message = "failed to broadcast to destination", error = ?err);
+            // TODO: Make prettier
+        }
+    }
 }
 
 pub async fn put_message(
     addr: Address,
     messages_raw: Bytes,
     database: Database,
-    bitcoin_client: BitcoinClient<HttpClient>,
+    bitcoin_client: NodeClient,
     msg_bus: MessageBus,
+    moderation: Arc<Moderation>,
     namespace: u8,
 ) -> Result<Response<Body>, PutMessageError> {
     // Time now
@@ -278,107 +976,581 @@ pub async fn put_message(
     let message_set =
         MessageSet::decode(&messages_raw[..]).map_err(PutMessageError::MessagesDecode)?;
 
-    for mut message in message_set.messages.into_iter() {
-        // Set received time
-        message.received_time = timestamp as i64;
+    let mut stamp_txids = Vec::new();
+    for message in message_set.messages.into_iter() {
+        let mut verified_message = verify_message(
+            &addr,
+            message,
+            timestamp,
+            &bitcoin_client,
+            &moderation,
+            namespace,
+        )
+        .await?;
 
-        // Get sender public key
-        let source_pubkey = &message.source_public_key;
-        let destination_pubkey = &message.destination_public_key;
-        let source_pubkey_hash = Ripemd160::digest(digest(&SHA256, &source_pubkey).as_ref());
-        let destination_pubkey_hash =
-            Ripemd160::digest(digest(&SHA256, &destination_pubkey).as_ref());
+        // Push to source and destination keys
+        database.push_messages(timestamp, &verified_message.entries(), namespace)?;
 
-        // Check if URL address is correct
-        if addr.as_body() == &destination_pubkey_hash[..] {
-            // TODO: What do we do here? Exit
-        }
+        #[cfg(feature = "monitoring")]
+        monitoring::observe_messages("put", 1);
 
-        // Serialze message which is stored in database
-        let encoded_length = message.encoded_len();
-        let mut raw_message = Vec::with_capacity(encoded_length);
-        message.encode(&mut raw_message).unwrap(); // This is safe
-
-        // TODO: Parse does not enforce there is *ACTUALLY* a payload, only that there is a
-        // payload digest. If the client is putting a message without a payload and only
-        // a payload digest, there won't be any way to recover it and it'll create downstream
-        // errors.
-        //
-        // This needs to be fixed.
-        let parsed_message = message.parse().map_err(PutMessageError::MessageParsing)?;
-
-        let is_self_send = destination_pubkey_hash == source_pubkey_hash;
-
-        // If sender is not self then check stamp
-        if !is_self_send {
-            parsed_message
-                .verify_stamp()
-                .map_err(PutMessageError::StampVerify)?;
-        }
+        stamp_txids.append(&mut verified_message.stamp_txids);
+        notify_message(&msg_bus, verified_message);
+    }
 
-        // Try broadcast stamp transactions
-        let broadcast = parsed_message
-            .stamp
-            .stamp_outpoints
-            .iter()
-            .map(|stamp_oupoint| {
-                let bitcoin_client_inner = bitcoin_client.clone();
-                async move { bitcoin_client_inner.send_tx(&stamp_oupoint.stamp_tx).await }
-            });
+    // Respond with a header per stamp transaction
+    let builder = stamp_txids
+        .into_iter()
+        .fold(Response::builder(), |builder, txid| {
+            builder.header(STAMP_TXID_HEADER, txid)
+        });
+    Ok(builder.body(Body::empty()).unwrap())
+}
 
-        future::try_join_all(broadcast)
-            .await
-            .map_err(PutMessageError::StampBroadcast)?;
+#[derive(Debug, Serialize)]
+struct BatchFailure {
+    index: usize,
+    error: String,
+}
 
-        // Push to source key
-        database.push_message(
-            &source_pubkey_hash,
-            timestamp,
-            &raw_message[..],
-            &parsed_message.payload_digest[..],
-            namespace,
-        )?;
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    stored: usize,
+    failed: Vec<BatchFailure>,
+}
 
-        // Push to destination key
-        database.push_message(
-            &destination_pubkey_hash,
+/// Put a batch of messages, storing every valid message in a single write.
+///
+/// Invalid messages don't prevent the rest from being stored, instead their indices are reported
+/// in the response.
+pub async fn put_messages_batch(
+    addr: Address,
+    messages_raw: Bytes,
+    database: Database,
+    bitcoin_client: NodeClient,
+    msg_bus: MessageBus,
+    moderation: Arc<Moderation>,
+    namespace: u8,
+) -> Result<Response<Body>, PutMessageError> {
+    // Time now
+    let timestamp = get_unix_now();
+
+    // Decode message
+    let message_set =
+        MessageSet::decode(&messages_raw[..]).map_err(PutMessageError::MessagesDecode)?;
+
+    // Verify each message
+    let mut verified_messages = Vec::with_capacity(message_set.messages.len());
+    let mut failed = Vec::new();
+    for (index, message) in message_set.messages.into_iter().enumerate() {
+        match verify_message(
+            &addr,
+            message,
             timestamp,
-            &raw_message[..],
-            &parsed_message.payload_digest[..],
+            &bitcoin_client,
+            &moderation,
             namespace,
-        )?;
-
-        // If serialized payload too long then remove it
-        let raw_message_ws =
-            if parsed_message.payload.len() > SETTINGS.websocket.truncation_length as usize {
-                let mut pruned_message = parsed_message.into_message();
-                pruned_message.payload = Vec::with_capacity(0);
-                let mut pruned_raw_message = Vec::with_capacity(encoded_length);
-                pruned_message.encode(&mut pruned_raw_message).unwrap(); // This is safe
-                pruned_raw_message
-            } else {
-                raw_message
-            };
-
-        // Send to source
-        if !is_self_send {
-            if let Some(sender) = msg_bus.get(&source_pubkey_hash.to_vec()) {
-                if let Err(err) = sender.send(raw_message_ws.clone()) {
-                    warn!(message = "failed to broadcast to source", error = ?err);
-                    // TODO: Make prettier
-                }
-            }
+        )
+        .await
+        {
+            Ok(verified_message) => verified_messages.push(verified_message),
+            Err(err) => failed.push(BatchFailure {
+                index,
+                error: err.to_string(),
+            }),
         }
+    }
 
-        // Send to destination
-        if let Some(sender) = msg_bus.get(&destination_pubkey_hash.to_vec()) {
-            if let Err(err) = sender.send(raw_message_ws) {
-                warn!(message = "failed to broadcast to destination", error = ?err);
-                // TODO: Make prettier
-            }
-        }
+    // Push to source and destination keys
+    let entries: Vec<_> = verified_messages
+        .iter()
+        .flat_map(|verified_message| verified_message.entries().to_vec())
+        .collect();
+    database.push_messages(timestamp, &entries, namespace)?;
+
+    #[cfg(feature = "monitoring")]
+    monitoring::observe_messages("put", verified_messages.len());
+
+    let batch_result = BatchResult {
+        stored: verified_messages.len(),
+        failed,
+    };
+    for verified_message in verified_messages {
+        notify_message(&msg_bus, verified_message);
     }
 
     // Respond
-    Ok(Response::builder().body(Body::empty()).unwrap())
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&batch_result).unwrap())) // This is safe
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cashweb::secp256k1::{
+        key::{PublicKey, SecretKey},
+        Secp256k1,
+    };
+    use dashmap::DashMap;
+
+    use crate::{
+        db::MESSAGE_NAMESPACE,
+        net::{
+            address_encode,
+            fixtures::{sign_deletion, signer_address, signer_public_key, unreachable_node},
+        },
+    };
+
+    #[test]
+    fn parse_byte_range() {
+        let range = |value: &'static str| {
+            let mut header_map = HeaderMap::new();
+            header_map.insert(RANGE, value.parse().unwrap());
+            byte_range(&header_map, 100)
+        };
+
+        assert_eq!(byte_range(&HeaderMap::new(), 100), None);
+        assert_eq!(range("bytes=0-9"), Some(Some((0, 10))));
+        assert_eq!(range("bytes=90-"), Some(Some((90, 100))));
+        assert_eq!(range("bytes=-10"), Some(Some((90, 100))));
+        assert_eq!(range("bytes=90-200"), Some(Some((90, 100))));
+        assert_eq!(range("bytes=100-"), Some(None));
+        assert_eq!(range("bytes=0-9,20-29"), None);
+        assert_eq!(range("items=0-9"), None);
+    }
+
+    #[test]
+    fn page_limit_cap() {
+        let query = |limit| Query {
+            limit,
+            ..Default::default()
+        };
+        let max_messages = SETTINGS.limits.max_messages_per_response;
+        let max_page_size = SETTINGS.limits.max_page_size as usize;
+
+        // Clients which don't choose a page size are capped
+        assert_eq!(page_limit(&query(None)), (max_messages, true));
+        assert_eq!(page_limit(&query(Some(10))), (10, false));
+        assert_eq!(
+            page_limit(&query(Some(usize::MAX))),
+            (
+                max_page_size.min(max_messages),
+                max_page_size > max_messages
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn get_message_range() {
+        let database = Database::try_new("./test_dbs/get_message_range").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let message = Message {
+            payload: vec![0; 100],
+            ..Default::default()
+        };
+        let mut raw_message = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut raw_message).unwrap();
+        let raw_digest = digest(&SHA256, &raw_message).as_ref().to_vec();
+        database
+            .push_message(
+                addr.as_body(),
+                0,
+                &raw_message,
+                &raw_digest,
+                MESSAGE_NAMESPACE,
+            )
+            .unwrap();
+
+        let query = Query {
+            start_digest: None,
+            end_digest: None,
+            start_time: None,
+            end_time: None,
+            digest: Some(hex::encode(&raw_digest)),
+            limit: None,
+        };
+        let mut header_map = HeaderMap::new();
+        header_map.insert(RANGE, "bytes=10-".parse().unwrap());
+        let response = get_messages(addr, query, header_map, database, MESSAGE_NAMESPACE)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(
+            response.headers()[CONTENT_RANGE],
+            format!("bytes 10-{}/{}", raw_message.len() - 1, raw_message.len())
+        );
+        assert_eq!(response.headers()[VARY], "accept");
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(&body[..], &raw_message[10..]);
+    }
+
+    #[tokio::test]
+    async fn get_messages_not_modified() {
+        let path = "./test_dbs/get_messages_not_modified";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let push_message = |received_time: u64| {
+            let message = Message {
+                received_time: received_time as i64,
+                ..Default::default()
+            };
+            let mut raw_message = Vec::with_capacity(message.encoded_len());
+            message.encode(&mut raw_message).unwrap();
+            let raw_digest = digest(&SHA256, &raw_message).as_ref().to_vec();
+            database
+                .push_message(
+                    addr.as_body(),
+                    received_time,
+                    &raw_message,
+                    &raw_digest,
+                    MESSAGE_NAMESPACE,
+                )
+                .unwrap();
+        };
+        push_message(5_000);
+        let query = || Query {
+            start_time: Some(0),
+            ..Default::default()
+        };
+
+        let response = get_messages(
+            addr.clone(),
+            query(),
+            HeaderMap::new(),
+            database.clone(),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let last_modified = response.headers()[LAST_MODIFIED].clone();
+        assert_eq!(
+            last_modified,
+            fmt_http_date(UNIX_EPOCH + Duration::from_secs(5))
+        );
+
+        // Nothing new since the last poll
+        let mut header_map = HeaderMap::new();
+        header_map.insert(IF_MODIFIED_SINCE, last_modified);
+        let response = get_messages(
+            addr.clone(),
+            query(),
+            header_map.clone(),
+            database.clone(),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 304);
+
+        // The range is validated before answering from the client's cache
+        let result = get_messages(
+            addr.clone(),
+            Query::default(),
+            header_map.clone(),
+            database.clone(),
+            MESSAGE_NAMESPACE,
+        )
+        .await;
+        assert!(matches!(result, Err(GetMessageError::MissingStart)));
+
+        // A message arrived since the last poll
+        push_message(7_000);
+        let response = get_messages(addr, query(), header_map, database, MESSAGE_NAMESPACE)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn put_fraudulent_digest() {
+        let database = Database::try_new("./test_dbs/put_fraudulent_digest").unwrap();
+        let bitcoin_client = unreachable_node();
+        let msg_bus: MessageBus = Arc::new(DashMap::new());
+
+        let raw_public_key = signer_public_key();
+        let addr = signer_address();
+
+        // Claim a digest which isn't the digest of the payload
+        let message = Message {
+            source_public_key: raw_public_key.clone(),
+            destination_public_key: raw_public_key,
+            payload: b"hello".to_vec(),
+            payload_digest: digest(&SHA256, b"goodbye").as_ref().to_vec(),
+            payload_hmac: vec![0; 32],
+            stamp: Some(Stamp::default()),
+            ..Default::default()
+        };
+        let message_set = MessageSet {
+            messages: vec![message],
+        };
+        let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+        message_set.encode(&mut raw_message_set).unwrap();
+        let err = put_message(
+            addr,
+            raw_message_set.into(),
+            database,
+            bitcoin_client,
+            msg_bus,
+            Arc::new(Moderation::default()),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            PutMessageError::MessageParsing(ParseError::Digest(DigestError::FraudulentDigest))
+        ));
+        assert_eq!(err.to_status(), 400);
+        assert_eq!(err.to_code(), "FRAUDULENT_DIGEST");
+    }
+
+    #[tokio::test]
+    async fn put_destination_mismatch() {
+        let database = Database::try_new("./test_dbs/put_destination_mismatch").unwrap();
+        let bitcoin_client = unreachable_node();
+        let msg_bus: MessageBus = Arc::new(DashMap::new());
+
+        let raw_public_key = signer_public_key();
+
+        // Put a message under an address other than its destination
+        let addr = Address {
+            body: vec![2; 20],
+            ..Default::default()
+        };
+        let message = Message {
+            source_public_key: raw_public_key.clone(),
+            destination_public_key: raw_public_key,
+            payload: b"hello".to_vec(),
+            payload_hmac: vec![0; 32],
+            stamp: Some(Stamp::default()),
+            ..Default::default()
+        };
+        let message_set = MessageSet {
+            messages: vec![message],
+        };
+        let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+        message_set.encode(&mut raw_message_set).unwrap();
+        let err = put_message(
+            addr,
+            raw_message_set.into(),
+            database,
+            bitcoin_client,
+            msg_bus,
+            Arc::new(Moderation::default()),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, PutMessageError::DestinationMismatch));
+        assert_eq!(err.to_status(), 400);
+    }
+
+    #[tokio::test]
+    async fn put_moderated_source() {
+        let database = Database::try_new("./test_dbs/put_moderated_source").unwrap();
+        let bitcoin_client = unreachable_node();
+        let msg_bus: MessageBus = Arc::new(DashMap::new());
+
+        let source_public_key = signer_public_key();
+        let destination_public_key = PublicKey::from_secret_key(
+            &Secp256k1::signing_only(),
+            &SecretKey::from_slice(&[2; 32]).unwrap(),
+        )
+        .serialize()
+        .to_vec();
+        let pubkey_hash =
+            |public_key: &[u8]| Ripemd160::digest(digest(&SHA256, public_key).as_ref()).to_vec();
+        let addr = Address {
+            body: pubkey_hash(&destination_public_key),
+            ..Default::default()
+        };
+
+        // A denied key can't send to an address which isn't moderated
+        let denied = address_encode(pubkey_hash(&source_public_key));
+        let moderation = Arc::new(Moderation::new(&[denied], None).unwrap());
+        let message = Message {
+            source_public_key,
+            destination_public_key,
+            payload: b"hello".to_vec(),
+            payload_hmac: vec![0; 32],
+            stamp: Some(Stamp::default()),
+            ..Default::default()
+        };
+        let message_set = MessageSet {
+            messages: vec![message],
+        };
+        let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+        message_set.encode(&mut raw_message_set).unwrap();
+        let err = put_message(
+            addr,
+            raw_message_set.into(),
+            database,
+            bitcoin_client,
+            msg_bus,
+            moderation,
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, PutMessageError::Moderated(_)));
+        assert_eq!(err.to_status(), 403);
+    }
+
+    #[tokio::test]
+    async fn put_batch_partial_failure() {
+        let database = Database::try_new("./test_dbs/put_batch_partial_failure").unwrap();
+        let bitcoin_client = unreachable_node();
+        let msg_bus: MessageBus = Arc::new(DashMap::new());
+
+        // Self-sent messages skip stamp verification
+        let raw_public_key = signer_public_key();
+        let addr = signer_address();
+
+        // Put a valid message followed by one missing its stamp
+        let message = Message {
+            source_public_key: raw_public_key.clone(),
+            destination_public_key: raw_public_key,
+            payload: b"hello".to_vec(),
+            payload_hmac: vec![0; 32],
+            stamp: Some(Stamp::default()),
+            ..Default::default()
+        };
+        let invalid_message = Message {
+            stamp: None,
+            ..message.clone()
+        };
+        let message_set = MessageSet {
+            messages: vec![message, invalid_message],
+        };
+        let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+        message_set.encode(&mut raw_message_set).unwrap();
+        let count_before = database
+            .count_messages(addr.as_body(), MESSAGE_NAMESPACE)
+            .unwrap();
+        let response = put_messages_batch(
+            addr.clone(),
+            raw_message_set.into(),
+            database.clone(),
+            bitcoin_client,
+            msg_bus,
+            Arc::new(Moderation::default()),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap();
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let batch_result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(batch_result["stored"], 1);
+        assert_eq!(batch_result["failed"][0]["index"], 1);
+        assert_eq!(
+            database
+                .count_messages(addr.as_body(), MESSAGE_NAMESPACE)
+                .unwrap(),
+            count_before + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_messages_replay() {
+        let path = "./test_dbs/remove_messages_replay";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let addr = signer_address();
+        let all = || Query {
+            start_time: Some(0),
+            ..Default::default()
+        };
+        let remove = |query, wrapper_raw, namespace| {
+            remove_messages(
+                addr.clone(),
+                query,
+                wrapper_raw,
+                database.clone(),
+                namespace,
+            )
+        };
+
+        // Deletions must be bound to the namespace
+        let err = remove(
+            all(),
+            sign_deletion(DELETE_FEEDS_OPERATION, 100),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, DeleteMessagesError::UnexpectedPayload));
+
+        // Invalid queries don't use up the deletion
+        let err = remove(
+            Query::default(),
+            sign_deletion(DELETE_MESSAGES_OPERATION, 100),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            DeleteMessagesError::Query(GetMessageError::MissingStart)
+        ));
+        let missing = Query {
+            digest: Some(hex::encode([0; 32])),
+            ..Default::default()
+        };
+        let err = remove(
+            missing,
+            sign_deletion(DELETE_MESSAGES_OPERATION, 100),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            DeleteMessagesError::Query(GetMessageError::NotFound)
+        ));
+
+        remove(
+            all(),
+            sign_deletion(DELETE_MESSAGES_OPERATION, 100),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap();
+        remove(
+            all(),
+            sign_deletion(DELETE_FEEDS_OPERATION, 100),
+            FEED_NAMESPACE,
+        )
+        .await
+        .unwrap();
+
+        // Replayed and older deletions are rejected
+        for timestamp in &[50, 100] {
+            let err = remove(
+                all(),
+                sign_deletion(DELETE_MESSAGES_OPERATION, *timestamp),
+                MESSAGE_NAMESPACE,
+            )
+            .await
+            .unwrap_err();
+            assert!(matches!(err, DeleteMessagesError::Outdated));
+            assert_eq!(err.to_code(), "DELETION_OUTDATED");
+        }
+    }
 }
@@ -1,22 +1,50 @@
+pub mod admin;
+pub mod body;
+pub mod client_ip;
+pub mod compression;
+#[cfg(test)]
+mod fixtures;
+pub mod health;
+pub mod idempotency;
+pub mod info;
 pub mod messages;
+pub mod moderation;
+pub mod negotiation;
 pub mod payments;
 pub mod profiles;
 pub mod protection;
+pub mod rate_limit;
+pub mod request_id;
 pub mod ws;
 
+pub use admin::*;
+pub use body::*;
+pub use client_ip::*;
+pub use compression::*;
+pub use health::*;
+pub use idempotency::*;
+pub use info::*;
 pub use messages::*;
+pub use moderation::*;
+pub use negotiation::*;
 pub use payments::*;
 pub use profiles::*;
 pub use protection::*;
+pub use rate_limit::*;
+pub use request_id::*;
 pub use ws::*;
 
 use std::{convert::Infallible, fmt};
 
-use bitcoincash_addr::Address;
+use bitcoincash_addr::{Address, Network as AddressNetwork, Scheme};
+use cashweb::bitcoin::Network;
+use serde::Serialize;
 use thiserror::Error;
 use tracing::error;
+
+use crate::SETTINGS;
 use warp::{
-    http::Response,
+    http::{header::CONTENT_TYPE, Response},
     hyper::Body,
     reject::{PayloadTooLarge, Reject, Rejection},
 };
@@ -30,10 +58,16 @@ pub enum AddressDecode {
     ),
     #[error("expected address payload of length 20, found {0}")]
     UnexpectedBodyLength(usize),
+    #[error("address is not on the {0} network")]
+    MismatchedNetwork(Network),
 }
 
 impl Reject for AddressDecode {}
 
+/// Decode a cash or legacy address.
+///
+/// The address is normalized to a cash address on the configured network, so both encodings of a
+/// key resolve to the same address and store keys.
 pub fn address_decode(addr_str: &str) -> Result<Address, AddressDecode> {
     // Convert address
     let address = Address::decode(&addr_str)
@@ -44,31 +78,114 @@ pub fn address_decode(addr_str: &str) -> Result<Address, AddressDecode> {
     if body_len != 20 {
         return Err(AddressDecode::UnexpectedBodyLength(body_len));
     }
-    Ok(address)
+
+    // Check address is on the configured network
+    if !on_network(&address, SETTINGS.network) {
+        return Err(AddressDecode::MismatchedNetwork(SETTINGS.network));
+    }
+    Ok(Address {
+        scheme: Scheme::CashAddr,
+        network: address_network(SETTINGS.network),
+        ..address
+    })
+}
+
+fn address_network(network: Network) -> AddressNetwork {
+    match network {
+        Network::Mainnet => AddressNetwork::Main,
+        Network::Testnet => AddressNetwork::Test,
+        Network::Regtest => AddressNetwork::Regtest,
+    }
+}
+
+fn on_network(address: &Address, network: Network) -> bool {
+    match (&address.scheme, &address.network) {
+        // Base58 addresses share a version byte between testnet and regtest
+        (Scheme::Base58, AddressNetwork::Test) => network != Network::Mainnet,
+        (_, addr_network) => *addr_network == address_network(network),
+    }
+}
+
+/// Encode an address payload as a cash address on the configured network.
+pub fn address_encode(addr_payload: Vec<u8>) -> String {
+    let address = Address {
+        body: addr_payload,
+        network: address_network(SETTINGS.network),
+        ..Default::default()
+    };
+    address.encode().unwrap() // This is safe
 }
 
 impl IntoResponse for AddressDecode {
     fn to_status(&self) -> u16 {
         400
     }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Decode(..) => "ADDRESS_DECODE",
+            Self::UnexpectedBodyLength(_) => "ADDRESS_LENGTH",
+            Self::MismatchedNetwork(_) => "MISMATCHED_NETWORK",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+}
+
+/// Construct a JSON error response.
+pub fn error_response(status: u16, code: &str, message: &str) -> Response<Body> {
+    error_response_with_memo(status, code, message, None)
+}
+
+/// Construct a JSON error response, carrying operator guidance such as a support URL.
+pub fn error_response_with_memo(
+    status: u16,
+    code: &str,
+    message: &str,
+    memo: Option<&str>,
+) -> Response<Body> {
+    let error_body = ErrorBody {
+        code,
+        message,
+        memo,
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&error_body).unwrap())) // This is safe
+        .unwrap()
 }
 
 pub trait IntoResponse: fmt::Display + Sized {
     fn to_status(&self) -> u16;
 
+    /// Machine-readable error code.
+    fn to_code(&self) -> &'static str;
+
+    /// Operator guidance included alongside the error.
+    fn to_memo(&self) -> Option<&'static str> {
+        None
+    }
+
     fn into_response(&self) -> Response<Body> {
         let status = self.to_status();
 
+        // Don't leak internal errors
         if status != 500 {
-            Response::builder()
-                .status(status)
-                .body(Body::from(self.to_string()))
-                .unwrap()
+            error_response_with_memo(status, self.to_code(), &self.to_string(), self.to_memo())
         } else {
-            Response::builder()
-                .status(status)
-                .body(Body::empty())
-                .unwrap()
+            error_response_with_memo(
+                status,
+                self.to_code(),
+                "internal server error",
+                self.to_memo(),
+            )
         }
     }
 }
@@ -79,6 +196,31 @@ pub async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Infallib
         return Ok(err.into_response());
     }
 
+    if let Some(err) = err.find::<ModerationError>() {
+        error!(message = "moderated address requested", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<AdminError>() {
+        error!(message = "admin protection triggered", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<BackupError>() {
+        error!(message = "failed to backup database", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<ListProfilesError>() {
+        error!(message = "failed to list profiles", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<QueryProfilesError>() {
+        error!(message = "failed to query profiles", error = %err);
+        return Ok(err.into_response());
+    }
+
     if let Some(err) = err.find::<GetProfileError>() {
         error!(message = "failed to get profile", error = %err);
         return Ok(err.into_response());
@@ -89,16 +231,36 @@ pub async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Infallib
         return Ok(err.into_response());
     }
 
+    if let Some(err) = err.find::<DeleteProfileError>() {
+        error!(message = "failed to delete profile", error = %err);
+        return Ok(err.into_response());
+    }
+
     if let Some(err) = err.find::<GetMessageError>() {
         error!(message = "failed to get messages", error = %err);
         return Ok(err.into_response());
     }
 
+    if let Some(err) = err.find::<AckMessageError>() {
+        error!(message = "failed to acknowledge message", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<DeleteMessagesError>() {
+        error!(message = "failed to delete messages", error = %err);
+        return Ok(err.into_response());
+    }
+
     if let Some(err) = err.find::<PutMessageError>() {
         error!(message = "failed to put messages", error = %err);
         return Ok(err.into_response());
     }
 
+    if let Some(err) = err.find::<RateLimitError>() {
+        error!(message = "rate limit exceeded", error = %err);
+        return Ok(err.into_response());
+    }
+
     if let Some(err) = err.find::<PaymentError>() {
         error!(message = "payment failed", error = %err);
         return Ok(err.into_response());
@@ -109,16 +271,120 @@ pub async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Infallib
         return Ok(protection_error_recovery(err).await);
     }
 
+    if let Some(err) = err.find::<BodyError>() {
+        error!(message = "failed to receive body", error = %err);
+        return Ok(err.into_response());
+    }
+
     if err.find::<PayloadTooLarge>().is_some() {
         error!("payload too large");
-        return Ok(Response::builder().status(413).body(Body::empty()).unwrap());
+        return Ok(error_response(
+            413,
+            "PAYLOAD_TOO_LARGE",
+            "payload too large",
+        ));
     }
 
     if err.is_not_found() {
         error!("page not found");
-        return Ok(Response::builder().status(404).body(Body::empty()).unwrap());
+        return Ok(error_response(404, "NOT_FOUND", "page not found"));
     }
 
     error!(message = "unexpected error", error = ?err);
-    Ok(Response::builder().status(500).body(Body::empty()).unwrap())
+    Ok(error_response(500, "INTERNAL", "internal server error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn json_error_response() {
+        let response = AddressDecode::UnexpectedBodyLength(3).into_response();
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let error_body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_body["code"], "ADDRESS_LENGTH");
+        assert_eq!(
+            error_body["message"],
+            "expected address payload of length 20, found 3"
+        );
+        assert!(error_body.get("memo").is_none());
+
+        let response = error_response_with_memo(
+            400,
+            "PAYMENT_EXPIRED",
+            "payment request expired",
+            Some("see https://example.com/support"),
+        );
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let error_body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_body["memo"], "see https://example.com/support");
+    }
+
+    #[tokio::test]
+    async fn json_fallback_rejections() {
+        let response = handle_rejection(warp::reject::not_found()).await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let error_body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_body["code"], "NOT_FOUND");
+
+        let filter = warp::body::content_length_limit(1)
+            .map(warp::reply)
+            .recover(handle_rejection);
+        let response = warp::test::request()
+            .method("POST")
+            .body("{}")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 413);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    }
+
+    #[test]
+    fn address_network_check() {
+        let cash_addr =
+            Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        assert!(on_network(&cash_addr, Network::Testnet));
+        assert!(!on_network(&cash_addr, Network::Regtest));
+        assert!(!on_network(&cash_addr, Network::Mainnet));
+
+        let base58_addr = Address {
+            scheme: Scheme::Base58,
+            ..cash_addr
+        };
+        assert!(on_network(&base58_addr, Network::Testnet));
+        assert!(on_network(&base58_addr, Network::Regtest));
+        assert!(!on_network(&base58_addr, Network::Mainnet));
+    }
+
+    #[test]
+    fn address_normalization() {
+        let cash_addr_str = address_encode(vec![3; 20]);
+        let base58_addr_str = Address {
+            body: vec![3; 20],
+            scheme: Scheme::Base58,
+            network: AddressNetwork::Test,
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+
+        let cash_addr = address_decode(&cash_addr_str).unwrap();
+        let base58_addr = address_decode(&base58_addr_str).unwrap();
+        assert_eq!(cash_addr, base58_addr);
+        assert_eq!(base58_addr.encode().unwrap(), cash_addr_str);
+    }
 }
@@ -0,0 +1,169 @@
+use std::{collections::HashSet, fs, io};
+
+use bitcoincash_addr::Address;
+use thiserror::Error;
+use warp::reject::Reject;
+
+use super::{address_decode, AddressDecode, IntoResponse};
+use crate::settings;
+
+#[derive(Debug, Error)]
+pub enum ModerationError {
+    #[error("address is denied")]
+    Denied,
+    #[error("address is not allowed")]
+    NotAllowed,
+}
+
+impl Reject for ModerationError {}
+
+impl IntoResponse for ModerationError {
+    fn to_status(&self) -> u16 {
+        403
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Denied => "ADDRESS_DENIED",
+            Self::NotAllowed => "ADDRESS_NOT_ALLOWED",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ModerationLoadError {
+    #[error("failed to read {0}: {1}")]
+    Read(String, io::Error),
+    #[error("invalid address {0}: {1}")]
+    Address(String, AddressDecode),
+}
+
+/// Read a list of addresses, one per line.
+///
+/// Blank lines and lines starting with `#` are ignored.
+fn read_list(path: &str) -> Result<Vec<String>, ModerationLoadError> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| ModerationLoadError::Read(path.to_string(), err))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn decode_list<'a>(
+    addresses: impl IntoIterator<Item = &'a String>,
+) -> Result<HashSet<Vec<u8>>, ModerationLoadError> {
+    addresses
+        .into_iter()
+        .map(|addr_str| {
+            address_decode(addr_str)
+                .map(|address| address.into_body())
+                .map_err(|err| ModerationLoadError::Address(addr_str.clone(), err))
+        })
+        .collect()
+}
+
+/// Addresses which are denied, and the addresses allowed when restricted to an allowlist.
+///
+/// Addresses are compared by payload, so cash and legacy encodings of a key match.
+#[derive(Debug, Default)]
+pub struct Moderation {
+    denied: HashSet<Vec<u8>>,
+    allowed: Option<HashSet<Vec<u8>>>,
+}
+
+impl Moderation {
+    pub fn new(
+        denylist: &[String],
+        allowlist: Option<&[String]>,
+    ) -> Result<Self, ModerationLoadError> {
+        Ok(Self {
+            denied: decode_list(denylist)?,
+            allowed: allowlist.map(decode_list).transpose()?,
+        })
+    }
+
+    /// Combine the inline lists with those read from files.
+    ///
+    /// Setting either allowlist restricts the relay to allowlisted addresses.
+    pub fn from_settings(settings: &settings::Moderation) -> Result<Self, ModerationLoadError> {
+        let mut denylist = settings.denylist.clone();
+        if let Some(path) = &settings.denylist_file {
+            denylist.extend(read_list(path)?);
+        }
+
+        let mut allowlist = settings.allowlist.clone();
+        if let Some(path) = &settings.allowlist_file {
+            allowlist
+                .get_or_insert_with(Vec::new)
+                .extend(read_list(path)?);
+        }
+
+        Self::new(&denylist, allowlist.as_deref())
+    }
+
+    /// Check that the address may be served.
+    pub fn check(&self, addr: &Address) -> Result<(), ModerationError> {
+        self.check_payload(addr.as_body())
+    }
+
+    /// Check that the address payload may be served, such as a key hash parsed from a message.
+    ///
+    /// The denylist takes precedence over the allowlist.
+    pub fn check_payload(&self, addr_payload: &[u8]) -> Result<(), ModerationError> {
+        if self.denied.contains(addr_payload) {
+            return Err(ModerationError::Denied);
+        }
+        match &self.allowed {
+            Some(allowed) if !allowed.contains(addr_payload) => Err(ModerationError::NotAllowed),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::net::address_encode;
+
+    fn address(byte: u8) -> (String, Address) {
+        let addr_str = address_encode(vec![byte; 20]);
+        let address = address_decode(&addr_str).unwrap();
+        (addr_str, address)
+    }
+
+    #[test]
+    fn denylist_mode() {
+        let (denied_str, denied) = address(1);
+        let (_, other) = address(2);
+        let moderation = Moderation::new(&[denied_str], None).unwrap();
+
+        assert!(matches!(
+            moderation.check(&denied),
+            Err(ModerationError::Denied)
+        ));
+        assert!(moderation.check(&other).is_ok());
+    }
+
+    #[test]
+    fn allowlist_mode() {
+        let (allowed_str, allowed) = address(1);
+        let (denied_str, denied) = address(2);
+        let (_, other) = address(3);
+        let moderation =
+            Moderation::new(&[denied_str.clone()], Some(&[allowed_str, denied_str][..])).unwrap();
+
+        assert!(moderation.check(&allowed).is_ok());
+        assert!(matches!(
+            moderation.check(&other),
+            Err(ModerationError::NotAllowed)
+        ));
+        assert!(matches!(
+            moderation.check(&denied),
+            Err(ModerationError::Denied)
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use cashweb::relay::{
+    stamp::{Stamp, StampOutpoints},
+    Message, MessagePage,
+};
+use serde::Serialize;
+use thiserror::Error;
+use warp::http::header::{HeaderMap, ACCEPT};
+
+use crate::models::wrapper::AuthWrapper;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Media types answered with raw protobuf.
+const PROTOBUF_MEDIA_TYPES: [&str; 5] = [
+    PROTOBUF_CONTENT_TYPE,
+    "application/x-protobuf",
+    "application/protobuf",
+    "application/*",
+    "*/*",
+];
+
+#[derive(Debug, Error)]
+#[error("no acceptable representation, expected protobuf or json")]
+pub struct NotAcceptable;
+
+/// Serialization of a response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Protobuf,
+    Json,
+}
+
+impl Representation {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Protobuf => PROTOBUF_CONTENT_TYPE,
+            Self::Json => JSON_CONTENT_TYPE,
+        }
+    }
+}
+
+/// Choose the representation from the first supported media type in the `Accept` header.
+///
+/// Quality values are ignored and a missing header falls back to protobuf.
+pub fn negotiate(header_map: &HeaderMap) -> Result<Representation, NotAcceptable> {
+    let mut media_types = header_map
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap().trim()) // This is safe
+        .filter(|media_type| !media_type.is_empty())
+        .peekable();
+    if media_types.peek().is_none() {
+        return Ok(Representation::Protobuf);
+    }
+
+    media_types
+        .find_map(|media_type| {
+            if media_type.eq_ignore_ascii_case(JSON_CONTENT_TYPE) {
+                Some(Representation::Json)
+            } else if PROTOBUF_MEDIA_TYPES
+                .iter()
+                .any(|protobuf_type| media_type.eq_ignore_ascii_case(protobuf_type))
+            {
+                Some(Representation::Protobuf)
+            } else {
+                None
+            }
+        })
+        .ok_or(NotAcceptable)
+}
+
+/// JSON view of an [`AuthWrapper`], with bytes hex encoded.
+#[derive(Debug, Serialize)]
+pub struct JsonAuthWrapper {
+    public_key: String,
+    signature: String,
+    scheme: i32,
+    payload: String,
+    payload_digest: String,
+}
+
+impl From<AuthWrapper> for JsonAuthWrapper {
+    fn from(wrapper: AuthWrapper) -> Self {
+        Self {
+            public_key: hex::encode(wrapper.public_key),
+            signature: hex::encode(wrapper.signature),
+            scheme: wrapper.scheme,
+            payload: hex::encode(wrapper.payload),
+            payload_digest: hex::encode(wrapper.payload_digest),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonStampOutpoints {
+    stamp_tx: String,
+    vouts: Vec<u32>,
+}
+
+impl From<StampOutpoints> for JsonStampOutpoints {
+    fn from(outpoints: StampOutpoints) -> Self {
+        Self {
+            stamp_tx: hex::encode(outpoints.stamp_tx),
+            vouts: outpoints.vouts,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonStamp {
+    stamp_type: i32,
+    stamp_outpoints: Vec<JsonStampOutpoints>,
+}
+
+impl From<Stamp> for JsonStamp {
+    fn from(stamp: Stamp) -> Self {
+        Self {
+            stamp_type: stamp.stamp_type,
+            stamp_outpoints: stamp.stamp_outpoints.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// JSON view of a [`Message`], with bytes hex encoded.
+#[derive(Debug, Serialize)]
+pub struct JsonMessage {
+    source_public_key: String,
+    destination_public_key: String,
+    received_time: i64,
+    payload_digest: String,
+    stamp: Option<JsonStamp>,
+    scheme: i32,
+    salt: String,
+    payload_hmac: String,
+    payload_size: u64,
+    payload: String,
+}
+
+impl From<Message> for JsonMessage {
+    fn from(message: Message) -> Self {
+        Self {
+            source_public_key: hex::encode(message.source_public_key),
+            destination_public_key: hex::encode(message.destination_public_key),
+            received_time: message.received_time,
+            payload_digest: hex::encode(message.payload_digest),
+            stamp: message.stamp.map(Into::into),
+            scheme: message.scheme,
+            salt: hex::encode(message.salt),
+            payload_hmac: hex::encode(message.payload_hmac),
+            payload_size: message.payload_size,
+            payload: hex::encode(message.payload),
+        }
+    }
+}
+
+/// JSON view of a [`MessagePage`], with bytes hex encoded.
+#[derive(Debug, Serialize)]
+pub struct JsonMessagePage {
+    messages: Vec<JsonMessage>,
+    start_time: i64,
+    end_time: i64,
+    start_digest: String,
+    end_digest: String,
+}
+
+impl From<MessagePage> for JsonMessagePage {
+    fn from(message_page: MessagePage) -> Self {
+        Self {
+            messages: message_page.messages.into_iter().map(Into::into).collect(),
+            start_time: message_page.start_time,
+            end_time: message_page.end_time,
+            start_digest: hex::encode(message_page.start_digest),
+            end_digest: hex::encode(message_page.end_digest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+        header_map.insert(ACCEPT, value.parse().unwrap());
+        header_map
+    }
+
+    #[test]
+    fn negotiate_accept() {
+        assert_eq!(
+            negotiate(&HeaderMap::new()).unwrap(),
+            Representation::Protobuf
+        );
+        assert_eq!(negotiate(&accept("*/*")).unwrap(), Representation::Protobuf);
+        assert_eq!(
+            negotiate(&accept("application/json")).unwrap(),
+            Representation::Json
+        );
+        assert_eq!(
+            negotiate(&accept("text/html, application/json;q=0.9, */*;q=0.8")).unwrap(),
+            Representation::Json
+        );
+        assert!(negotiate(&accept("text/html")).is_err());
+    }
+}
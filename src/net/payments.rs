@@ -1,4 +1,6 @@
 use std::{
+    convert::{TryFrom, TryInto},
+    future::Future,
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
@@ -11,28 +13,53 @@ use cashweb::{
         transaction::{DecodeError as TransactionDecodeError, Transaction},
         Decodable,
     },
-    bitcoin_client::{BitcoinClient, HttpClient, HttpError, NodeError},
+    bitcoin_client::HttpError,
     payments::bip70::{Output, Payment, PaymentAck, PaymentDetails, PaymentRequest},
     payments::{
+        preprocess_payment,
         wallet::{UnexpectedOutputs, Wallet as WalletGeneric},
         PreprocessingError,
     },
     token::schemes::hmac_bearer::HmacScheme,
 };
+use futures::TryFutureExt;
 use prost::Message as _;
+use ring::digest::{digest, SHA256};
 use thiserror::Error;
-use tracing::info;
+use tracing::{info, warn};
 use warp::{
-    http::{header::AUTHORIZATION, Response},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, LOCATION},
+        Response,
+    },
     hyper::Body,
     reject::Reject,
+    Filter, Rejection,
 };
 
-use super::IntoResponse;
-use crate::{PAYMENTS_PATH, SETTINGS};
+use super::{
+    body_bytes,
+    idempotency::{CachedResponse, IdempotencyCache, Reservation},
+    protection::{construct_token, Scope},
+    IntoResponse,
+};
+use crate::{
+    derivation::{AddressDeriver, DerivationError},
+    node::{self, NodeClient},
+    PAYMENTS_PATH, SETTINGS,
+};
 
 pub type Wallet = WalletGeneric<Vec<u8>, Output>;
 
+/// Where the output addresses of payment requests come from.
+#[derive(Clone, Debug)]
+pub enum OutputSource {
+    /// Fetch a new address from the node's wallet.
+    Node(NodeClient),
+    /// Derive the next address from the configured extended public key.
+    Derived(Arc<AddressDeriver>),
+}
+
 #[derive(Debug, Error)]
 pub enum PaymentError {
     #[error("preprocessing failed: {0}")]
@@ -43,8 +70,22 @@ pub enum PaymentError {
     MalformedTx(TransactionDecodeError),
     #[error("missing merchant data")]
     MissingMerchantData,
+    #[error("malformed merchant data")]
+    MalformedMerchantData,
+    #[error("payment request expired")]
+    Expired,
     #[error("bitcoin request failed: {0}")]
     Node(HttpError),
+    #[error("payment with this idempotency key is in progress")]
+    InProgress,
+    #[error("idempotency key was used with a different payment")]
+    IdempotencyMismatch,
+    #[error("refund output is missing a script")]
+    MalformedRefund,
+    #[error("payment has {0} outputs, the maximum is {1}")]
+    TooManyOutputs(usize, usize),
+    #[error("memo is {0} bytes, the maximum is {1}")]
+    MemoTooLong(usize, usize),
 }
 
 impl Reject for PaymentError {}
@@ -60,18 +101,154 @@ impl IntoResponse for PaymentError {
             PaymentError::Wallet(_) => 404,
             PaymentError::MalformedTx(_) => 400,
             PaymentError::MissingMerchantData => 400,
-            PaymentError::Node(err) => match err {
-                NodeError::Rpc(_) => 400,
-                _ => 500,
+            PaymentError::MalformedMerchantData => 400,
+            PaymentError::Expired => 400,
+            PaymentError::InProgress => 409,
+            PaymentError::IdempotencyMismatch => 422,
+            PaymentError::MalformedRefund => 400,
+            PaymentError::TooManyOutputs(..) => 400,
+            PaymentError::MemoTooLong(..) => 400,
+            PaymentError::Node(err) => node::error_status(err),
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            PaymentError::Preprocess(err) => match err {
+                PreprocessingError::MissingAcceptHeader => "MISSING_ACCEPT_HEADER",
+                PreprocessingError::MissingContentTypeHeader => "MISSING_CONTENT_TYPE_HEADER",
+                PreprocessingError::PaymentDecode(_) => "PAYMENT_DECODE",
             },
+            PaymentError::Wallet(_) => "UNEXPECTED_OUTPUTS",
+            PaymentError::MalformedTx(_) => "MALFORMED_TX",
+            PaymentError::MissingMerchantData => "MISSING_MERCHANT_DATA",
+            PaymentError::MalformedMerchantData => "MALFORMED_MERCHANT_DATA",
+            PaymentError::Expired => "PAYMENT_EXPIRED",
+            PaymentError::InProgress => "PAYMENT_IN_PROGRESS",
+            PaymentError::IdempotencyMismatch => "IDEMPOTENCY_KEY_MISMATCH",
+            PaymentError::MalformedRefund => "MALFORMED_REFUND",
+            PaymentError::TooManyOutputs(..) => "TOO_MANY_OUTPUTS",
+            PaymentError::MemoTooLong(..) => "MEMO_TOO_LONG",
+            PaymentError::Node(_) => "NODE",
+        }
+    }
+
+    fn to_memo(&self) -> Option<&'static str> {
+        rejection_memo(self.to_code())
+    }
+}
+
+/// The operator's guidance for payment rejections with the error code.
+fn rejection_memo(code: &str) -> Option<&'static str> {
+    SETTINGS
+        .payments
+        .rejection_memos
+        .get(code)
+        .map(String::as_str)
+}
+
+const MERCHANT_DATA_LEN: usize = 20 + 1 + 8;
+const PAYMENT_REQUEST_CONTENT_TYPE: &str = "application/bitcoincash-paymentrequest";
+const PAYMENT_ACK_CONTENT_TYPE: &str = "application/bitcoincash-paymentack";
+
+/// P2PKH script surrounding the address payload of payment request outputs.
+const P2PKH_SCRIPT_PRE: [u8; 3] = [118, 169, 20];
+const P2PKH_SCRIPT_POST: [u8; 2] = [136, 172];
+
+/// Encode the merchant data, the address payload and scope followed by the expiry of the payment
+/// request.
+fn encode_merchant_data(addr_payload: &[u8], scope: Scope, expiry: u64) -> Vec<u8> {
+    [addr_payload, &[scope.into()], &expiry.to_be_bytes()].concat()
+}
+
+/// Decode the merchant data, checking the payment request hasn't expired.
+fn decode_merchant_data(merchant_data: &[u8], now: u64) -> Result<(&[u8], Scope), PaymentError> {
+    if merchant_data.len() != MERCHANT_DATA_LEN {
+        return Err(PaymentError::MalformedMerchantData);
+    }
+    let (addr_payload, rest) = merchant_data.split_at(20);
+    let scope = Scope::try_from(rest[0]).map_err(|_| PaymentError::MalformedMerchantData)?;
+    let expiry = u64::from_be_bytes(rest[1..].try_into().unwrap()); // This is safe
+    if now > expiry {
+        return Err(PaymentError::Expired);
+    }
+    Ok((addr_payload, scope))
+}
+
+/// Values substituted into the memo template.
+#[derive(Debug, Default)]
+struct MemoValues {
+    amount: Option<u64>,
+    address: Option<String>,
+    expiry: Option<u64>,
+}
+
+/// Render the memo template, substituting the `{amount}`, `{address}` and `{expiry}` placeholders.
+///
+/// Literal braces are escaped by doubling them. Placeholders without a value are left empty and
+/// unknown placeholders are left untouched.
+fn render_memo(template: &str, values: &MemoValues) -> String {
+    let mut memo = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(index) = rest.find(&['{', '}'][..]) {
+        memo.push_str(&rest[..index]);
+        let tail = &rest[index..];
+
+        // Escaped braces
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            memo.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+
+        // Placeholders
+        if let (true, Some(end)) = (tail.starts_with('{'), tail.find('}')) {
+            let value = match &tail[1..end] {
+                "amount" => Some(values.amount.map(|amount| amount.to_string())),
+                "address" => Some(values.address.clone()),
+                "expiry" => Some(values.expiry.map(|expiry| expiry.to_string())),
+                _ => None,
+            };
+            match value {
+                Some(value) => memo.push_str(&value.unwrap_or_default()),
+                None => memo.push_str(&tail[..=end]),
+            }
+            rest = &tail[end + 1..];
+            continue;
         }
+
+        // Unmatched brace
+        memo.push_str(&tail[..1]);
+        rest = &tail[1..];
     }
+    memo.push_str(rest);
+    memo
 }
 
+/// Buffer and preprocess a payment, rejecting bodies over `payment_size` bytes before buffering.
+pub fn payment_body(
+    payment_size: u64,
+    body_timeout: Duration,
+) -> impl Filter<Extract = (Payment,), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::body::content_length_limit(payment_size))
+        .and(body_bytes(body_timeout))
+        .and_then(|headers, body| {
+            preprocess_payment(headers, body)
+                .map_err(PaymentError::Preprocess)
+                .map_err(warp::reject::custom)
+        })
+}
+
+/// Accept a payment of a payment request, granting a token for its address and scope.
+///
+/// Overpayments are never refunded, they extend the token lifetime instead. The `refund_to`
+/// outputs are only logged and echoed in the ack, so operators can return funds by hand.
 pub async fn process_payment(
     payment: Payment,
     wallet: Wallet,
-    bitcoin_client: BitcoinClient<HttpClient>,
+    bitcoin_client: NodeClient,
+    output_source: OutputSource,
     token_state: Arc<HmacScheme>,
 ) -> Result<Response<Body>, PaymentError> {
     let txs_res: Result<Vec<Transaction>, TransactionDecodeError> = payment
@@ -80,6 +257,25 @@ pub async fn process_payment(
         .map(|raw_tx: &Vec<u8>| Transaction::decode(&mut raw_tx.as_slice()))
         .collect();
     let txs = txs_res.map_err(PaymentError::MalformedTx)?;
+
+    // The memo is echoed in the ack, decoding already guarantees it is UTF-8
+    if let Some(memo) = &payment.memo {
+        if memo.len() > SETTINGS.payments.max_memo_length {
+            return Err(PaymentError::MemoTooLong(
+                memo.len(),
+                SETTINGS.payments.max_memo_length,
+            ));
+        }
+    }
+
+    // Bound the outputs scanned by the wallet
+    let n_outputs = txs.iter().map(|tx| tx.outputs.len()).sum();
+    if n_outputs > SETTINGS.payments.max_outputs {
+        return Err(PaymentError::TooManyOutputs(
+            n_outputs,
+            SETTINGS.payments.max_outputs,
+        ));
+    }
     let outputs: Vec<Output> = txs
         .into_iter()
         .map(move |tx| tx.outputs)
@@ -90,38 +286,179 @@ pub async fn process_payment(
         })
         .collect();
 
-    let pubkey_hash = payment
+    let merchant_data = payment
         .merchant_data
         .as_ref()
         .ok_or(PaymentError::MissingMerchantData)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (pubkey_hash, scope) = decode_merchant_data(merchant_data, now)?;
+    let pubkey_hash = pubkey_hash.to_vec();
+
+    // Refund outputs are handed to operators, so they must be payable
+    if payment
+        .refund_to
+        .iter()
+        .any(|refund_output| refund_output.script.is_empty())
+    {
+        return Err(PaymentError::MalformedRefund);
+    }
 
+    // Find the output paying the relay, which may pay more than the fee
     info!(message = "checking wallet", outputs = ?outputs, address_payload = ?pubkey_hash);
-    wallet
-        .recv_outputs(pubkey_hash, &outputs)
-        .map_err(PaymentError::Wallet)?;
+    let token_fee = token_fee(scope);
+    let (paid, paid_script) = outputs
+        .iter()
+        .filter_map(|output| output.amount.map(|amount| (amount, &output.script)))
+        .filter(|(amount, _)| *amount >= token_fee)
+        .find_map(|(amount, script)| {
+            let expected_output = Output {
+                amount: Some(token_fee),
+                script: script.clone(),
+            };
+            wallet
+                .recv_outputs(&pubkey_hash, &[expected_output])
+                .ok()
+                .map(|_| (amount, script))
+        })
+        .ok_or(PaymentError::Wallet(UnexpectedOutputs))?;
 
-    for tx in &payment.transactions {
-        bitcoin_client
-            .send_tx(tx)
-            .await
-            .map_err(PaymentError::Node)?;
+    if SETTINGS.payments.dry_run {
+        warn!(message = "dry run, not broadcasting payment", address_payload = ?pubkey_hash);
+    } else {
+        for tx in &payment.transactions {
+            bitcoin_client
+                .call(|client| async move { client.send_tx(tx).await })
+                .await
+                .map_err(PaymentError::Node)?;
+        }
     }
 
-    // Construct token
-    let token = format!("POP {}", token_state.construct_token(pubkey_hash));
+    // Paid addresses are never handed out again
+    if let OutputSource::Derived(deriver) = &output_source {
+        // The wallet only matches the P2PKH outputs of its payment requests
+        deriver.mark_paid(&paid_script[P2PKH_SCRIPT_PRE.len()..][..20]);
+    }
+
+    // Construct token, extending its lifetime for overpayments
+    let token_ttl = granted_ttl(
+        paid,
+        token_fee,
+        SETTINGS.payments.token_ttl,
+        SETTINGS.payments.max_ttl_multiplier,
+    );
+    info!(message = "granting token", paid, token_ttl, address_payload = ?pubkey_hash);
+    let token = format!(
+        "POP {}",
+        construct_token(&token_state, &pubkey_hash, scope, token_ttl)
+    );
+
+    // Log the refund outputs so that operators can return funds by hand
+    if !payment.refund_to.is_empty() {
+        info!(message = "payment refund outputs", refund_to = ?payment.refund_to, address_payload = ?pubkey_hash);
+    }
 
-    // Create PaymentAck
-    let memo = Some(SETTINGS.payments.memo.clone());
+    Ok(construct_payment_ack(payment, paid, token))
+}
+
+/// The minimum price of a token for the scope.
+pub fn token_fee(scope: Scope) -> u64 {
+    let scope_fee = match scope {
+        Scope::Messages => SETTINGS.payments.message_fee,
+        Scope::Feeds => SETTINGS.payments.feed_fee,
+        Scope::Profiles => SETTINGS.payments.profile_fee,
+    };
+    scope_fee.unwrap_or(SETTINGS.payments.token_fee)
+}
+
+/// The token lifetime for a payment, scaled by how much the fee was overpaid up to
+/// `max_ttl_multiplier` times the base lifetime.
+fn granted_ttl(paid: u64, token_fee: u64, token_ttl: u64, max_ttl_multiplier: f64) -> u64 {
+    if token_fee == 0 {
+        return token_ttl;
+    }
+    let multiplier = (paid as f64 / token_fee as f64)
+        .min(max_ttl_multiplier)
+        .max(1.);
+    (token_ttl as f64 * multiplier) as u64
+}
+
+/// Construct the BIP70 payment ack, echoing the payment, carrying the token.
+fn construct_payment_ack(payment: Payment, amount: u64, token: String) -> Response<Body> {
+    let memo_values = MemoValues {
+        amount: Some(amount),
+        ..Default::default()
+    };
+    let memo = Some(render_memo(&SETTINGS.payments.memo, &memo_values));
     let payment_ack = PaymentAck { payment, memo };
 
     // Encode payment ack
     let mut raw_ack = Vec::with_capacity(payment_ack.encoded_len());
     payment_ack.encode(&mut raw_ack).unwrap();
 
-    Ok(Response::builder()
+    Response::builder()
+        .header(CONTENT_TYPE, PAYMENT_ACK_CONTENT_TYPE)
         .header(AUTHORIZATION, token)
         .body(Body::from(raw_ack))
-        .unwrap())
+        .unwrap()
+}
+
+/// Scope the idempotency key to the payment request, identified by its merchant data.
+fn scoped_idempotency_key(payment: &Payment, idempotency_key: &str) -> String {
+    let merchant_data = payment.merchant_data.as_deref().unwrap_or_default();
+    format!("{}:{}", hex::encode(merchant_data), idempotency_key)
+}
+
+/// Digest of the encoded payment, binding an idempotency key to the payment which used it.
+fn payment_fingerprint(payment: &Payment) -> Vec<u8> {
+    let mut raw_payment = Vec::with_capacity(payment.encoded_len());
+    payment.encode(&mut raw_payment).unwrap(); // This is safe
+    digest(&SHA256, &raw_payment).as_ref().to_vec()
+}
+
+/// Process a payment at most once per idempotency key, replaying the response to retries.
+///
+/// Keys are scoped to the payment request and bound to the payment, so a reused key can't
+/// replay the response to a different payment. Failed payments release the key so that they may
+/// be retried.
+pub async fn idempotent_payment<F, Fut>(
+    idempotency_key: Option<String>,
+    idempotency_cache: Arc<IdempotencyCache>,
+    payment: Payment,
+    process: F,
+) -> Result<Response<Body>, PaymentError>
+where
+    F: FnOnce(Payment) -> Fut,
+    Fut: Future<Output = Result<Response<Body>, PaymentError>>,
+{
+    let idempotency_key = match idempotency_key {
+        Some(idempotency_key) => scoped_idempotency_key(&payment, &idempotency_key),
+        None => return process(payment).await,
+    };
+
+    match idempotency_cache.reserve(&idempotency_key, &payment_fingerprint(&payment)) {
+        Reservation::Reserved => (),
+        Reservation::InProgress => return Err(PaymentError::InProgress),
+        Reservation::Mismatch => return Err(PaymentError::IdempotencyMismatch),
+        Reservation::Complete(cached_response) => {
+            info!(message = "replaying payment response", idempotency_key = %idempotency_key);
+            return Ok(cached_response.to_response());
+        }
+    }
+
+    let response = match process(payment).await {
+        Ok(response) => response,
+        Err(err) => {
+            idempotency_cache.release(&idempotency_key);
+            return Err(err);
+        }
+    };
+    // The payment ack is constructed in memory so buffering it cannot fail
+    let (cached_response, response) = CachedResponse::buffer(response).await.unwrap();
+    idempotency_cache.complete(&idempotency_key, cached_response);
+    Ok(response)
 }
 
 #[derive(Error, Debug)]
@@ -132,47 +469,117 @@ pub enum PaymentRequestError {
     Node(HttpError),
     #[error("mismatched network")]
     MismatchedNetwork,
+    #[error("failed to derive address: {0}")]
+    Derivation(DerivationError),
+}
+
+impl IntoResponse for PaymentRequestError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Node(err) => node::error_status(err),
+            Self::Derivation(_) => 500,
+            _ => 400,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Address(..) => "ADDRESS_DECODE",
+            Self::Node(_) => "NODE",
+            Self::MismatchedNetwork => "MISMATCHED_NETWORK",
+            Self::Derivation(_) => "DERIVATION",
+        }
+    }
+
+    fn to_memo(&self) -> Option<&'static str> {
+        rejection_memo(self.to_code())
+    }
+}
+
+/// Get the payload of the address the next payment request should pay to.
+async fn next_output_payload(output_source: OutputSource) -> Result<Vec<u8>, PaymentRequestError> {
+    match output_source {
+        OutputSource::Node(bitcoin_client) => {
+            let output_addr_str = bitcoin_client
+                .call(|client| async move { client.get_new_addr().await })
+                .await
+                .map_err(PaymentRequestError::Node)?;
+            let output_addr =
+                Address::decode(&output_addr_str).map_err(|(cash_err, base58_err)| {
+                    PaymentRequestError::Address(cash_err, base58_err)
+                })?;
+            Ok(output_addr.into_body())
+        }
+        OutputSource::Derived(deriver) => {
+            // Persisting the index blocks on the database
+            tokio::task::spawn_blocking(move || deriver.next_address())
+                .await
+                .unwrap() // This is safe
+                .map_err(PaymentRequestError::Derivation)
+        }
+    }
 }
 
 pub async fn generate_payment_request(
     addr: Address,
+    scope: Scope,
     wallet: Wallet,
-    bitcoin_client: BitcoinClient<HttpClient>,
+    output_source: OutputSource,
+    token_fee: u64,
 ) -> Result<Response<Body>, PaymentRequestError> {
-    let output_addr_str = bitcoin_client
-        .get_new_addr()
-        .await
-        .map_err(PaymentRequestError::Node)?;
-    let output_addr = Address::decode(&output_addr_str)
-        .map_err(|(cash_err, base58_err)| PaymentRequestError::Address(cash_err, base58_err))?;
+    let output_payload = next_output_payload(output_source).await?;
 
     // Generate output
-    let p2pkh_script_pre: [u8; 3] = [118, 169, 20];
-    let p2pkh_script_post: [u8; 2] = [136, 172];
     let script = [
-        &p2pkh_script_pre[..],
-        output_addr.as_body(),
-        &p2pkh_script_post[..],
+        &P2PKH_SCRIPT_PRE[..],
+        &output_payload,
+        &P2PKH_SCRIPT_POST[..],
     ]
     .concat();
     let output = Output {
-        amount: Some(SETTINGS.payments.token_fee),
+        amount: Some(token_fee),
         script,
     };
     let cleanup = wallet.add_outputs(addr.as_body().to_vec(), vec![output.clone()]);
     info!(message = "added to wallet", output = ?output, address_payload = ?addr.as_body());
     tokio::spawn(cleanup);
 
+    Ok(construct_payment_request(
+        &addr,
+        scope,
+        output,
+        token_fee,
+        SystemTime::now(),
+    ))
+}
+
+/// Construct the 402 response carrying a BIP70 payment request for the output, pointing the client
+/// at the payments endpoint.
+fn construct_payment_request(
+    addr: &Address,
+    scope: Scope,
+    output: Output,
+    token_fee: u64,
+    current_time: SystemTime,
+) -> Response<Body> {
     // Valid interval
-    let current_time = SystemTime::now();
     let expiry_time = current_time + Duration::from_millis(SETTINGS.payments.timeout);
+    let expiry = expiry_time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    // Render memo
+    let memo_values = MemoValues {
+        amount: Some(token_fee),
+        address: addr.encode().ok(),
+        expiry: Some(expiry),
+    };
+    let memo = render_memo(&SETTINGS.payments.memo, &memo_values);
 
     let payment_details = PaymentDetails {
         network: Some(SETTINGS.network.to_string()),
         time: current_time.duration_since(UNIX_EPOCH).unwrap().as_secs(),
-        expires: Some(expiry_time.duration_since(UNIX_EPOCH).unwrap().as_secs()),
-        memo: None,
-        merchant_data: Some(addr.into_body()),
+        expires: Some(expiry),
+        memo: Some(memo),
+        merchant_data: Some(encode_merchant_data(addr.as_body(), scope, expiry)),
         outputs: vec![output],
         payment_url: Some(format!("/{}", PAYMENTS_PATH)),
     };
@@ -194,8 +601,260 @@ pub async fn generate_payment_request(
     let mut payment_invoice_raw = Vec::with_capacity(payment_invoice.encoded_len());
     payment_invoice.encode(&mut payment_invoice_raw).unwrap();
 
-    Ok(Response::builder()
+    Response::builder()
         .status(402)
+        .header(CONTENT_TYPE, PAYMENT_REQUEST_CONTENT_TYPE)
+        .header(LOCATION, format!("/{}", PAYMENTS_PATH))
         .body(Body::from(payment_invoice_raw))
-        .unwrap())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cashweb::bitcoin::Encodable;
+    use warp::http::{header::ACCEPT, HeaderMap};
+
+    use crate::net::{handle_rejection, protection::validate_token};
+
+    #[tokio::test]
+    async fn payment_required_response() {
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let output = Output {
+            amount: Some(1_000),
+            script: vec![118, 169, 20],
+        };
+        let response = construct_payment_request(
+            &addr,
+            Scope::Feeds,
+            output.clone(),
+            1_000,
+            SystemTime::now(),
+        );
+        assert_eq!(response.status(), 402);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            PAYMENT_REQUEST_CONTENT_TYPE
+        );
+        assert_eq!(response.headers()[LOCATION], "/payments");
+
+        let raw_body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let payment_request = PaymentRequest::decode(raw_body).unwrap();
+        let payment_details =
+            PaymentDetails::decode(&payment_request.serialized_payment_details[..]).unwrap();
+        assert_eq!(payment_details.outputs, vec![output]);
+        assert_eq!(payment_details.payment_url.as_deref(), Some("/payments"));
+        let merchant_data = payment_details.merchant_data.unwrap();
+        assert_eq!(
+            decode_merchant_data(&merchant_data, payment_details.time).unwrap(),
+            (addr.as_body(), Scope::Feeds)
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_ack_roundtrip() {
+        // Submit the payment as a wallet would
+        let payment = Payment {
+            merchant_data: Some(encode_merchant_data(&[1; 20], Scope::Messages, 0)),
+            transactions: vec![vec![1, 2, 3]],
+            refund_to: vec![Output {
+                amount: None,
+                script: vec![118, 169, 20],
+            }],
+            memo: Some("refund me".to_string()),
+        };
+        let mut raw_payment = Vec::with_capacity(payment.encoded_len());
+        payment.encode(&mut raw_payment).unwrap();
+        let mut header_map = HeaderMap::new();
+        header_map.insert(
+            CONTENT_TYPE,
+            "application/bitcoincash-payment".parse().unwrap(),
+        );
+        header_map.insert(ACCEPT, PAYMENT_ACK_CONTENT_TYPE.parse().unwrap());
+        let payment = preprocess_payment(header_map, raw_payment.into())
+            .await
+            .unwrap();
+
+        let response = construct_payment_ack(payment.clone(), 1_000, "POP token".to_string());
+        assert_eq!(response.headers()[CONTENT_TYPE], PAYMENT_ACK_CONTENT_TYPE);
+        assert_eq!(response.headers()[AUTHORIZATION], "POP token");
+
+        let raw_body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let payment_ack = PaymentAck::decode(raw_body).unwrap();
+        assert_eq!(payment_ack.payment, payment);
+        assert!(payment_ack.memo.is_some());
+    }
+
+    /// Build a payment of a payment request for the address, paying `amount` to the output.
+    fn payment_to(
+        addr_payload: &[u8],
+        script: &[u8],
+        amount: u64,
+        refund_to: Vec<Output>,
+    ) -> Payment {
+        let tx = Transaction {
+            version: 1,
+            outputs: vec![cashweb::bitcoin::transaction::Output {
+                value: amount,
+                script: script.to_vec().into(),
+            }],
+            ..Default::default()
+        };
+        let mut raw_tx = Vec::with_capacity(tx.encoded_len());
+        tx.encode(&mut raw_tx).unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Payment {
+            merchant_data: Some(encode_merchant_data(
+                addr_payload,
+                Scope::Messages,
+                now + 60,
+            )),
+            transactions: vec![raw_tx],
+            refund_to,
+            memo: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn process_dry_run_payment() {
+        assert!(SETTINGS.payments.dry_run);
+        let token_scheme = Arc::new(HmacScheme::new(b"secret"));
+        let bitcoin_client = node::new_client();
+        let output_source = OutputSource::Node(bitcoin_client.clone());
+        let script = [&P2PKH_SCRIPT_PRE[..], &[2; 20], &P2PKH_SCRIPT_POST[..]].concat();
+        let token_fee = token_fee(Scope::Messages);
+        let wallet = Wallet::new(Duration::from_secs(60));
+
+        // Refund outputs must be payable
+        let malformed_refund = vec![Output {
+            amount: None,
+            script: vec![],
+        }];
+        let payment = payment_to(&[1; 20], &script, token_fee, malformed_refund);
+        assert!(matches!(
+            process_payment(
+                payment,
+                wallet.clone(),
+                bitcoin_client.clone(),
+                output_source.clone(),
+                token_scheme.clone(),
+            )
+            .await,
+            Err(PaymentError::MalformedRefund)
+        ));
+
+        // Overpayments aren't refunded, the refund outputs are echoed in the ack
+        let expected_output = Output {
+            amount: Some(token_fee),
+            script: script.clone(),
+        };
+        tokio::spawn(wallet.add_outputs(vec![1; 20], vec![expected_output]));
+        let refund_to = vec![Output {
+            amount: None,
+            script: [&P2PKH_SCRIPT_PRE[..], &[3; 20], &P2PKH_SCRIPT_POST[..]].concat(),
+        }];
+        let payment = payment_to(&[1; 20], &script, 2 * token_fee, refund_to.clone());
+        let response = process_payment(
+            payment.clone(),
+            wallet,
+            bitcoin_client,
+            output_source,
+            token_scheme.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], PAYMENT_ACK_CONTENT_TYPE);
+
+        let authorization = response.headers()[AUTHORIZATION].to_str().unwrap();
+        let token = authorization.strip_prefix("POP ").unwrap();
+        assert!(validate_token(&token_scheme, &[1; 20], Scope::Messages, token).is_ok());
+
+        let raw_body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let payment_ack = PaymentAck::decode(raw_body).unwrap();
+        assert_eq!(payment_ack.payment.refund_to, refund_to);
+        assert_eq!(payment_ack.payment, payment);
+    }
+
+    #[test]
+    fn overpayment_ttl() {
+        assert_eq!(granted_ttl(1_000, 1_000, 60_000, 1.), 60_000);
+        assert_eq!(granted_ttl(5_000, 1_000, 60_000, 1.), 60_000);
+        assert_eq!(granted_ttl(1_500, 1_000, 60_000, 4.), 90_000);
+        assert_eq!(granted_ttl(5_000, 1_000, 60_000, 4.), 240_000);
+        assert_eq!(granted_ttl(5_000, 0, 60_000, 4.), 60_000);
+    }
+
+    #[test]
+    fn merchant_data_expiry() {
+        let merchant_data = encode_merchant_data(&[1; 20], Scope::Messages, 1_600_000_000);
+        assert_eq!(
+            decode_merchant_data(&merchant_data, 1_600_000_000).unwrap(),
+            (&[1; 20][..], Scope::Messages)
+        );
+        assert!(matches!(
+            decode_merchant_data(&merchant_data, 1_600_000_001),
+            Err(PaymentError::Expired)
+        ));
+        assert!(matches!(
+            decode_merchant_data(&[1; 20], 0),
+            Err(PaymentError::MalformedMerchantData)
+        ));
+        let unknown_scope = [&[1; 20][..], &[u8::MAX], &[0; 8]].concat();
+        assert!(matches!(
+            decode_merchant_data(&unknown_scope, 0),
+            Err(PaymentError::MalformedMerchantData)
+        ));
+    }
+
+    #[test]
+    fn memo_placeholders() {
+        let memo_values = MemoValues {
+            amount: Some(100),
+            address: Some("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65".to_string()),
+            expiry: Some(1_600_000_000),
+        };
+        assert_eq!(
+            render_memo("Pay {amount} for {address} by {expiry}", &memo_values),
+            "Pay 100 for bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65 by 1600000000"
+        );
+    }
+
+    #[test]
+    fn memo_escaped_braces() {
+        let memo_values = MemoValues {
+            amount: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            render_memo("{{amount}} is {amount}, {address}{unknown} }", &memo_values),
+            "{amount} is 100, {unknown} }"
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_too_large() {
+        let filter = payment_body(16, Duration::from_secs(1))
+            .map(|_| warp::reply())
+            .recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .header(CONTENT_TYPE, "application/bitcoincash-payment")
+            .header(ACCEPT, PAYMENT_ACK_CONTENT_TYPE)
+            .body(vec![0; 17])
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 413);
+    }
 }
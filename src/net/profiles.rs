@@ -1,14 +1,34 @@
+use std::{collections::BTreeMap, sync::Arc};
+
 use bitcoincash_addr::Address;
 use bytes::Bytes;
-use cashweb::auth_wrapper::{ParseError, VerifyError};
+use cashweb::relay::Profile;
 use prost::Message as _;
+use ring::digest::{digest, SHA256};
+use ripemd160::{Digest, Ripemd160};
 use rocksdb::Error as RocksError;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::task;
-use warp::{http::Response, hyper::Body, reject::Reject};
+use warp::{
+    http::{
+        header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, VARY},
+        Response,
+    },
+    hyper::Body,
+    reject::Reject,
+};
 
-use super::IntoResponse;
-use crate::{db::Database, models::wrapper::AuthWrapper};
+use super::{
+    address_decode, address_encode, negotiate, AddressDecode, IntoResponse, JsonAuthWrapper,
+    Moderation, NotAcceptable, Representation,
+};
+use crate::{
+    crypto::{parse_deletion, verify_auth_wrapper, CryptoError, DELETE_PROFILE_OPERATION},
+    db::Database,
+    models::wrapper::AuthWrapper,
+    SETTINGS,
+};
 
 #[derive(Debug, Error)]
 pub enum GetProfileError {
@@ -16,6 +36,10 @@ pub enum GetProfileError {
     NotFound,
     #[error("failed to read from database: {0}")]
     Database(#[from] RocksError),
+    #[error("stored profile is malformed: {0}")]
+    Malformed(prost::DecodeError),
+    #[error(transparent)]
+    NotAcceptable(#[from] NotAcceptable),
 }
 
 impl Reject for GetProfileError {}
@@ -24,21 +48,85 @@ impl IntoResponse for GetProfileError {
     fn to_status(&self) -> u16 {
         match self {
             Self::NotFound => 404,
-            Self::Database(_) => 500,
+            Self::Database(_) | Self::Malformed(_) => 500,
+            Self::NotAcceptable(_) => 406,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "PROFILE_NOT_FOUND",
+            Self::Database(_) => "DATABASE",
+            Self::Malformed(_) => "MALFORMED_PROFILE",
+            Self::NotAcceptable(_) => "NOT_ACCEPTABLE",
+        }
+    }
+}
+
+/// Addresses whose profiles to fetch.
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    addresses: Vec<String>,
+    /// Return the entity tags of the profiles rather than the profiles.
+    #[serde(default)]
+    digests_only: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum QueriedProfile {
+    Profile(JsonAuthWrapper),
+    Digest(String),
+}
+
+#[derive(Debug, Error)]
+pub enum QueryProfilesError {
+    #[error("expected at most {0} addresses, found {1}")]
+    TooManyAddresses(usize, usize),
+    #[error(transparent)]
+    Address(AddressDecode),
+    #[error("failed to read from database: {0}")]
+    Database(#[from] RocksError),
+    #[error("stored profile is malformed: {0}")]
+    Malformed(prost::DecodeError),
+}
+
+impl Reject for QueryProfilesError {}
+
+impl IntoResponse for QueryProfilesError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Database(_) | Self::Malformed(_) => 500,
+            _ => 400,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::TooManyAddresses(..) => "TOO_MANY_ADDRESSES",
+            Self::Address(_) => "INVALID_ADDRESS",
+            Self::Database(_) => "DATABASE",
+            Self::Malformed(_) => "MALFORMED_PROFILE",
         }
     }
 }
 
 #[derive(Debug, Error)]
 pub enum PutProfileError {
+    #[error("profile too large")]
+    TooLarge,
     #[error("failed to write to database: {0}")]
     Database(#[from] RocksError),
     #[error("failed to decode authorization wrapper: {0}")]
     ProfileDecode(prost::DecodeError),
-    #[error("failed to verify authorization wrapper: {0}")]
-    Verify(VerifyError),
-    #[error("failed to parse authorization wrapper: {0}")]
-    Parse(ParseError),
+    #[error(transparent)]
+    Auth(CryptoError),
+    #[error("failed to decode profile: {0}")]
+    PayloadDecode(prost::DecodeError),
+    #[error("metadata is outdated")]
+    Outdated,
+    #[error("stored profile does not match If-Match")]
+    PreconditionFailed,
 }
 
 impl Reject for PutProfileError {}
@@ -46,47 +134,852 @@ impl Reject for PutProfileError {}
 impl IntoResponse for PutProfileError {
     fn to_status(&self) -> u16 {
         match self {
+            Self::TooLarge => 413,
             Self::Database(_) => 500,
+            Self::PreconditionFailed => 412,
             _ => 400,
         }
     }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::TooLarge => "PROFILE_TOO_LARGE",
+            Self::Database(_) => "DATABASE",
+            Self::ProfileDecode(_) => "PROFILE_DECODE",
+            Self::Auth(CryptoError::Parse(_)) => "PROFILE_PARSE",
+            Self::Auth(CryptoError::Verify(_)) => "PROFILE_VERIFY",
+            Self::PayloadDecode(_) => "PROFILE_PAYLOAD_DECODE",
+            Self::Outdated => "PROFILE_OUTDATED",
+            Self::PreconditionFailed => "PROFILE_PRECONDITION_FAILED",
+        }
+    }
+}
+
+/// The time of the stored profile or of its deletion, whichever is later.
+///
+/// Profiles and deletions must be strictly newer than this, so stale signed requests can't be
+/// replayed over the current state. Call while holding the address, see
+/// [`Database::lock_address`].
+fn latest_profile_time(
+    database: &Database,
+    addr: &[u8],
+    opt_raw_profile: Option<&[u8]>,
+) -> Result<Option<i64>, RocksError> {
+    let opt_profile_timestamp = opt_raw_profile
+        .and_then(|raw_profile| AuthWrapper::decode(raw_profile).ok())
+        .and_then(|wrapper| Profile::decode(&wrapper.payload[..]).ok())
+        .map(|profile| profile.timestamp);
+    let opt_deletion_timestamp = database.get_profile_deletion_time(addr)?;
+    Ok(opt_profile_timestamp.max(opt_deletion_timestamp))
+}
+
+/// Construct the entity tag of a serialized profile.
+pub fn profile_etag(raw_profile: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(digest(&SHA256, raw_profile)))
+}
+
+/// Construct the entity tag of a serialized profile in the representation.
+///
+/// The JSON body differs from the stored bytes, so it needs a distinct tag for caches keying on
+/// the tag alone.
+fn representation_etag(raw_profile: &[u8], representation: Representation) -> String {
+    match representation {
+        Representation::Protobuf => profile_etag(raw_profile),
+        Representation::Json => format!("\"{}-json\"", hex::encode(digest(&SHA256, raw_profile))),
+    }
+}
+
+/// Check whether the `If-None-Match` header matches the entity tag.
+fn etag_matches(header_map: &HeaderMap, etag: &str) -> bool {
+    header_map
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+/// Check the `If-Match` header against the entity tags of the stored profile, empty if there is
+/// none.
+///
+/// Requests without the header pass. Weak tags never match as the comparison is strong.
+fn if_match_passes(header_map: &HeaderMap, stored_etags: &[String]) -> bool {
+    let mut tags = header_map
+        .get_all(IF_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    if tags.peek().is_none() {
+        return true;
+    }
+    if stored_etags.is_empty() {
+        return false;
+    }
+    tags.any(|tag| tag == "*" || stored_etags.iter().any(|etag| etag == tag))
 }
 
 pub async fn get_profile(
     addr: Address,
+    header_map: HeaderMap,
     database: Database,
 ) -> Result<Response<Body>, GetProfileError> {
+    let representation = negotiate(&header_map)?;
+
     // Get profile
     let raw_profile = task::spawn_blocking(move || database.get_raw_profile(addr.as_body()))
         .await
         .unwrap()?
         .ok_or(GetProfileError::NotFound)?;
 
+    // Check whether client already has the profile
+    let etag = representation_etag(&raw_profile, representation);
+    if etag_matches(&header_map, &etag) {
+        return Ok(Response::builder()
+            .status(304)
+            .header(ETAG, etag)
+            .header(VARY, "accept")
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    // Serialize profile
+    let body = match representation {
+        Representation::Protobuf => raw_profile,
+        Representation::Json => {
+            let wrapper =
+                AuthWrapper::decode(&raw_profile[..]).map_err(GetProfileError::Malformed)?;
+            serde_json::to_vec(&JsonAuthWrapper::from(wrapper)).unwrap() // This is safe
+        }
+    };
+
     // Respond
-    Ok(Response::builder().body(Body::from(raw_profile)).unwrap())
+    Ok(Response::builder()
+        .header(ETAG, etag)
+        .header(VARY, "accept")
+        .header(CONTENT_TYPE, representation.content_type())
+        .header(CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap())
+}
+
+/// Get the profiles of many addresses, as a JSON map from address to profile.
+///
+/// Addresses without a stored profile, or which are moderated, map to `null`.
+pub async fn query_profiles(
+    query: ProfileQuery,
+    database: Database,
+    moderation: Arc<Moderation>,
+) -> Result<Response<Body>, QueryProfilesError> {
+    let max_addresses = SETTINGS.limits.max_profile_query;
+    if query.addresses.len() > max_addresses {
+        return Err(QueryProfilesError::TooManyAddresses(
+            max_addresses,
+            query.addresses.len(),
+        ));
+    }
+
+    let addr_payloads = query
+        .addresses
+        .iter()
+        .map(|addr_str| address_decode(addr_str).map(|addr| addr.body))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(QueryProfilesError::Address)?;
+
+    // Get profiles, skipping moderated addresses
+    let raw_profiles = task::spawn_blocking(move || {
+        let raw_profiles = database.get_raw_profiles(&addr_payloads)?;
+        Ok::<_, RocksError>(
+            addr_payloads
+                .iter()
+                .zip(raw_profiles)
+                .map(|(addr_payload, opt_raw_profile)| {
+                    opt_raw_profile.filter(|_| moderation.check_payload(addr_payload).is_ok())
+                })
+                .collect::<Vec<_>>(),
+        )
+    })
+    .await
+    .unwrap()?;
+
+    let digests_only = query.digests_only;
+    let profiles = query
+        .addresses
+        .into_iter()
+        .zip(raw_profiles)
+        .map(|(addr_str, opt_raw_profile)| {
+            let opt_profile = opt_raw_profile
+                .map(|raw_profile| {
+                    if digests_only {
+                        Ok(QueriedProfile::Digest(profile_etag(&raw_profile)))
+                    } else {
+                        let wrapper = AuthWrapper::decode(&raw_profile[..])
+                            .map_err(QueryProfilesError::Malformed)?;
+                        Ok(QueriedProfile::Profile(JsonAuthWrapper::from(wrapper)))
+                    }
+                })
+                .transpose()?;
+            Ok((addr_str, opt_profile))
+        })
+        .collect::<Result<BTreeMap<String, Option<QueriedProfile>>, QueryProfilesError>>()?;
+
+    // Respond
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&profiles).unwrap())) // This is safe
+        .unwrap())
 }
 
 pub async fn put_profile(
     addr: Address,
+    header_map: HeaderMap,
     profile_raw: Bytes,
     database: Database,
 ) -> Result<Response<Body>, PutProfileError> {
+    // Check profile size
+    if profile_raw.len() as u64 > SETTINGS.limits.profile_size {
+        return Err(PutProfileError::TooLarge);
+    }
+
     // Decode profile
     let profile =
         AuthWrapper::decode(profile_raw.clone()).map_err(PutProfileError::ProfileDecode)?;
 
     // Verify signatures
-    profile
-        .parse()
-        .map_err(PutProfileError::Parse)?
-        .verify()
-        .map_err(PutProfileError::Verify)?;
-
-    // Put to database
-    task::spawn_blocking(move || database.put_profile(addr.as_body(), &profile_raw))
-        .await
-        .unwrap()?;
+    let parsed_wrapper = verify_auth_wrapper(profile).map_err(PutProfileError::Auth)?;
+
+    // Decode profile metadata
+    let timestamp = Profile::decode(&parsed_wrapper.payload[..])
+        .map_err(PutProfileError::PayloadDecode)?
+        .timestamp;
+    let etag = profile_etag(&profile_raw);
+
+    task::spawn_blocking(move || {
+        // Hold the address until the put, so the checks below see the profile being replaced
+        let _guard = database.lock_address(addr.as_body());
+        let opt_stored_profile = database.get_raw_profile(addr.as_body())?;
+
+        // Only replace the profile the client last saw in either representation, so concurrent
+        // updates aren't clobbered
+        let stored_etags: Vec<String> = opt_stored_profile
+            .iter()
+            .flat_map(|raw_profile| {
+                [Representation::Protobuf, Representation::Json]
+                    .iter()
+                    .map(move |representation| representation_etag(raw_profile, *representation))
+            })
+            .collect();
+        if !if_match_passes(&header_map, &stored_etags) {
+            return Err(PutProfileError::PreconditionFailed);
+        }
+
+        // Only replace a stored or deleted profile with a strictly newer one, so stale signed
+        // profiles can't be replayed over it
+        let opt_latest_timestamp =
+            latest_profile_time(&database, addr.as_body(), opt_stored_profile.as_deref())?;
+        if let Some(latest_timestamp) = opt_latest_timestamp {
+            if timestamp <= latest_timestamp {
+                return Err(PutProfileError::Outdated);
+            }
+        }
+
+        // Put to database
+        database.put_profile(addr.as_body(), &profile_raw)?;
+        Ok(())
+    })
+    .await
+    .unwrap()?;
+
+    // Respond
+    Ok(Response::builder()
+        .header(ETAG, etag)
+        .body(Body::empty())
+        .unwrap())
+}
+
+#[derive(Debug, Error)]
+pub enum DeleteProfileError {
+    #[error("not found")]
+    NotFound,
+    #[error("failed to delete from database: {0}")]
+    Database(#[from] RocksError),
+    #[error("failed to decode authorization wrapper: {0}")]
+    WrapperDecode(prost::DecodeError),
+    #[error(transparent)]
+    Auth(CryptoError),
+    #[error("expected profile deletion payload")]
+    UnexpectedPayload,
+    #[error("public key does not match address")]
+    UnexpectedPublicKey,
+    #[error("deletion is outdated")]
+    Outdated,
+}
+
+impl Reject for DeleteProfileError {}
+
+impl IntoResponse for DeleteProfileError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::NotFound => 404,
+            Self::Database(_) => 500,
+            _ => 400,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "PROFILE_NOT_FOUND",
+            Self::Database(_) => "DATABASE",
+            Self::WrapperDecode(_) => "WRAPPER_DECODE",
+            Self::Auth(CryptoError::Parse(_)) => "WRAPPER_PARSE",
+            Self::Auth(CryptoError::Verify(_)) => "WRAPPER_VERIFY",
+            Self::UnexpectedPayload => "UNEXPECTED_PAYLOAD",
+            Self::UnexpectedPublicKey => "UNEXPECTED_PUBLIC_KEY",
+            Self::Outdated => "DELETION_OUTDATED",
+        }
+    }
+}
+
+pub async fn delete_profile(
+    addr: Address,
+    wrapper_raw: Bytes,
+    database: Database,
+) -> Result<Response<Body>, DeleteProfileError> {
+    // Decode authorization wrapper
+    let wrapper = AuthWrapper::decode(wrapper_raw).map_err(DeleteProfileError::WrapperDecode)?;
+
+    // Verify signatures
+    let parsed_wrapper = verify_auth_wrapper(wrapper).map_err(DeleteProfileError::Auth)?;
+
+    // Only a timestamped profile deletion authorizes deletion
+    let timestamp = parse_deletion(&parsed_wrapper.payload, DELETE_PROFILE_OPERATION)
+        .ok_or(DeleteProfileError::UnexpectedPayload)?;
+
+    // Check the signer owns the address
+    let raw_public_key = parsed_wrapper.public_key.serialize();
+    let pubkey_hash = Ripemd160::digest(digest(&SHA256, &raw_public_key).as_ref());
+    if addr.as_body() != &pubkey_hash[..] {
+        return Err(DeleteProfileError::UnexpectedPublicKey);
+    }
+
+    // Remove from database
+    task::spawn_blocking(move || {
+        let _guard = database.lock_address(addr.as_body());
+        let raw_profile = database
+            .get_raw_profile(addr.as_body())?
+            .ok_or(DeleteProfileError::NotFound)?;
+
+        // Only delete with a deletion newer than the profile, so it can't be replayed over a
+        // profile put since
+        let opt_latest_timestamp =
+            latest_profile_time(&database, addr.as_body(), Some(&raw_profile))?;
+        if let Some(latest_timestamp) = opt_latest_timestamp {
+            if timestamp <= latest_timestamp {
+                return Err(DeleteProfileError::Outdated);
+            }
+        }
+
+        database
+            .delete_profile(addr.as_body(), timestamp)?
+            .ok_or(DeleteProfileError::NotFound)
+    })
+    .await
+    .unwrap()?;
 
     // Respond
     Ok(Response::builder().body(Body::empty()).unwrap())
 }
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileListQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProfileList {
+    profiles: Vec<String>,
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ListProfilesError {
+    #[error("failed to read from database: {0}")]
+    Database(#[from] RocksError),
+    #[error("invalid cursor: {0}")]
+    Cursor(AddressDecode),
+}
+
+impl Reject for ListProfilesError {}
+
+impl IntoResponse for ListProfilesError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Database(_) => 500,
+            Self::Cursor(_) => 400,
+        }
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Database(_) => "DATABASE",
+            Self::Cursor(_) => "INVALID_CURSOR",
+        }
+    }
+}
+
+/// List the addresses with a stored profile.
+///
+/// The cursor is the last address of the previous page and is omitted from the last page.
+pub async fn list_profiles(
+    query: ProfileListQuery,
+    database: Database,
+) -> Result<Response<Body>, ListProfilesError> {
+    let opt_cursor = query
+        .cursor
+        .map(|cursor| address_decode(&cursor))
+        .transpose()
+        .map_err(ListProfilesError::Cursor)?;
+    let limit = query
+        .limit
+        .unwrap_or(SETTINGS.limits.max_page_size as usize)
+        .min(SETTINGS.limits.max_page_size as usize);
+
+    // Get page of profiles
+    let (addrs, has_more) = task::spawn_blocking(move || {
+        database.list_profiles(opt_cursor.as_ref().map(|cursor| cursor.as_body()), limit)
+    })
+    .await
+    .unwrap()?;
+
+    let profiles: Vec<String> = addrs.into_iter().map(address_encode).collect();
+    let cursor = if has_more {
+        profiles.last().cloned()
+    } else {
+        None
+    };
+    let profile_list = ProfileList { profiles, cursor };
+
+    // Respond
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&profile_list).unwrap())) // This is safe
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use warp::http::{header::ACCEPT, HeaderValue};
+
+    use crate::net::fixtures::{sign_deletion, sign_payload, signer_address};
+
+    #[tokio::test]
+    async fn get_profile_not_modified() {
+        let database = Database::try_new("./test_dbs/get_profile_not_modified").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let raw_profile = vec![1, 2, 3];
+        database.put_profile(addr.as_body(), &raw_profile).unwrap();
+
+        let etag = profile_etag(&raw_profile);
+        let mut header_map = HeaderMap::new();
+        header_map.insert(IF_NONE_MATCH, HeaderValue::from_str(&etag).unwrap());
+
+        let response = get_profile(addr, header_map, database).await.unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()[ETAG], etag);
+    }
+
+    #[tokio::test]
+    async fn get_profile_modified() {
+        let database = Database::try_new("./test_dbs/get_profile_modified").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let raw_profile = vec![1, 2, 3];
+        database.put_profile(addr.as_body(), &raw_profile).unwrap();
+
+        let stale_etag = profile_etag(&[1, 2]);
+        let mut header_map = HeaderMap::new();
+        header_map.insert(IF_NONE_MATCH, HeaderValue::from_str(&stale_etag).unwrap());
+
+        let response = get_profile(addr, header_map, database).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[ETAG], profile_etag(&raw_profile));
+    }
+
+    #[tokio::test]
+    async fn get_profile_json() {
+        let database = Database::try_new("./test_dbs/get_profile_json").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let wrapper = AuthWrapper {
+            payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let mut raw_profile = Vec::with_capacity(wrapper.encoded_len());
+        wrapper.encode(&mut raw_profile).unwrap();
+        database.put_profile(addr.as_body(), &raw_profile).unwrap();
+
+        let mut header_map = HeaderMap::new();
+        header_map.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+        let response = get_profile(addr, header_map, database).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[VARY], "accept");
+        assert_ne!(
+            response.headers()[ETAG],
+            profile_etag(&raw_profile).as_str()
+        );
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let json_profile: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json_profile["payload"], "010203");
+    }
+
+    #[tokio::test]
+    async fn get_profile_malformed() {
+        let database = Database::try_new("./test_dbs/get_profile_malformed").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        database.put_profile(addr.as_body(), &[0xff]).unwrap();
+
+        let mut header_map = HeaderMap::new();
+        header_map.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+        let err = get_profile(addr, header_map, database).await.unwrap_err();
+        assert!(matches!(err, GetProfileError::Malformed(_)));
+        assert_eq!(err.to_status(), 500);
+    }
+
+    #[tokio::test]
+    async fn get_profile_not_acceptable() {
+        let database = Database::try_new("./test_dbs/get_profile_not_acceptable").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let mut header_map = HeaderMap::new();
+        header_map.insert(ACCEPT, HeaderValue::from_static("text/html"));
+
+        let err = get_profile(addr, header_map, database).await.unwrap_err();
+        assert_eq!(err.to_status(), 406);
+    }
+
+    #[tokio::test]
+    async fn query_profiles_map() {
+        let database = Database::try_new("./test_dbs/query_profiles_map").unwrap();
+
+        let stored = &address_encode(vec![6; 20]);
+        let missing = address_encode(vec![7; 20]);
+        let wrapper = AuthWrapper {
+            payload: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let mut raw_profile = Vec::with_capacity(wrapper.encoded_len());
+        wrapper.encode(&mut raw_profile).unwrap();
+        database.put_profile(&[6; 20], &raw_profile).unwrap();
+        let moderation = Arc::new(Moderation::default());
+
+        let query = ProfileQuery {
+            addresses: vec![stored.to_string(), missing.clone()],
+            digests_only: false,
+        };
+        let response = query_profiles(query, database.clone(), moderation.clone())
+            .await
+            .unwrap();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let profiles: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(profiles[stored]["payload"], "010203");
+        assert!(profiles[&missing].is_null());
+
+        let query = ProfileQuery {
+            addresses: vec![stored.to_string()],
+            digests_only: true,
+        };
+        let response = query_profiles(query, database.clone(), moderation.clone())
+            .await
+            .unwrap();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let profiles: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(profiles[stored], profile_etag(&raw_profile));
+
+        let query = ProfileQuery {
+            addresses: vec![missing; SETTINGS.limits.max_profile_query + 1],
+            digests_only: false,
+        };
+        let err = query_profiles(query, database.clone(), moderation)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, QueryProfilesError::TooManyAddresses(..)));
+
+        // Denied profiles aren't served
+        let moderation = Arc::new(Moderation::new(&[stored.to_string()], None).unwrap());
+        let query = ProfileQuery {
+            addresses: vec![stored.to_string()],
+            digests_only: false,
+        };
+        let response = query_profiles(query, database, moderation).await.unwrap();
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let profiles: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(profiles[stored].is_null());
+    }
+
+    fn sign_profile(timestamp: i64) -> Bytes {
+        let profile = Profile {
+            timestamp,
+            ..Default::default()
+        };
+        let mut payload = Vec::with_capacity(profile.encoded_len());
+        profile.encode(&mut payload).unwrap();
+        sign_payload(payload)
+    }
+
+    #[tokio::test]
+    async fn put_profile_outdated() {
+        let path = "./test_dbs/put_profile_outdated";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(200),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+
+        // Older and replayed profiles are rejected
+        for timestamp in &[100, 200] {
+            let err = put_profile(
+                addr.clone(),
+                HeaderMap::new(),
+                sign_profile(*timestamp),
+                database.clone(),
+            )
+            .await
+            .unwrap_err();
+            assert!(matches!(err, PutProfileError::Outdated));
+        }
+
+        // Newer profiles replace the stored one
+        put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(300),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+        let stored_wrapper = database.get_profile(addr.as_body()).unwrap().unwrap();
+        let stored_profile = Profile::decode(&stored_wrapper.payload[..]).unwrap();
+        assert_eq!(stored_profile.timestamp, 300);
+    }
+
+    #[tokio::test]
+    async fn put_profile_if_match() {
+        let path = "./test_dbs/put_profile_if_match";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let if_match = |etag: &str| {
+            let mut header_map = HeaderMap::new();
+            header_map.insert(IF_MATCH, HeaderValue::from_str(etag).unwrap());
+            header_map
+        };
+
+        // There is no stored profile to match
+        let err = put_profile(
+            addr.clone(),
+            if_match("*"),
+            sign_profile(100),
+            database.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PutProfileError::PreconditionFailed));
+        assert_eq!(err.to_status(), 412);
+
+        let response = put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(100),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        assert_eq!(etag, profile_etag(&sign_profile(100)));
+
+        // Updates based on a profile which has since been replaced are rejected
+        let stale_etag = profile_etag(&sign_profile(50));
+        let err = put_profile(
+            addr.clone(),
+            if_match(&stale_etag),
+            sign_profile(200),
+            database.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PutProfileError::PreconditionFailed));
+
+        put_profile(addr, if_match(&etag), sign_profile(200), database)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_profile_replay() {
+        let path = "./test_dbs/delete_profile_replay";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let addr = signer_address();
+        put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(100),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+
+        // Untimestamped deletions, or those no newer than the profile, are rejected
+        let err = delete_profile(addr.clone(), sign_payload(vec![]), database.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeleteProfileError::UnexpectedPayload));
+        let err = delete_profile(
+            addr.clone(),
+            sign_deletion(DELETE_PROFILE_OPERATION, 100),
+            database.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, DeleteProfileError::Outdated));
+
+        delete_profile(
+            addr.clone(),
+            sign_deletion(DELETE_PROFILE_OPERATION, 200),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+
+        // Profiles signed before the deletion can't be replayed
+        let err = put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(100),
+            database.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PutProfileError::Outdated));
+
+        // The deletion can't be replayed over a newer profile
+        put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(300),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+        let err = delete_profile(addr, sign_deletion(DELETE_PROFILE_OPERATION, 200), database)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeleteProfileError::Outdated));
+    }
+
+    #[tokio::test]
+    async fn put_profile_concurrent() {
+        let path = "./test_dbs/put_profile_concurrent";
+        let _ = std::fs::remove_dir_all(path);
+        let database = Database::try_new(path).unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        put_profile(
+            addr.clone(),
+            HeaderMap::new(),
+            sign_profile(100),
+            database.clone(),
+        )
+        .await
+        .unwrap();
+        let mut header_map = HeaderMap::new();
+        header_map.insert(
+            IF_MATCH,
+            HeaderValue::from_str(&profile_etag(&sign_profile(100))).unwrap(),
+        );
+
+        // Only one of the updates based on the same profile replaces it
+        let results = futures::future::join_all((200..210).map(|timestamp| {
+            put_profile(
+                addr.clone(),
+                header_map.clone(),
+                sign_profile(timestamp),
+                database.clone(),
+            )
+        }))
+        .await;
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .all(|err| matches!(err, PutProfileError::PreconditionFailed)));
+    }
+
+    #[tokio::test]
+    async fn profile_address_encodings() {
+        let database = Database::try_new("./test_dbs/profile_address_encodings").unwrap();
+
+        // Put under the cash address and get under the legacy address of the same key
+        let cash_addr = address_decode(&address_encode(vec![4; 20])).unwrap();
+        let base58_addr_str = Address {
+            scheme: bitcoincash_addr::Scheme::Base58,
+            network: bitcoincash_addr::Network::Test,
+            ..cash_addr.clone()
+        }
+        .encode()
+        .unwrap();
+        let base58_addr = address_decode(&base58_addr_str).unwrap();
+
+        let raw_profile = sign_profile(100);
+        database
+            .put_profile(cash_addr.as_body(), &raw_profile)
+            .unwrap();
+        let response = get_profile(base58_addr, HeaderMap::new(), database)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[ETAG], profile_etag(&raw_profile));
+    }
+
+    #[tokio::test]
+    async fn put_profile_too_large() {
+        let database = Database::try_new("./test_dbs/put_profile_too_large").unwrap();
+
+        let addr = Address::decode("bchtest:qz35wy0grm4tze4p5tvu0fc6kujsa5vnrcr7y5xl65").unwrap();
+        let profile_raw = Bytes::from(vec![0; SETTINGS.limits.profile_size as usize + 1]);
+
+        let err = put_profile(addr, HeaderMap::new(), profile_raw, database)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PutProfileError::TooLarge));
+        assert_eq!(err.to_status(), 413);
+    }
+}
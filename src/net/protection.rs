@@ -1,38 +1,119 @@
-use std::sync::Arc;
+use std::{
+    convert::TryFrom,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use bitcoincash_addr::Address;
-use cashweb::bitcoin_client::{BitcoinClient, HttpClient};
 use cashweb::token::{extract_pop, schemes::hmac_bearer::*, split_pop_token};
 use http::header::HeaderMap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use warp::{http::Response, hyper::Body, reject::Reject};
 
-use crate::net::payments::{generate_payment_request, Wallet};
+use super::{address_encode, error_response, IntoResponse};
+use crate::net::payments::{generate_payment_request, OutputSource, Wallet};
+
+const SEGMENT_SEPARATOR: char = '.';
+const JWT_ALGORITHM: &str = "HS256";
+const JWT_TYPE: &str = "JWT";
+
+/// The endpoints a token grants access to, each paid for separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Messages,
+    Feeds,
+    Profiles,
+}
+
+impl From<Scope> for u8 {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::Messages => 0,
+            Scope::Feeds => 1,
+            Scope::Profiles => 2,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(raw_scope: &str) -> Result<Self, Self::Err> {
+        match raw_scope {
+            "messages" => Ok(Scope::Messages),
+            "feeds" => Ok(Scope::Feeds),
+            "profiles" => Ok(Scope::Profiles),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<u8> for Scope {
+    type Error = ();
+
+    fn try_from(raw_scope: u8) -> Result<Self, Self::Error> {
+        match raw_scope {
+            0 => Ok(Scope::Messages),
+            1 => Ok(Scope::Feeds),
+            2 => Ok(Scope::Profiles),
+            _ => Err(()),
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum ProtectionError {
     #[error("missing token: {0:?}")] // TODO: Make this prettier
-    MissingToken(Address, Wallet, BitcoinClient<HttpClient>),
+    MissingToken(Address, Scope, Wallet, OutputSource, u64),
     #[error("validation failed: {0}")]
-    Validation(ValidationError),
+    Validation(TokenError),
+}
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("token expired")]
+    Expired,
+    #[error(transparent)]
+    Invalid(ValidationError),
+    #[error("token does not grant access to this address and scope")]
+    Unauthorized,
+}
+
+impl TokenError {
+    /// Machine-readable error code.
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Malformed => "TOKEN_MALFORMED",
+            Self::Expired => "TOKEN_EXPIRED",
+            Self::Invalid(_) => "TOKEN_INVALID",
+            Self::Unauthorized => "TOKEN_UNAUTHORIZED",
+        }
+    }
 }
 
 pub async fn protection_error_recovery(err: &ProtectionError) -> Response<Body> {
     match err {
-        ProtectionError::Validation(_) => Response::builder()
-            .status(400)
-            .body(Body::from(err.to_string()))
-            .unwrap(),
-        ProtectionError::MissingToken(addr, wallet, bitcoin_client) => {
+        ProtectionError::Validation(token_err) => {
+            error_response(400, token_err.to_code(), &err.to_string())
+        }
+        ProtectionError::MissingToken(addr, scope, wallet, output_source, token_fee) => {
             // TODO: Remove clones here
-            match generate_payment_request(addr.clone(), wallet.clone(), bitcoin_client.clone())
-                .await
+            match generate_payment_request(
+                addr.clone(),
+                *scope,
+                wallet.clone(),
+                output_source.clone(),
+                *token_fee,
+            )
+            .await
             {
                 Ok(ok) => ok,
-                Err(err) => Response::builder()
-                    .status(400)
-                    .body(Body::from(err.to_string()))
-                    .unwrap(),
+                Err(err) => err.into_response(),
             }
         }
     }
@@ -40,13 +121,113 @@ pub async fn protection_error_recovery(err: &ProtectionError) -> Response<Body>
 
 impl Reject for ProtectionError {}
 
+fn get_unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    typ: String,
+}
+
+/// The claims carried by a token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// The address the token grants access to.
+    pub sub: String,
+    pub scope: Scope,
+    /// Issue time, in seconds since the Unix epoch.
+    pub iat: u64,
+    /// Expiry time, in seconds since the Unix epoch.
+    pub exp: u64,
+}
+
+fn encode_segment<T: Serialize>(value: &T) -> String {
+    let raw_value = serde_json::to_vec(value).unwrap(); // This is safe
+    base64::encode_config(raw_value, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_segment<T: DeserializeOwned>(segment: &str) -> Result<T, TokenError> {
+    let raw_value = base64::decode_config(segment, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| TokenError::Malformed)?;
+    serde_json::from_slice(&raw_value).map_err(|_| TokenError::Malformed)
+}
+
+/// Construct a token for the address payload and scope which expires after `ttl` milliseconds.
+///
+/// The token is a JWT signed using HMAC-SHA256 so clients can read their own claims.
+pub fn construct_token(
+    token_scheme: &HmacScheme,
+    addr_payload: &[u8],
+    scope: Scope,
+    ttl: u64,
+) -> String {
+    let now = get_unix_now();
+    let header = JwtHeader {
+        alg: JWT_ALGORITHM.to_string(),
+        typ: JWT_TYPE.to_string(),
+    };
+    let claims = Claims {
+        sub: address_encode(addr_payload.to_vec()),
+        scope,
+        iat: now / 1000,
+        exp: now.saturating_add(ttl) / 1000,
+    };
+    let signing_input = format!(
+        "{}{}{}",
+        encode_segment(&header),
+        SEGMENT_SEPARATOR,
+        encode_segment(&claims)
+    );
+    let signature = token_scheme.construct_token(signing_input.as_bytes());
+    format!("{}{}{}", signing_input, SEGMENT_SEPARATOR, signature)
+}
+
+/// Validate a token constructed by [`construct_token`], returning its claims.
+pub fn validate_token(
+    token_scheme: &HmacScheme,
+    addr_payload: &[u8],
+    scope: Scope,
+    token: &str,
+) -> Result<Claims, TokenError> {
+    let mut split = token.rsplitn(2, SEGMENT_SEPARATOR);
+    let signature = split.next().ok_or(TokenError::Malformed)?;
+    let signing_input = split.next().ok_or(TokenError::Malformed)?;
+
+    // Check the signature before the claims so forged claims are reported as invalid
+    token_scheme
+        .validate_token(signing_input.as_bytes(), signature)
+        .map_err(TokenError::Invalid)?;
+
+    let mut segments = signing_input.splitn(2, SEGMENT_SEPARATOR);
+    let header: JwtHeader = decode_segment(segments.next().ok_or(TokenError::Malformed)?)?;
+    if header.alg != JWT_ALGORITHM {
+        return Err(TokenError::Malformed);
+    }
+    let claims: Claims = decode_segment(segments.next().ok_or(TokenError::Malformed)?)?;
+
+    if claims.sub != address_encode(addr_payload.to_vec()) || claims.scope != scope {
+        return Err(TokenError::Unauthorized);
+    }
+    if claims.exp <= get_unix_now() / 1000 {
+        return Err(TokenError::Expired);
+    }
+    Ok(claims)
+}
+
 pub async fn pop_protection(
     addr: Address,
+    scope: Scope,
     header_map: HeaderMap,
     access_token: Option<String>,
+    token_fee: u64,
     token_scheme: Arc<HmacScheme>,
     wallet: Wallet,
-    bitcoin_client: BitcoinClient<HttpClient>,
+    output_source: OutputSource,
 ) -> Result<Address, ProtectionError> {
     match extract_pop(&header_map).or_else(|| {
         access_token
@@ -54,11 +235,76 @@ pub async fn pop_protection(
             .and_then(|access_token| split_pop_token(access_token))
     }) {
         Some(pop_token) => {
-            token_scheme
-                .validate_token(&addr.as_body().to_vec(), pop_token)
+            validate_token(&token_scheme, addr.as_body(), scope, pop_token)
                 .map_err(ProtectionError::Validation)?;
             Ok(addr)
         }
-        None => Err(ProtectionError::MissingToken(addr, wallet, bitcoin_client)),
+        None => Err(ProtectionError::MissingToken(
+            addr,
+            scope,
+            wallet,
+            output_source,
+            token_fee,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_roundtrip() {
+        let token_scheme = HmacScheme::new(b"secret");
+        let token = construct_token(&token_scheme, &[1; 20], Scope::Messages, 10_000);
+        let claims = validate_token(&token_scheme, &[1; 20], Scope::Messages, &token).unwrap();
+        assert_eq!(claims.sub, address_encode(vec![1; 20]));
+        assert_eq!(claims.scope, Scope::Messages);
+        assert_eq!(claims.exp - claims.iat, 10);
+        assert!(matches!(
+            validate_token(&token_scheme, &[2; 20], Scope::Messages, &token),
+            Err(TokenError::Unauthorized)
+        ));
+        assert!(matches!(
+            validate_token(&token_scheme, &[1; 20], Scope::Profiles, &token),
+            Err(TokenError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn token_readable_claims() {
+        let token_scheme = HmacScheme::new(b"secret");
+        let token = construct_token(&token_scheme, &[1; 20], Scope::Feeds, 10_000);
+        let segments: Vec<_> = token.split(SEGMENT_SEPARATOR).collect();
+        assert_eq!(segments.len(), 3);
+
+        let header: JwtHeader = decode_segment(segments[0]).unwrap();
+        assert_eq!(header.alg, "HS256");
+        let claims: serde_json::Value = decode_segment(segments[1]).unwrap();
+        assert_eq!(claims["scope"], "feeds");
+    }
+
+    #[test]
+    fn token_expired() {
+        let token_scheme = HmacScheme::new(b"secret");
+        let token = construct_token(&token_scheme, &[1; 20], Scope::Messages, 0);
+        assert!(matches!(
+            validate_token(&token_scheme, &[1; 20], Scope::Messages, &token),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn token_forged_claims() {
+        let token_scheme = HmacScheme::new(b"secret");
+        let token = construct_token(&token_scheme, &[1; 20], Scope::Messages, 0);
+        let segments: Vec<_> = token.split(SEGMENT_SEPARATOR).collect();
+        let mut claims: Claims = decode_segment(segments[1]).unwrap();
+        claims.exp = u64::MAX;
+        let forged_token = [segments[0], &encode_segment(&claims), segments[2]].join(".");
+        assert!(matches!(
+            validate_token(&token_scheme, &[1; 20], Scope::Messages, &forged_token),
+            Err(TokenError::Invalid(_))
+        ));
     }
 }
@@ -0,0 +1,167 @@
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bitcoincash_addr::Address;
+use dashmap::DashMap;
+use thiserror::Error;
+use warp::reject::Reject;
+
+use super::IntoResponse;
+
+#[derive(Debug, Error)]
+pub enum RateLimitError {
+    #[error("too many requests for address")]
+    Address,
+    #[error("too many requests from {0}")]
+    Ip(IpAddr),
+}
+
+impl Reject for RateLimitError {}
+
+impl IntoResponse for RateLimitError {
+    fn to_status(&self) -> u16 {
+        429
+    }
+
+    fn to_code(&self) -> &'static str {
+        match self {
+            Self::Address => "ADDRESS_RATE_LIMITED",
+            Self::Ip(_) => "IP_RATE_LIMITED",
+        }
+    }
+}
+
+/// Sliding window log of request times.
+#[derive(Debug, Default)]
+struct Window(VecDeque<Instant>);
+
+impl Window {
+    /// Drop requests which have fallen out of the window.
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while let Some(oldest) = self.0.front() {
+            if now.duration_since(*oldest) < window {
+                break;
+            }
+            self.0.pop_front();
+        }
+    }
+
+    /// Prune the window, returning whether any requests remain.
+    fn retain(&mut self, now: Instant, window: Duration) -> bool {
+        self.prune(now, window);
+        !self.0.is_empty()
+    }
+}
+
+/// Limits the number of requests per address and per source IP over a sliding window.
+#[derive(Debug)]
+pub struct RateLimiter {
+    window: Duration,
+    address_limit: usize,
+    ip_limit: usize,
+    addresses: DashMap<Vec<u8>, Window>,
+    ips: DashMap<IpAddr, Window>,
+}
+
+impl RateLimiter {
+    pub fn new(window: Duration, address_limit: usize, ip_limit: usize) -> Self {
+        Self {
+            window,
+            address_limit,
+            ip_limit,
+            addresses: DashMap::new(),
+            ips: DashMap::new(),
+        }
+    }
+
+    /// Record a request, failing if either the address or the IP is over its limit.
+    ///
+    /// Rejected requests are not recorded.
+    pub fn check(&self, addr_payload: &[u8], ip: Option<IpAddr>) -> Result<(), RateLimitError> {
+        let now = Instant::now();
+
+        let mut address_window = self.addresses.entry(addr_payload.to_vec()).or_default();
+        address_window.prune(now, self.window);
+        if address_window.0.len() >= self.address_limit {
+            return Err(RateLimitError::Address);
+        }
+
+        if let Some(ip) = ip {
+            let mut ip_window = self.ips.entry(ip).or_default();
+            ip_window.prune(now, self.window);
+            if ip_window.0.len() >= self.ip_limit {
+                return Err(RateLimitError::Ip(ip));
+            }
+            ip_window.0.push_back(now);
+        }
+
+        address_window.0.push_back(now);
+        Ok(())
+    }
+
+    /// Remove addresses and IPs which have no requests in the current window.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.addresses
+            .retain(|_, requests| requests.retain(now, self.window));
+        self.ips
+            .retain(|_, requests| requests.retain(now, self.window));
+    }
+}
+
+pub async fn rate_limit(
+    addr: Address,
+    client_ip: Option<IpAddr>,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<Address, RateLimitError> {
+    rate_limiter.check(addr.as_body(), client_ip)?;
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn address_limit() {
+        let rate_limiter = RateLimiter::new(Duration::from_secs(60), 2, 10);
+        assert!(rate_limiter.check(&[1; 20], Some(IP)).is_ok());
+        assert!(rate_limiter.check(&[1; 20], None).is_ok());
+        assert!(matches!(
+            rate_limiter.check(&[1; 20], Some(IP)),
+            Err(RateLimitError::Address)
+        ));
+        assert!(rate_limiter.check(&[2; 20], Some(IP)).is_ok());
+    }
+
+    #[test]
+    fn ip_limit() {
+        let rate_limiter = RateLimiter::new(Duration::from_secs(60), 10, 2);
+        assert!(rate_limiter.check(&[1; 20], Some(IP)).is_ok());
+        assert!(rate_limiter.check(&[2; 20], Some(IP)).is_ok());
+        assert!(matches!(
+            rate_limiter.check(&[3; 20], Some(IP)),
+            Err(RateLimitError::Ip(_))
+        ));
+        assert!(rate_limiter.check(&[3; 20], None).is_ok());
+    }
+
+    #[test]
+    fn window_expiry() {
+        let rate_limiter = RateLimiter::new(Duration::from_millis(10), 1, 1);
+        assert!(rate_limiter.check(&[1; 20], Some(IP)).is_ok());
+        assert!(rate_limiter.check(&[1; 20], Some(IP)).is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        rate_limiter.sweep();
+        assert!(rate_limiter.addresses.is_empty());
+        assert!(rate_limiter.check(&[1; 20], Some(IP)).is_ok());
+    }
+}
@@ -0,0 +1,140 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{field::display, Span};
+use warp::{
+    http::{header::HeaderMap, HeaderValue, Response},
+    hyper::Body,
+    trace::Info,
+    Reply,
+};
+
+use super::client_ip;
+use crate::SETTINGS;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const REQUEST_ID_LEN: usize = 16;
+
+/// Longest client supplied request ID which is accepted.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Get the client supplied request ID, if it is short and only uses characters safe to log and
+/// echo.
+fn client_request_id(header_map: &HeaderMap) -> Option<&str> {
+    header_map
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= MAX_REQUEST_ID_LEN
+                && value
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte))
+        })
+}
+
+/// Generate a random request ID.
+fn new_request_id() -> String {
+    let mut raw_request_id = [0; REQUEST_ID_LEN];
+    SystemRandom::new().fill(&mut raw_request_id).unwrap(); // This is safe
+    hex::encode(raw_request_id)
+}
+
+/// Create a span for the request, tagged with the request ID once it is resolved, see
+/// [`resolve_request_id`].
+///
+/// Every event logged while handling the request is recorded within this span.
+pub fn request_span(info: Info) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        request_id = tracing::field::Empty,
+        method = %info.method(),
+        path = %info.path(),
+        remote.addr = tracing::field::Empty,
+        client.addr = tracing::field::Empty,
+    );
+    if let Some(remote_addr) = info.remote_addr() {
+        span.record("remote.addr", &display(remote_addr));
+    }
+    if let Some(client_ip) = client_ip(
+        info.remote_addr(),
+        info.request_headers(),
+        &SETTINGS.trusted_proxies,
+    ) {
+        span.record("client.addr", &display(client_ip));
+    }
+    span
+}
+
+/// Log the outcome of a request.
+///
+/// The request ID, remote address and client address are carried by the enclosing request span.
+pub fn access_log(info: Info) {
+    tracing::info!(
+        target: "access",
+        method = %info.method(),
+        path = info.path(),
+        status = info.status().as_u16(),
+        duration_ms = info.elapsed().as_millis() as u64,
+    );
+}
+
+/// Use the client supplied request ID or generate one, recording it in the request span.
+pub fn resolve_request_id(header_map: HeaderMap) -> String {
+    let request_id = client_request_id(&header_map)
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+    Span::current().record("request_id", &display(&request_id));
+    request_id
+}
+
+/// Echo the request ID in the response.
+pub fn with_request_id(request_id: String, reply: impl Reply) -> Response<Body> {
+    let mut response = reply.into_response();
+    // Request IDs are either validated or generated as visible ASCII
+    let value = HeaderValue::from_str(&request_id).unwrap();
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_request_id() {
+        let request_id = |value: Option<&str>| {
+            let mut header_map = HeaderMap::new();
+            if let Some(value) = value {
+                header_map.insert(REQUEST_ID_HEADER, HeaderValue::from_str(value).unwrap());
+            }
+            let response = with_request_id(resolve_request_id(header_map), warp::reply());
+            response.headers()[REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(request_id(Some("abc-123")), "abc-123");
+
+        // Missing, long and unsafe IDs are replaced with a generated one
+        let long_request_id = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for value in &[
+            None,
+            Some(""),
+            Some(long_request_id.as_str()),
+            Some("abc 123"),
+            Some("a\"b"),
+        ] {
+            let echoed = request_id(*value);
+            assert_eq!(echoed.len(), 2 * REQUEST_ID_LEN);
+            assert!(echoed.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        }
+    }
+
+    #[test]
+    fn generated_request_ids() {
+        let request_id = new_request_id();
+        assert_eq!(request_id.len(), 2 * REQUEST_ID_LEN);
+        assert_ne!(request_id, new_request_id());
+    }
+}
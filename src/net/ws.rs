@@ -1,14 +1,17 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use bitcoincash_addr::Address;
 use dashmap::DashMap;
-use futures::prelude::*;
+use futures::{future::Either, pin_mut, prelude::*};
 use thiserror::Error;
 use tokio::{
     sync::broadcast,
     time::{interval, Duration},
 };
-use tracing::error;
+use tracing::{error, warn};
 use warp::{
     ws::{Message, WebSocket, Ws},
     Reply,
@@ -34,9 +37,28 @@ enum WsError {
     SinkError(warp::Error),
     #[error("broadcast failure: {0}")]
     BusError(broadcast::RecvError),
+    #[error("no pong received for {0:?}")]
+    PongTimeout(Duration),
 }
 
 pub async fn connect_ws(pubkey_hash: Vec<u8>, ws: WebSocket, msg_bus: MessageBus) {
+    stream_messages(
+        pubkey_hash,
+        ws,
+        msg_bus,
+        Duration::from_millis(SETTINGS.websocket.ping_interval),
+        Duration::from_millis(SETTINGS.websocket.pong_timeout),
+    )
+    .await
+}
+
+async fn stream_messages(
+    pubkey_hash: Vec<u8>,
+    ws: WebSocket,
+    msg_bus: MessageBus,
+    ping_interval: Duration,
+    pong_timeout: Duration,
+) {
     let rx = msg_bus
         .entry(pubkey_hash.clone())
         .or_insert(broadcast::channel(BROADCAST_CHANNEL_CAPACITY).0)
@@ -44,20 +66,167 @@ pub async fn connect_ws(pubkey_hash: Vec<u8>, ws: WebSocket, msg_bus: MessageBus
         .map_ok(Message::binary)
         .map_err(WsError::BusError);
 
-    let (user_ws_tx, _) = ws.split();
+    let (user_ws_tx, user_ws_rx) = ws.split();
 
-    // Setup periodic ping
-    let periodic_ping = interval(Duration::from_millis(SETTINGS.websocket.ping_interval))
-        .map(move |_| Ok(Message::ping(vec![])));
+    // Record the time of the last pong
+    let last_pong = Arc::new(Mutex::new(Instant::now()));
+    let last_pong_inner = last_pong.clone();
+    let read_pongs = user_ws_rx.try_for_each(move |message| {
+        if message.is_pong() {
+            *last_pong_inner.lock().unwrap() = Instant::now();
+        }
+        future::ok(())
+    });
+
+    // Setup periodic ping, closing the socket once the client stops answering
+    let periodic_ping = interval(ping_interval).map(move |_| {
+        if last_pong.lock().unwrap().elapsed() > pong_timeout {
+            Err(WsError::PongTimeout(pong_timeout))
+        } else {
+            Ok(Message::ping(vec![]))
+        }
+    });
     let merged = stream::select(rx, periodic_ping);
+    let forward = merged.forward(user_ws_tx.sink_map_err(WsError::SinkError));
 
-    if let Err(err) = merged
-        .forward(user_ws_tx.sink_map_err(WsError::SinkError))
-        .await
+    // Stop forwarding if the client closes the socket, dropping the subscription before cleanup
     {
-        error!(message = "forwarding error", error = %err);
+        pin_mut!(forward);
+        match future::select(forward, read_pongs).await {
+            Either::Left((Err(WsError::PongTimeout(_)), _)) => {
+                warn!(message = "closing dead websocket")
+            }
+            Either::Left((Err(err), _)) => error!(message = "forwarding error", error = %err),
+            Either::Right((Err(err), _)) => {
+                error!(message = "websocket receive failed", error = %err)
+            }
+            _ => (),
+        }
     }
 
     // TODO: Double check this is atomic
     msg_bus.remove_if(&pubkey_hash, |_, sender| sender.receiver_count() == 0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cashweb::relay::{stamp::Stamp, Message as RelayMessage, MessageSet};
+    use prost::Message as _;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use warp::Filter;
+
+    use crate::{
+        db::{Database, MESSAGE_NAMESPACE},
+        net::{
+            fixtures::{signer_address, signer_public_key, unreachable_node},
+            put_message, Moderation,
+        },
+    };
+
+    #[tokio::test]
+    async fn push_on_put() {
+        let database = Database::try_new("./test_dbs/push_on_put").unwrap();
+        let bitcoin_client = unreachable_node();
+        let msg_bus: MessageBus = Arc::new(DashMap::new());
+
+        // Self-sent messages skip stamp verification
+        let raw_public_key = signer_public_key();
+        let addr = signer_address();
+
+        // Connect
+        let msg_bus_inner = msg_bus.clone();
+        let ws_addr = addr.clone();
+        let filter = warp::ws()
+            .map(move |ws| upgrade_ws(ws_addr.clone(), ws, msg_bus_inner.clone()).into_response());
+        let mut client = warp::test::ws().handshake(filter).await.unwrap();
+
+        // The first tick of the periodic ping arrives once subscribed
+        assert!(client.recv().await.unwrap().is_ping());
+
+        // Put message
+        let message = RelayMessage {
+            source_public_key: raw_public_key.clone(),
+            destination_public_key: raw_public_key,
+            payload: b"hello".to_vec(),
+            payload_hmac: vec![0; 32],
+            stamp: Some(Stamp::default()),
+            ..Default::default()
+        };
+        let message_set = MessageSet {
+            messages: vec![message],
+        };
+        let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+        message_set.encode(&mut raw_message_set).unwrap();
+        put_message(
+            addr,
+            raw_message_set.into(),
+            database,
+            bitcoin_client,
+            msg_bus,
+            Arc::new(Moderation::default()),
+            MESSAGE_NAMESPACE,
+        )
+        .await
+        .unwrap();
+
+        // Receive push
+        let pushed = loop {
+            let ws_message = client.recv().await.unwrap();
+            if ws_message.is_binary() {
+                break ws_message;
+            }
+        };
+        let pushed_message = RelayMessage::decode(pushed.as_bytes()).unwrap();
+        assert_eq!(pushed_message.payload, b"hello".to_vec());
+    }
+
+    #[tokio::test]
+    async fn close_dead_socket() {
+        let msg_bus: MessageBus = Arc::new(DashMap::new());
+        let pubkey_hash = vec![0; 20];
+
+        // Connect with a short ping interval and pong timeout
+        let msg_bus_inner = msg_bus.clone();
+        let pubkey_hash_inner = pubkey_hash.clone();
+        let filter = warp::ws().map(move |ws: Ws| {
+            let msg_bus = msg_bus_inner.clone();
+            let pubkey_hash = pubkey_hash_inner.clone();
+            ws.on_upgrade(move |socket| {
+                stream_messages(
+                    pubkey_hash,
+                    socket,
+                    msg_bus,
+                    Duration::from_millis(10),
+                    Duration::from_millis(50),
+                )
+            })
+        });
+        let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Perform the handshake by hand, after which the client never reads and so never pongs
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Connection: upgrade\r\n\
+                Upgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = [0; 12];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 101");
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        assert!(!msg_bus.contains_key(&pubkey_hash));
+    }
+}
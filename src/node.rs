@@ -0,0 +1,558 @@
+use std::{
+    fs,
+    future::Future,
+    io,
+    sync::{Arc, Mutex, RwLock},
+    time::{Instant, SystemTime},
+};
+
+use async_json_rpc::prelude::RequestFactory;
+use cashweb::{
+    bitcoin::Network,
+    bitcoin_client::{BitcoinClient, HttpError, NodeError},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::time::{delay_for, Duration};
+use tracing::warn;
+use warp::hyper::Client as HyperClient;
+
+use crate::{
+    proxy::{Proxy, ProxyConnector},
+    SETTINGS,
+};
+
+/// HTTP client connecting to nodes directly or through the configured proxy.
+pub type RpcClient = HyperClient<ProxyConnector>;
+
+#[derive(Debug, Deserialize)]
+pub struct BlockchainInfo {
+    pub chain: String,
+    pub blocks: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum CookieError {
+    #[error("failed to read cookie file: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed cookie file")]
+    Malformed,
+}
+
+/// Read the `user:password` pair from a node's cookie file.
+pub fn read_cookie(path: &str) -> Result<(String, String), CookieError> {
+    let cookie = fs::read_to_string(path)?;
+    let mut split = cookie.trim_end().splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some(username), Some(password)) if !username.is_empty() => {
+            Ok((username.to_string(), password.to_string()))
+        }
+        _ => Err(CookieError::Malformed),
+    }
+}
+
+pub const SATOSHIS_PER_BCH: f64 = 100_000_000.0;
+
+/// JSON-RPC error code for unknown methods.
+pub const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+/// Credentials read from a node's cookie file.
+///
+/// The node writes a fresh cookie each time it starts, so the file is re-read whenever it changes.
+#[derive(Debug)]
+struct Cookie {
+    path: String,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+/// Client for a list of nodes in order of preference, failing over to the next node when a node
+/// can't be reached.
+#[derive(Clone, Debug)]
+pub struct NodeClient {
+    clients: Arc<RwLock<Arc<Vec<BitcoinClient<RpcClient>>>>>,
+    cookie: Option<Arc<Cookie>>,
+    fee_rate: Arc<Mutex<Option<(Instant, u64)>>>,
+}
+
+impl NodeClient {
+    /// Create a client from a non-empty list of nodes.
+    pub fn new(clients: Vec<BitcoinClient<RpcClient>>) -> Self {
+        assert!(!clients.is_empty(), "at least one node is required");
+        Self {
+            clients: Arc::new(RwLock::new(Arc::new(clients))),
+            cookie: None,
+            fee_rate: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Rebuild the clients with the credentials from the cookie file, if it changed since it was
+    /// last read.
+    ///
+    /// While the cookie can't be read, such as when the node is stopped, the previous credentials
+    /// are kept.
+    fn refresh_cookie(&self) -> Result<(), CookieError> {
+        let cookie = match &self.cookie {
+            Some(cookie) => cookie,
+            None => return Ok(()),
+        };
+        let modified = fs::metadata(&cookie.path)?.modified()?;
+        let mut last_modified = cookie.modified.lock().unwrap();
+        if *last_modified == Some(modified) {
+            return Ok(());
+        }
+
+        let (username, password) = read_cookie(&cookie.path)?;
+        *self.clients.write().unwrap() = Arc::new(build_clients(&username, &password));
+        *last_modified = Some(modified);
+        Ok(())
+    }
+
+    /// The clients for each node, in order of preference.
+    pub fn clients(&self) -> Arc<Vec<BitcoinClient<RpcClient>>> {
+        // Failures are surfaced by the nodes rejecting the stale credentials
+        let _ = self.refresh_cookie();
+        self.clients.read().unwrap().clone()
+    }
+
+    /// The estimated fee rate in satoshis per kilobyte, cached for `ttl`.
+    pub async fn fee_rate(&self, ttl: Duration) -> Result<u64, HttpError> {
+        if let Some((fetched, fee_rate)) = *self.fee_rate.lock().unwrap() {
+            if fetched.elapsed() < ttl {
+                return Ok(fee_rate);
+            }
+        }
+
+        let fee_rate = self
+            .call(|client| async move { estimate_fee(&client).await })
+            .await?;
+        *self.fee_rate.lock().unwrap() = Some((Instant::now(), fee_rate));
+        Ok(fee_rate)
+    }
+
+    /// Call each node in turn, with retries, until one can be reached.
+    ///
+    /// The node rejecting a request is returned immediately as the request is at fault rather
+    /// than the node, see [`is_retryable`].
+    pub async fn call<F, Fut, T>(&self, call: F) -> Result<T, HttpError>
+    where
+        F: FnMut(BitcoinClient<RpcClient>) -> Fut,
+        Fut: Future<Output = Result<T, HttpError>>,
+    {
+        failover(
+            &self.clients(),
+            SETTINGS.bitcoin_rpc.max_retries,
+            SETTINGS.bitcoin_rpc.base_delay,
+            call,
+        )
+        .await
+    }
+}
+
+async fn failover<F, Fut, T>(
+    clients: &[BitcoinClient<RpcClient>],
+    max_retries: u32,
+    base_delay: u64,
+    mut call: F,
+) -> Result<T, HttpError>
+where
+    F: FnMut(BitcoinClient<RpcClient>) -> Fut,
+    Fut: Future<Output = Result<T, HttpError>>,
+{
+    let (last, preferred) = clients.split_last().unwrap(); // This is safe
+    for (index, client) in preferred.iter().enumerate() {
+        match retry(max_retries, base_delay, || call(client.clone())).await {
+            Err(err) if is_retryable(&err) => {
+                warn!(message = "node unreachable, failing over", error = %err, index);
+            }
+            result => return result,
+        }
+    }
+    retry(max_retries, base_delay, || call(last.clone())).await
+}
+
+/// Construct a [`NodeClient`] for the primary and fallback nodes, sharing a keep-alive connection
+/// pool configured from settings.
+///
+/// If a cookie file is configured then the credentials are read from it, and re-read whenever the
+/// node rewrites it. Otherwise the configured username and password are used. The same
+/// credentials and proxy are used for every node.
+pub fn new_client() -> NodeClient {
+    let mut bitcoin_client = NodeClient::new(build_clients(
+        &SETTINGS.bitcoin_rpc.username,
+        &SETTINGS.bitcoin_rpc.password,
+    ));
+    if let Some(cookie_path) = &SETTINGS.bitcoin_rpc.cookie_path {
+        bitcoin_client.cookie = Some(Arc::new(Cookie {
+            path: cookie_path.clone(),
+            modified: Mutex::new(None),
+        }));
+        // The node removes its cookie while stopped, it is read once the node writes it
+        if let Err(err) = bitcoin_client.refresh_cookie() {
+            warn!(message = "failed to read rpc cookie", error = %err);
+        }
+    }
+    bitcoin_client
+}
+
+fn build_clients(username: &str, password: &str) -> Vec<BitcoinClient<RpcClient>> {
+    // The proxy was validated when loading settings
+    let proxy = SETTINGS
+        .bitcoin_rpc
+        .proxy
+        .as_ref()
+        .map(|proxy| Proxy::parse(proxy).unwrap());
+    let http_client = HyperClient::builder()
+        .pool_max_idle_per_host(SETTINGS.bitcoin_rpc.pool_max_idle)
+        .pool_idle_timeout(Duration::from_millis(
+            SETTINGS.bitcoin_rpc.pool_idle_timeout,
+        ))
+        .build(ProxyConnector::new(proxy));
+    std::iter::once(&SETTINGS.bitcoin_rpc.address)
+        .chain(&SETTINGS.bitcoin_rpc.fallback_addresses)
+        .map(|address| {
+            BitcoinClient::from_service(
+                http_client.clone(),
+                address.clone(),
+                username.to_string(),
+                password.to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Construct a client connecting directly to a single node.
+pub fn direct_client(
+    address: String,
+    username: String,
+    password: String,
+) -> BitcoinClient<RpcClient> {
+    let http_client = HyperClient::builder().build(ProxyConnector::new(None));
+    BitcoinClient::from_service(http_client, address, username, password)
+}
+
+impl BlockchainInfo {
+    /// The network corresponding to the `chain` field.
+    pub fn network(&self) -> Option<Network> {
+        match self.chain.as_str() {
+            "main" => Some(Network::Mainnet),
+            "test" => Some(Network::Testnet),
+            "regtest" => Some(Network::Regtest),
+            _ => None,
+        }
+    }
+}
+
+/// Calls the `getblockchaininfo` method.
+pub async fn get_blockchain_info(
+    bitcoin_client: &BitcoinClient<RpcClient>,
+) -> Result<BlockchainInfo, HttpError> {
+    let request = bitcoin_client
+        .build_request()
+        .method("getblockchaininfo")
+        .finish()
+        .unwrap();
+    let response = bitcoin_client
+        .send(request)
+        .await
+        .map_err(NodeError::Http)?;
+    if response.is_error() {
+        return Err(NodeError::Rpc(response.error().unwrap()));
+    }
+    response
+        .into_result()
+        .ok_or(NodeError::EmptyResponse)?
+        .map_err(NodeError::Json)
+}
+
+/// Calls the `estimatefee` method, converting the fee rate to satoshis per kilobyte.
+pub async fn estimate_fee(bitcoin_client: &BitcoinClient<RpcClient>) -> Result<u64, HttpError> {
+    let request = bitcoin_client
+        .build_request()
+        .method("estimatefee")
+        .finish()
+        .unwrap();
+    let response = bitcoin_client
+        .send(request)
+        .await
+        .map_err(NodeError::Http)?;
+    if response.is_error() {
+        return Err(NodeError::Rpc(response.error().unwrap()));
+    }
+    let fee_rate: f64 = response
+        .into_result()
+        .ok_or(NodeError::EmptyResponse)?
+        .map_err(NodeError::Json)?;
+    Ok((fee_rate * SATOSHIS_PER_BCH).round() as u64)
+}
+
+/// The node's verdict on whether a transaction would be accepted to its mempool.
+#[derive(Debug, Deserialize)]
+pub struct MempoolAcceptance {
+    pub txid: String,
+    pub allowed: bool,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+}
+
+/// Reject reasons given for transactions the node already has.
+const ALREADY_KNOWN_REASONS: &[&str] = &["txn-already-in-mempool", "txn-already-known"];
+
+impl MempoolAcceptance {
+    /// Whether the transaction would be accepted, or is already in the mempool.
+    ///
+    /// Broadcasting a transaction the node already has succeeds, so it isn't a rejection.
+    pub fn is_accepted(&self) -> bool {
+        self.allowed
+            || self.reject_reason.as_deref().map_or(false, |reason| {
+                ALREADY_KNOWN_REASONS
+                    .iter()
+                    .any(|known| reason.contains(known))
+            })
+    }
+}
+
+/// Calls the `testmempoolaccept` method for a single transaction, without broadcasting it.
+pub async fn test_accept(
+    bitcoin_client: &BitcoinClient<RpcClient>,
+    raw_tx: &[u8],
+) -> Result<MempoolAcceptance, HttpError> {
+    let request = bitcoin_client
+        .build_request()
+        .method("testmempoolaccept")
+        .params(vec![Value::Array(vec![Value::String(hex::encode(raw_tx))])])
+        .finish()
+        .unwrap();
+    let response = bitcoin_client
+        .send(request)
+        .await
+        .map_err(NodeError::Http)?;
+    if response.is_error() {
+        return Err(NodeError::Rpc(response.error().unwrap()));
+    }
+    let acceptances: Vec<MempoolAcceptance> = response
+        .into_result()
+        .ok_or(NodeError::EmptyResponse)?
+        .map_err(NodeError::Json)?;
+    acceptances
+        .into_iter()
+        .next()
+        .ok_or(NodeError::EmptyResponse)
+}
+
+/// Whether a node call failed transiently.
+///
+/// Connection-level failures and empty responses, which are typical of a node mid-restart, are
+/// transient while the node rejecting a request is not.
+pub fn is_retryable(err: &HttpError) -> bool {
+    matches!(err, NodeError::Http(_) | NodeError::EmptyResponse)
+}
+
+/// The status of a response to a request which failed on a node call.
+///
+/// Requests the node rejected are the client's fault, while an unreachable node makes the relay
+/// temporarily unavailable for requests which need it.
+pub fn error_status(err: &HttpError) -> u16 {
+    match err {
+        NodeError::Rpc(_) => 400,
+        err if is_retryable(err) => 503,
+        _ => 500,
+    }
+}
+
+/// Retry a node call with exponential backoff using the configured retry policy.
+///
+/// Only transient failures are retried, the node rejecting a request is returned immediately.
+pub async fn with_retry<F, Fut, T>(call: F) -> Result<T, HttpError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, HttpError>>,
+{
+    retry(
+        SETTINGS.bitcoin_rpc.max_retries,
+        SETTINGS.bitcoin_rpc.base_delay,
+        call,
+    )
+    .await
+}
+
+async fn retry<F, Fut, T>(max_retries: u32, base_delay: u64, mut call: F) -> Result<T, HttpError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, HttpError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Err(err) if attempt < max_retries && is_retryable(&err) => {
+                let delay = base_delay.saturating_mul(1 << attempt.min(32));
+                warn!(message = "node request failed, retrying", error = %err, attempt, delay);
+                delay_for(Duration::from_millis(delay)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn mempool_rejection() {
+        let raw_acceptances =
+            r#"[{"txid": "00", "allowed": false, "reject-reason": "66: insufficient priority"}]"#;
+        let acceptances: Vec<MempoolAcceptance> = serde_json::from_str(raw_acceptances).unwrap();
+        assert!(!acceptances[0].allowed);
+        assert_eq!(
+            acceptances[0].reject_reason.as_deref(),
+            Some("66: insufficient priority")
+        );
+        assert!(!acceptances[0].is_accepted());
+    }
+
+    #[test]
+    fn mempool_already_known() {
+        let acceptance = |reject_reason: &str| MempoolAcceptance {
+            txid: "00".to_string(),
+            allowed: false,
+            reject_reason: Some(reject_reason.to_string()),
+        };
+        assert!(acceptance("18: txn-already-in-mempool").is_accepted());
+        assert!(acceptance("txn-already-known").is_accepted());
+        assert!(!acceptance("16: bad-txns-inputs-missingorspent").is_accepted());
+    }
+
+    #[test]
+    fn cookie_credentials() {
+        fs::create_dir_all("./test_dbs").unwrap();
+
+        let cookie_path = "./test_dbs/cookie_credentials";
+        fs::write(cookie_path, "__cookie__:abc:def\n").unwrap();
+        let (username, password) = read_cookie(cookie_path).unwrap();
+        assert_eq!(username, "__cookie__");
+        assert_eq!(password, "abc:def");
+
+        fs::write(cookie_path, "no separator").unwrap();
+        assert!(matches!(
+            read_cookie(cookie_path),
+            Err(CookieError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn chain_network() {
+        let blockchain_info = BlockchainInfo {
+            chain: "main".to_string(),
+            blocks: 0,
+        };
+        assert_eq!(blockchain_info.network(), Some(Network::Mainnet));
+
+        let blockchain_info = BlockchainInfo {
+            chain: "signet".to_string(),
+            blocks: 0,
+        };
+        assert_eq!(blockchain_info.network(), None);
+    }
+
+    #[tokio::test]
+    async fn retry_connection_failure() {
+        let bitcoin_client = direct_client(
+            "http://127.0.0.1:1".to_string(),
+            "user".to_string(),
+            "password".to_string(),
+        );
+        let attempts = AtomicU32::new(0);
+        let result = retry(2, 1, || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            get_blockchain_info(&bitcoin_client)
+        })
+        .await;
+        assert!(matches!(result, Err(NodeError::Http(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn failover_connection_failure() {
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                direct_client(
+                    "http://127.0.0.1:1".to_string(),
+                    "user".to_string(),
+                    "password".to_string(),
+                )
+            })
+            .collect();
+        let attempts = AtomicU32::new(0);
+        let result = failover(&clients, 1, 1, |client| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async move { get_blockchain_info(&client).await }
+        })
+        .await;
+        assert!(matches!(result, Err(NodeError::Http(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn no_failover_on_rejection() {
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                direct_client(
+                    "http://127.0.0.1:1".to_string(),
+                    "user".to_string(),
+                    "password".to_string(),
+                )
+            })
+            .collect();
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = failover(&clients, 1, 1, |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err(NodeError::HexDecode(hex::FromHexError::OddLength)) }
+        })
+        .await;
+        assert!(matches!(result, Err(NodeError::HexDecode(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_empty_response() {
+        let attempts = AtomicU32::new(0);
+        let result = retry(2, 1, || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    Err(NodeError::EmptyResponse)
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 1);
+
+        // Persistent empty responses are returned once retries are exhausted
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = retry(2, 1, || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err(NodeError::EmptyResponse) }
+        })
+        .await;
+        assert!(matches!(result, Err(NodeError::EmptyResponse)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn no_retry_on_rejection() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = retry(2, 1, || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err(NodeError::HexDecode(hex::FromHexError::OddLength)) }
+        })
+        .await;
+        assert!(matches!(result, Err(NodeError::HexDecode(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
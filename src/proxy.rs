@@ -0,0 +1,293 @@
+use std::{
+    convert::TryFrom,
+    error::Error as StdError,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use url::Url;
+use warp::{
+    http::Uri,
+    hyper::{
+        client::connect::{Connected, Connection, HttpConnector},
+        service::Service,
+    },
+};
+
+const DEFAULT_HTTP_PROXY_PORT: u16 = 80;
+const DEFAULT_SOCKS5_PROXY_PORT: u16 = 1080;
+const DEFAULT_DESTINATION_PORT: u16 = 80;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_CONNECT: u8 = 1;
+const SOCKS5_IPV4: u8 = 1;
+const SOCKS5_DOMAIN: u8 = 3;
+const SOCKS5_IPV6: u8 = 4;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("failed to parse proxy url: {0}")]
+    Parse(#[from] url::ParseError),
+    #[error("unsupported proxy scheme {0}, expected http or socks5")]
+    UnsupportedScheme(String),
+    #[error("proxy url is missing a host")]
+    MissingHost,
+}
+
+/// A proxy to route outbound connections through.
+///
+/// HTTP proxies are sent requests in absolute form while SOCKS5 proxies are asked to connect to
+/// the destination by host name, so that names such as Tor onion addresses are resolved by the
+/// proxy.
+#[derive(Clone, Debug)]
+pub enum Proxy {
+    Http(Uri),
+    Socks5(Uri),
+}
+
+impl Proxy {
+    pub fn parse(proxy_url: &str) -> Result<Self, ProxyError> {
+        let url = Url::parse(proxy_url)?;
+        let host = url.host_str().ok_or(ProxyError::MissingHost)?;
+        let (default_port, is_socks5) = match url.scheme() {
+            "http" => (DEFAULT_HTTP_PROXY_PORT, false),
+            "socks5" | "socks5h" => (DEFAULT_SOCKS5_PROXY_PORT, true),
+            scheme => return Err(ProxyError::UnsupportedScheme(scheme.to_string())),
+        };
+
+        // The proxy itself is reached over plain TCP
+        let port = url.port().unwrap_or(default_port);
+        let uri = format!("http://{}:{}", host, port)
+            .parse()
+            .map_err(|_| ProxyError::MissingHost)?;
+        if is_socks5 {
+            Ok(Self::Socks5(uri))
+        } else {
+            Ok(Self::Http(uri))
+        }
+    }
+}
+
+/// Connector which connects directly or through a [`Proxy`].
+#[derive(Clone, Debug)]
+pub struct ProxyConnector {
+    http: HttpConnector,
+    proxy: Option<Proxy>,
+}
+
+impl ProxyConnector {
+    pub fn new(proxy: Option<Proxy>) -> Self {
+        Self {
+            http: HttpConnector::new(),
+            proxy,
+        }
+    }
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = ProxyStream;
+    type Error = Box<dyn StdError + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, destination: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let proxy = self.proxy.clone();
+        Box::pin(async move {
+            let stream = match proxy {
+                None => ProxyStream {
+                    stream: http.call(destination).await?,
+                    is_proxied: false,
+                },
+                Some(Proxy::Http(proxy_uri)) => ProxyStream {
+                    stream: http.call(proxy_uri).await?,
+                    is_proxied: true,
+                },
+                Some(Proxy::Socks5(proxy_uri)) => {
+                    let mut stream = http.call(proxy_uri).await?;
+                    let host = destination.host().ok_or(ProxyError::MissingHost)?;
+                    let port = destination.port_u16().unwrap_or(DEFAULT_DESTINATION_PORT);
+                    socks5_connect(&mut stream, host, port).await?;
+                    ProxyStream {
+                        stream,
+                        is_proxied: false,
+                    }
+                }
+            };
+            Ok::<_, Self::Error>(stream)
+        })
+    }
+}
+
+fn socks5_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+/// Perform the SOCKS5 handshake, leaving the stream connected to the destination.
+async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    // Greet, offering no authentication
+    stream
+        .write_all(&[SOCKS5_VERSION, 1, SOCKS5_NO_AUTH])
+        .await?;
+    let mut method_reply = [0; 2];
+    stream.read_exact(&mut method_reply).await?;
+    if method_reply != [SOCKS5_VERSION, SOCKS5_NO_AUTH] {
+        return Err(socks5_error(
+            "socks5 proxy requires authentication".to_string(),
+        ));
+    }
+
+    // Request a connection to the destination by host name
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host_len = u8::try_from(host.len())
+        .map_err(|_| socks5_error(format!("host name too long: {}", host)))?;
+    let request = [
+        &[SOCKS5_VERSION, SOCKS5_CONNECT, 0, SOCKS5_DOMAIN, host_len][..],
+        host.as_bytes(),
+        &port.to_be_bytes(),
+    ]
+    .concat();
+    stream.write_all(&request).await?;
+
+    let mut connect_reply = [0; 4];
+    stream.read_exact(&mut connect_reply).await?;
+    if connect_reply[1] != 0 {
+        return Err(socks5_error(format!(
+            "socks5 proxy failed to connect with reply {}",
+            connect_reply[1]
+        )));
+    }
+
+    // Discard the bound address and port
+    let addr_len = match connect_reply[3] {
+        SOCKS5_IPV4 => 4,
+        SOCKS5_IPV6 => 16,
+        SOCKS5_DOMAIN => stream.read_u8().await? as usize,
+        address_type => {
+            return Err(socks5_error(format!(
+                "unexpected socks5 address type {}",
+                address_type
+            )))
+        }
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// A connection made by [`ProxyConnector`].
+#[derive(Debug)]
+pub struct ProxyStream {
+    stream: TcpStream,
+    is_proxied: bool,
+}
+
+impl Connection for ProxyStream {
+    fn connected(&self) -> Connected {
+        self.stream.connected().proxy(self.is_proxied)
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_proxy() {
+        match Proxy::parse("socks5h://127.0.0.1:9050").unwrap() {
+            Proxy::Socks5(uri) => {
+                assert_eq!(uri.host(), Some("127.0.0.1"));
+                assert_eq!(uri.port_u16(), Some(9050));
+            }
+            proxy => panic!("unexpected proxy {:?}", proxy),
+        }
+        match Proxy::parse("http://proxy.local").unwrap() {
+            Proxy::Http(uri) => {
+                assert_eq!(uri.host(), Some("proxy.local"));
+                assert_eq!(uri.port_u16(), Some(DEFAULT_HTTP_PROXY_PORT));
+            }
+            proxy => panic!("unexpected proxy {:?}", proxy),
+        }
+        assert!(matches!(
+            Proxy::parse("ftp://proxy.local"),
+            Err(ProxyError::UnsupportedScheme(_))
+        ));
+        assert!(matches!(
+            Proxy::parse("not a url"),
+            Err(ProxyError::Parse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn socks5_handshake() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+
+        // Answer as a SOCKS5 proxy, recording the connect request
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 5 + 11 + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            request
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        socks5_connect(&mut stream, "relay.onion", 8332)
+            .await
+            .unwrap();
+
+        let request = proxy.await.unwrap();
+        assert_eq!(&request[..5], &[5, 1, 0, 3, 11]);
+        assert_eq!(&request[5..16], b"relay.onion");
+        assert_eq!(&request[16..], &8332u16.to_be_bytes());
+    }
+}
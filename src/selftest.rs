@@ -0,0 +1,313 @@
+use std::{
+    convert::TryInto,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcoincash_addr::{Address, Network as AddressNetwork};
+use cashweb::{
+    bitcoin_client::{HttpError, NodeError},
+    payments::bip70::{Payment, PaymentDetails, PaymentRequest},
+    relay::{
+        stamp::{create_stamp_private_keys, Stamp, StampOutpoints, StampType},
+        Message, MessagePage, MessageSet,
+    },
+    secp256k1::{
+        key::{PublicKey, SecretKey as PrivateKey},
+        Secp256k1,
+    },
+};
+use prost::{DecodeError, Message as _};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+use tracing::info;
+use warp::{
+    http::{
+        header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+        Request, StatusCode,
+    },
+    hyper::{body::to_bytes, Body, Client as HyperClient, Error as HyperError},
+};
+
+use crate::{
+    node::{self, NodeClient, SATOSHIS_PER_BCH},
+    stamps::pubkey_hash,
+    MESSAGES_PATH, PAYMENTS_PATH, SETTINGS,
+};
+
+const PAYMENT_CONTENT_TYPE: &str = "application/bitcoincash-payment";
+const PAYMENT_ACK_CONTENT_TYPE: &str = "application/bitcoincash-paymentack";
+
+/// Coinbase outputs can only be spent once this many blocks deep.
+const COINBASE_MATURITY: u64 = 100;
+
+/// Value of the self-test stamp, if above the configured minimum.
+const STAMP_VALUE: u64 = 100_000;
+
+#[derive(Debug, Error)]
+pub enum SelftestError {
+    #[error("node request failed: {0}")]
+    Node(HttpError),
+    #[error("relay request failed: {0}")]
+    Http(HyperError),
+    #[error("{0} responded with status {1}")]
+    Status(&'static str, StatusCode),
+    #[error("failed to decode {0}: {1}")]
+    Decode(&'static str, DecodeError),
+    #[error("{0}")]
+    Check(&'static str),
+}
+
+impl From<HyperError> for SelftestError {
+    fn from(err: HyperError) -> Self {
+        Self::Http(err)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawTransaction {
+    hex: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignedTransaction {
+    hex: String,
+    complete: bool,
+}
+
+/// Call an RPC method on the nodes.
+async fn rpc<T: DeserializeOwned>(
+    bitcoin_client: &NodeClient,
+    method: &'static str,
+    params: Vec<Value>,
+) -> Result<T, SelftestError> {
+    bitcoin_client
+        .call(|client| {
+            let params = params.clone();
+            async move {
+                let request = client
+                    .build_request()
+                    .method(method)
+                    .params(params)
+                    .finish()
+                    .unwrap();
+                let response = client.send(request).await.map_err(NodeError::Http)?;
+                if response.is_error() {
+                    return Err(NodeError::Rpc(response.error().unwrap()));
+                }
+                response
+                    .into_result()
+                    .ok_or(NodeError::EmptyResponse)?
+                    .map_err(NodeError::Json)
+            }
+        })
+        .await
+        .map_err(SelftestError::Node)
+}
+
+fn random_private_key() -> PrivateKey {
+    let rng = SystemRandom::new();
+    loop {
+        let mut raw_private_key = [0; 32];
+        rng.fill(&mut raw_private_key).unwrap();
+        if let Ok(private_key) = PrivateKey::from_slice(&raw_private_key) {
+            return private_key;
+        }
+    }
+}
+
+fn regtest_address(pubkey_hash: Vec<u8>) -> String {
+    Address {
+        body: pubkey_hash,
+        network: AddressNetwork::Regtest,
+        ..Default::default()
+    }
+    .encode()
+    .unwrap() // This is safe
+}
+
+/// Build a transaction paying the outputs, funded and signed by the node's wallet.
+///
+/// The change output follows the requested outputs.
+async fn fund_transaction(
+    bitcoin_client: &NodeClient,
+    outputs: &[(String, u64)],
+) -> Result<Vec<u8>, SelftestError> {
+    let outputs: Map<String, Value> = outputs
+        .iter()
+        .map(|(address, value)| (address.clone(), json!(*value as f64 / SATOSHIS_PER_BCH)))
+        .collect();
+    let raw_tx: String = rpc(
+        bitcoin_client,
+        "createrawtransaction",
+        vec![json!([]), Value::Object(outputs.clone())],
+    )
+    .await?;
+    let funded_tx: RawTransaction = rpc(
+        bitcoin_client,
+        "fundrawtransaction",
+        vec![json!(raw_tx), json!({ "changePosition": outputs.len() })],
+    )
+    .await?;
+    let signed_tx: SignedTransaction = rpc(
+        bitcoin_client,
+        "signrawtransactionwithwallet",
+        vec![json!(funded_tx.hex)],
+    )
+    .await?;
+    if !signed_tx.complete {
+        return Err(SelftestError::Check(
+            "node wallet failed to sign transaction",
+        ));
+    }
+    hex::decode(signed_tx.hex).map_err(|_| SelftestError::Check("node returned malformed hex"))
+}
+
+/// Pay for a token, put a stamped message and read it back through the relay at `relay_addr`.
+///
+/// Coins are mined to the node's wallet, which funds the payment and the stamp.
+pub async fn run(relay_addr: SocketAddr) -> Result<(), SelftestError> {
+    let bitcoin_client = node::new_client();
+    let http_client = HyperClient::new();
+    let context = Secp256k1::signing_only();
+
+    // Mine spendable coins
+    info!(message = "generating coins", blocks = COINBASE_MATURITY + 1);
+    let mining_address: String = rpc(&bitcoin_client, "getnewaddress", vec![]).await?;
+    let _: Vec<String> = rpc(
+        &bitcoin_client,
+        "generatetoaddress",
+        vec![json!(COINBASE_MATURITY + 1), json!(mining_address)],
+    )
+    .await?;
+
+    let source_private_key = random_private_key();
+    let source_public_key = PublicKey::from_secret_key(&context, &source_private_key);
+    let destination_private_key = random_private_key();
+    let destination_public_key = PublicKey::from_secret_key(&context, &destination_private_key);
+    let destination_address = regtest_address(pubkey_hash(&destination_public_key.serialize()));
+    let messages_url = format!(
+        "http://{}/{}/{}",
+        relay_addr, MESSAGES_PATH, destination_address
+    );
+
+    // Reading messages without a token returns a payment request
+    info!(message = "requesting payment", address = %destination_address);
+    let response = http_client.get(messages_url.parse().unwrap()).await?;
+    if response.status() != StatusCode::PAYMENT_REQUIRED {
+        return Err(SelftestError::Status("messages", response.status()));
+    }
+    let raw_payment_request = to_bytes(response.into_body()).await?;
+    let payment_request = PaymentRequest::decode(raw_payment_request)
+        .map_err(|err| SelftestError::Decode("payment request", err))?;
+    let payment_details = PaymentDetails::decode(&payment_request.serialized_payment_details[..])
+        .map_err(|err| SelftestError::Decode("payment details", err))?;
+    let outputs = payment_details
+        .outputs
+        .iter()
+        .map(|output| {
+            if output.script.len() != 25 {
+                return Err(SelftestError::Check("payment request output is not p2pkh"));
+            }
+            let address = regtest_address(output.script[3..23].to_vec());
+            Ok((address, output.amount.unwrap_or_default()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Pay for a token
+    info!("paying for token");
+    let payment = Payment {
+        merchant_data: payment_details.merchant_data,
+        transactions: vec![fund_transaction(&bitcoin_client, &outputs).await?],
+        ..Default::default()
+    };
+    let mut raw_payment = Vec::with_capacity(payment.encoded_len());
+    payment.encode(&mut raw_payment).unwrap(); // This is safe
+    let request = Request::post(format!("http://{}/{}", relay_addr, PAYMENTS_PATH))
+        .header(CONTENT_TYPE, PAYMENT_CONTENT_TYPE)
+        .header(ACCEPT, PAYMENT_ACK_CONTENT_TYPE)
+        .body(Body::from(raw_payment))
+        .unwrap(); // This is safe
+    let response = http_client.request(request).await?;
+    if response.status() != StatusCode::OK {
+        return Err(SelftestError::Status("payments", response.status()));
+    }
+    let token = response
+        .headers()
+        .get(AUTHORIZATION)
+        .ok_or(SelftestError::Check("payment ack is missing a token"))?
+        .clone();
+
+    // Stamp a message, paying to the key derived from the destination key and payload digest
+    info!("putting stamped message");
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let payload = format!("cash-relay self-test {}", timestamp).into_bytes();
+    let payload_digest: [u8; 32] = digest(&SHA256, &payload).as_ref().try_into().unwrap(); // This is safe
+    let stamp_private_keys =
+        create_stamp_private_keys(destination_private_key, &payload_digest, vec![1])
+            .map_err(|_| SelftestError::Check("failed to derive stamp key"))?;
+    let stamp_public_key = PublicKey::from_secret_key(&context, &stamp_private_keys[0][0]);
+    let stamp_output = (
+        regtest_address(pubkey_hash(&stamp_public_key.serialize())),
+        SETTINGS.stamps.min_stamp_value.max(STAMP_VALUE),
+    );
+    let stamp_tx = fund_transaction(&bitcoin_client, &[stamp_output]).await?;
+    let message = Message {
+        source_public_key: source_public_key.serialize().to_vec(),
+        destination_public_key: destination_public_key.serialize().to_vec(),
+        payload_digest: payload_digest.to_vec(),
+        payload,
+        payload_hmac: vec![0; 32],
+        stamp: Some(Stamp {
+            stamp_type: StampType::MessageCommitment as i32,
+            stamp_outpoints: vec![StampOutpoints {
+                stamp_tx,
+                vouts: vec![0],
+            }],
+        }),
+        ..Default::default()
+    };
+    let message_set = MessageSet {
+        messages: vec![message],
+    };
+    let mut raw_message_set = Vec::with_capacity(message_set.encoded_len());
+    message_set.encode(&mut raw_message_set).unwrap(); // This is safe
+    let request = Request::put(&messages_url)
+        .body(Body::from(raw_message_set))
+        .unwrap(); // This is safe
+    let response = http_client.request(request).await?;
+    if response.status() != StatusCode::OK {
+        return Err(SelftestError::Status("messages", response.status()));
+    }
+
+    // Read the message back with the token
+    info!("retrieving message");
+    let request = Request::get(&messages_url)
+        .header(AUTHORIZATION, token)
+        .body(Body::empty())
+        .unwrap(); // This is safe
+    let response = http_client.request(request).await?;
+    if response.status() != StatusCode::OK {
+        return Err(SelftestError::Status("messages", response.status()));
+    }
+    let raw_message_page = to_bytes(response.into_body()).await?;
+    let message_page = MessagePage::decode(raw_message_page)
+        .map_err(|err| SelftestError::Decode("message page", err))?;
+    if !message_page
+        .messages
+        .iter()
+        .any(|message| message.payload_digest[..] == payload_digest[..])
+    {
+        return Err(SelftestError::Check("stored message was not retrieved"));
+    }
+
+    Ok(())
+}
@@ -1,24 +1,70 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    process,
+};
 
 use cashweb::bitcoin::Network;
 use clap::App;
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
+use warp::http::{header::HeaderName, uri::Parts, Method, Uri};
+
+use crate::{net::Scope, proxy::Proxy};
 
 const FOLDER_DIR: &str = ".relay";
 const DEFAULT_BIND: &str = "127.0.0.1:8080";
 const DEFAULT_RPC_ADDR: &str = "http://127.0.0.1:18443";
 const DEFAULT_RPC_USER: &str = "user";
 const DEFAULT_RPC_PASSWORD: &str = "password";
+const DEFAULT_RPC_MAX_RETRIES: u32 = 3;
+const DEFAULT_RPC_BASE_DELAY: u64 = 200;
+const DEFAULT_RPC_POOL_MAX_IDLE: usize = 32;
+const DEFAULT_RPC_POOL_IDLE_TIMEOUT: u64 = 90_000; // 90 seconds
 const DEFAULT_NETWORK: &str = "regtest";
+const DEFAULT_STATIC_DIR: &str = "./static/";
+const DEFAULT_SERVE_STATIC: bool = true;
+const DEFAULT_COMPRESSION: bool = true;
+const DEFAULT_LOG_FORMAT: &str = "text";
 const DEFAULT_PING_INTERVAL: u64 = 10_000;
+const DEFAULT_PONG_TIMEOUT: u64 = 30_000;
+const DEBUG_HMAC_SECRET: &str = "1234";
 const DEFAULT_MESSAGE_LIMIT: usize = 1024 * 1024 * 20; // 20Mb
 const DEFAULT_PROFILE_LIMIT: usize = 1024 * 512; // 512Kb
 const DEFAULT_PAYMENT_LIMIT: usize = 1024 * 3; // 3Kb
+const DEFAULT_MAX_PAGE_SIZE: usize = 1_000;
+const DEFAULT_MAX_MESSAGES_PER_RESPONSE: usize = 5_000;
+const DEFAULT_MAX_PROFILE_QUERY: usize = 100;
+const DEFAULT_BODY_TIMEOUT: u64 = 1_000 * 30; // 30 seconds
 const DEFAULT_PAYMENT_TIMEOUT: usize = 1_000 * 60; // 60 seconds
+const DEFAULT_PAYMENT_DRY_RUN: bool = false;
+const DEFAULT_PAYMENT_MAX_OUTPUTS: usize = 32;
+const DEFAULT_PAYMENT_MAX_MEMO_LENGTH: usize = 256;
 const DEFAULT_TRUNCATION_LENGTH: usize = 500;
 const DEFAULT_TOKEN_FEE: u64 = 100_000;
+const DEFAULT_TOKEN_TTL: u64 = 1_000 * 60 * 60 * 24 * 7; // 1 week
+const DEFAULT_MAX_TTL_MULTIPLIER: f64 = 1.;
 const DEFAULT_MEMO: &str = "Thanks for your custom!";
+const DEFAULT_GC_INTERVAL: u64 = 1_000 * 60 * 60; // 1 hour
+const DEFAULT_RATE_LIMIT_WINDOW: u64 = 1_000 * 60; // 1 minute
+const DEFAULT_RATE_LIMIT_ADDRESS: usize = 60;
+const DEFAULT_RATE_LIMIT_IP: usize = 120;
+const DEFAULT_ALLOWED_ORIGIN: &str = "*";
+const DEFAULT_ALLOWED_METHODS: &[&str] = &["GET", "HEAD", "PUT", "POST", "DELETE"];
+const DEFAULT_ALLOWED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-type",
+    "idempotency-key",
+    "if-match",
+    "if-none-match",
+    "if-modified-since",
+    "x-request-id",
+];
+const DEFAULT_MIN_STAMP_VALUE: u64 = 546; // Dust limit
+const DEFAULT_REQUIRE_STAMP: bool = true;
+const DEFAULT_BROADCAST_STAMPS: bool = true;
+const DEFAULT_FEE_RATE_TTL: u64 = 1_000 * 60 * 10; // 10 minutes
 
 #[cfg(feature = "monitoring")]
 const DEFAULT_BIND_PROM: &str = "127.0.0.1:9095";
@@ -26,8 +72,15 @@ const DEFAULT_BIND_PROM: &str = "127.0.0.1:9095";
 #[derive(Debug, Deserialize)]
 pub struct BitcoinRpc {
     pub address: String,
+    pub fallback_addresses: Vec<String>,
     pub username: String,
     pub password: String,
+    pub cookie_path: Option<String>,
+    pub proxy: Option<String>,
+    pub max_retries: u32,
+    pub base_delay: u64,
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,33 +88,162 @@ pub struct Limits {
     pub message_size: u64,
     pub profile_size: u64,
     pub payment_size: u64,
+    pub max_page_size: u64,
+    pub max_messages_per_response: usize,
+    pub max_profile_query: usize,
+    pub body_timeout: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Payment {
     pub timeout: u64,
     pub token_fee: u64,
+    pub message_fee: Option<u64>,
+    pub feed_fee: Option<u64>,
+    pub profile_fee: Option<u64>,
+    pub token_ttl: u64,
+    pub max_ttl_multiplier: f64,
     pub memo: String,
     pub hmac_secret: String,
+    pub dry_run: bool,
+    pub max_outputs: usize,
+    pub max_memo_length: usize,
+    pub xpub: Option<String>,
+    #[serde(default)]
+    pub rejection_memos: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Websocket {
     pub ping_interval: u64,
+    pub pong_timeout: u64,
     pub truncation_length: u64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Messages {
+    pub ttl: Option<u64>,
+    pub acked_ttl: Option<u64>,
+    pub gc_interval: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Stamps {
+    pub require_stamp: bool,
+    pub min_stamp_value: u64,
+    pub broadcast: bool,
+    pub fee_rate_multiplier: Option<f64>,
+    pub fee_rate_ttl: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimits {
+    pub window: u64,
+    pub address_limit: usize,
+    pub ip_limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Admin {
+    pub api_key: Option<String>,
+    pub protect_metrics: bool,
+    pub backup_dir: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Moderation {
+    pub denylist: Vec<String>,
+    pub denylist_file: Option<String>,
+    pub allowlist: Option<Vec<String>>,
+    pub allowlist_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
+}
+
+/// RocksDB tuning, unset values keep the RocksDB defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RocksDb {
+    pub write_buffer_size: Option<usize>,
+    pub max_open_files: Option<i32>,
+    pub block_cache_size: Option<usize>,
+    pub compression: Option<Compression>,
+    pub sync_writes: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Arguments of the `token` subcommand.
+#[derive(Debug)]
+pub struct MintToken {
+    pub address: String,
+    pub scope: Scope,
+    /// Token lifetime in seconds.
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub bind: SocketAddr,
     #[cfg(feature = "monitoring")]
     pub bind_prom: SocketAddr,
     pub db_path: String,
+    pub static_dir: String,
+    pub serve_static: bool,
+    pub compression: bool,
+    pub repair_db: bool,
+    #[serde(default)]
+    pub rocksdb: RocksDb,
+    pub log_format: LogFormat,
     pub network: Network,
     pub bitcoin_rpc: BitcoinRpc,
     pub limits: Limits,
     pub payments: Payment,
     pub websocket: Websocket,
+    pub messages: Messages,
+    pub stamps: Stamps,
+    pub rate_limits: RateLimits,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub cors: Cors,
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub moderation: Moderation,
+    #[serde(skip)]
+    pub mint_token: Option<MintToken>,
+    #[serde(skip)]
+    pub selftest: bool,
 }
 
 impl Settings {
@@ -71,11 +253,16 @@ impl Settings {
         // Set defaults
         let yaml = load_yaml!("cli.yml");
         #[allow(deprecated)]
-        let matches = App::from_yaml(yaml)
+        let app = App::from_yaml(yaml)
             .about(crate_description!())
             .author(crate_authors!("\n"))
-            .version(crate_version!())
-            .get_matches();
+            .version(crate_version!());
+
+        // Don't interpret the test harness arguments
+        #[cfg(not(test))]
+        let matches = app.get_matches();
+        #[cfg(test)]
+        let matches = app.get_matches_from([crate_name!()]);
         let home_dir = match dirs::home_dir() {
             Some(some) => some,
             None => return Err(ConfigError::Message("no home directory".to_string())),
@@ -87,26 +274,77 @@ impl Settings {
         let mut default_db = home_dir.clone();
         default_db.push(format!("{}/db", FOLDER_DIR));
         s.set_default("db_path", default_db.to_str())?;
+        s.set_default("static_dir", DEFAULT_STATIC_DIR)?;
+        s.set_default("serve_static", DEFAULT_SERVE_STATIC)?;
+        s.set_default("compression", DEFAULT_COMPRESSION)?;
+        s.set_default("repair_db", false)?;
+        s.set_default("log_format", DEFAULT_LOG_FORMAT)?;
         s.set_default("bitcoin_rpc.address", DEFAULT_RPC_ADDR)?;
+        s.set_default("bitcoin_rpc.fallback_addresses", Vec::<String>::new())?;
         s.set_default("bitcoin_rpc.username", DEFAULT_RPC_USER)?;
         s.set_default("bitcoin_rpc.password", DEFAULT_RPC_PASSWORD)?;
+        s.set_default("bitcoin_rpc.max_retries", DEFAULT_RPC_MAX_RETRIES as i64)?;
+        s.set_default("bitcoin_rpc.base_delay", DEFAULT_RPC_BASE_DELAY as i64)?;
+        s.set_default(
+            "bitcoin_rpc.pool_max_idle",
+            DEFAULT_RPC_POOL_MAX_IDLE as i64,
+        )?;
+        s.set_default(
+            "bitcoin_rpc.pool_idle_timeout",
+            DEFAULT_RPC_POOL_IDLE_TIMEOUT as i64,
+        )?;
         s.set_default("limits.message_size", DEFAULT_MESSAGE_LIMIT as i64)?;
         s.set_default("limits.profile_size", DEFAULT_PROFILE_LIMIT as i64)?;
         s.set_default("limits.payment_size", DEFAULT_PAYMENT_LIMIT as i64)?;
+        s.set_default("limits.max_page_size", DEFAULT_MAX_PAGE_SIZE as i64)?;
+        s.set_default(
+            "limits.max_messages_per_response",
+            DEFAULT_MAX_MESSAGES_PER_RESPONSE as i64,
+        )?;
+        s.set_default("limits.max_profile_query", DEFAULT_MAX_PROFILE_QUERY as i64)?;
+        s.set_default("limits.body_timeout", DEFAULT_BODY_TIMEOUT as i64)?;
         s.set_default("payments.token_fee", DEFAULT_TOKEN_FEE as i64)?;
+        s.set_default("payments.token_ttl", DEFAULT_TOKEN_TTL as i64)?;
+        s.set_default("payments.max_ttl_multiplier", DEFAULT_MAX_TTL_MULTIPLIER)?;
         s.set_default("payments.memo", DEFAULT_MEMO)?;
         s.set_default("payments.timeout", DEFAULT_PAYMENT_TIMEOUT as i64)?;
+        // NOTE: Never broadcast payments from the test harness
+        #[cfg(not(test))]
+        s.set_default("payments.dry_run", DEFAULT_PAYMENT_DRY_RUN)?;
+        #[cfg(test)]
+        s.set_default("payments.dry_run", true)?;
+        s.set_default("payments.max_outputs", DEFAULT_PAYMENT_MAX_OUTPUTS as i64)?;
+        s.set_default(
+            "payments.max_memo_length",
+            DEFAULT_PAYMENT_MAX_MEMO_LENGTH as i64,
+        )?;
         s.set_default(
             "websocket.truncation_length",
             DEFAULT_TRUNCATION_LENGTH as i64,
         )?;
         s.set_default("websocket.ping_interval", DEFAULT_PING_INTERVAL as i64)?;
+        s.set_default("websocket.pong_timeout", DEFAULT_PONG_TIMEOUT as i64)?;
+        s.set_default("messages.gc_interval", DEFAULT_GC_INTERVAL as i64)?;
+        s.set_default("stamps.min_stamp_value", DEFAULT_MIN_STAMP_VALUE as i64)?;
+        s.set_default("stamps.require_stamp", DEFAULT_REQUIRE_STAMP)?;
+        s.set_default("stamps.broadcast", DEFAULT_BROADCAST_STAMPS)?;
+        s.set_default("stamps.fee_rate_ttl", DEFAULT_FEE_RATE_TTL as i64)?;
+        s.set_default("rate_limits.window", DEFAULT_RATE_LIMIT_WINDOW as i64)?;
+        s.set_default(
+            "rate_limits.address_limit",
+            DEFAULT_RATE_LIMIT_ADDRESS as i64,
+        )?;
+        s.set_default("rate_limits.ip_limit", DEFAULT_RATE_LIMIT_IP as i64)?;
+        s.set_default("trusted_proxies", Vec::<String>::new())?;
+        s.set_default("cors.allowed_origins", vec![DEFAULT_ALLOWED_ORIGIN])?;
+        s.set_default("cors.allowed_methods", DEFAULT_ALLOWED_METHODS.to_vec())?;
+        s.set_default("cors.allowed_headers", DEFAULT_ALLOWED_HEADERS.to_vec())?;
 
         // NOTE: Don't set HMAC key to a default during release for security reasons
         #[cfg(debug_assertions)]
-        {
-            s.set_default("payments.hmac_secret", "1234")?;
-        }
+        s.set_default("payments.hmac_secret", DEBUG_HMAC_SECRET)?;
+        #[cfg(not(debug_assertions))]
+        s.set_default("payments.hmac_secret", "")?;
 
         // Load config from file
         let mut default_config = home_dir;
@@ -135,6 +373,21 @@ impl Settings {
             s.set("db_path", db_path)?;
         }
 
+        // Attempt to repair a corrupted database
+        if matches.is_present("repair") {
+            s.set("repair_db", true)?;
+        }
+
+        // Set static directory from cmd line
+        if let Some(static_dir) = matches.value_of("static-dir") {
+            s.set("static_dir", static_dir)?;
+        }
+
+        // Set log format from cmd line
+        if let Some(log_format) = matches.value_of("log-format") {
+            s.set("log_format", log_format)?;
+        }
+
         // Set node IP from cmd line
         if let Some(node_ip) = matches.value_of("rpc-addr") {
             s.set("bitcoin_rpc.address", node_ip)?;
@@ -150,11 +403,132 @@ impl Settings {
             s.set("bitcoin_rpc.password", rpc_password)?;
         }
 
+        // Set rpc cookie path from cmd line
+        if let Some(rpc_cookie) = matches.value_of("rpc-cookie") {
+            s.set("bitcoin_rpc.cookie_path", rpc_cookie)?;
+        }
+
         // Set secret from cmd line
         if let Some(hmac_secret) = matches.value_of("hmac-secret") {
             s.set("payments.hmac_secret", hmac_secret)?;
         }
 
-        s.try_into()
+        let mut settings: Self = s.try_into()?;
+
+        // Mint a token instead of serving
+        if let Some(token_matches) = matches.subcommand_matches("token") {
+            let address = token_matches.value_of("address").unwrap().to_string(); // This is safe
+            let scope = token_matches
+                .value_of("scope")
+                .unwrap() // This is safe
+                .parse()
+                .map_err(|_| ConfigError::Message("unknown token scope".to_string()))?;
+            let ttl = token_matches
+                .value_of("ttl")
+                .map(str::parse)
+                .transpose()
+                .map_err(|_| ConfigError::Message("malformed token ttl".to_string()))?;
+            settings.mint_token = Some(MintToken {
+                address,
+                scope,
+                ttl,
+            });
+        }
+
+        // Run the self-test against a scratch database instead of serving
+        if matches.subcommand_matches("selftest").is_some() {
+            if settings.network != Network::Regtest {
+                return Err(ConfigError::Message(
+                    "selftest requires the regtest network".to_string(),
+                ));
+            }
+            let scratch_db = env::temp_dir().join(format!("relay-selftest-{}", process::id()));
+            settings.db_path = scratch_db.to_string_lossy().into_owned();
+            settings.selftest = true;
+        }
+
+        // Require both the certificate and the key when TLS is configured
+        if let Some(tls) = &settings.tls {
+            if tls.cert_path.is_some() != tls.key_path.is_some() {
+                return Err(ConfigError::Message(
+                    "tls requires both cert_path and key_path".to_string(),
+                ));
+            }
+        }
+
+        // Check the RPC proxy is well-formed
+        if let Some(proxy) = &settings.bitcoin_rpc.proxy {
+            if let Err(err) = Proxy::parse(proxy) {
+                return Err(ConfigError::Message(format!(
+                    "malformed bitcoin_rpc.proxy: {}",
+                    err
+                )));
+            }
+        }
+
+        // Check the CORS origins, methods and headers are well-formed
+        for origin in &settings.cors.allowed_origins {
+            if origin != "*" && !is_origin(origin) {
+                return Err(ConfigError::Message(format!(
+                    "malformed cors.allowed_origins entry: {}",
+                    origin
+                )));
+            }
+        }
+        for method in &settings.cors.allowed_methods {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(ConfigError::Message(format!(
+                    "malformed cors.allowed_methods entry: {}",
+                    method
+                )));
+            }
+        }
+        for header in &settings.cors.allowed_headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(ConfigError::Message(format!(
+                    "malformed cors.allowed_headers entry: {}",
+                    header
+                )));
+            }
+        }
+
+        // NOTE: Require an operator chosen HMAC key in release builds
+        if !cfg!(debug_assertions)
+            && (settings.payments.hmac_secret.is_empty()
+                || settings.payments.hmac_secret == DEBUG_HMAC_SECRET)
+        {
+            return Err(ConfigError::Message(
+                "payments.hmac_secret must be set to a secret hex key in release builds"
+                    .to_string(),
+            ));
+        }
+
+        // NOTE: Never accept unbroadcast payments on mainnet in release builds
+        if settings.payments.dry_run
+            && settings.network == Network::Mainnet
+            && !cfg!(debug_assertions)
+        {
+            return Err(ConfigError::Message(
+                "payments dry run is not allowed on mainnet in release builds".to_string(),
+            ));
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Check an origin is a scheme and authority, as CORS requires.
+fn is_origin(origin: &str) -> bool {
+    if !origin.contains("://") {
+        return false;
+    }
+    match origin.parse::<Uri>().map(Uri::into_parts) {
+        Ok(Parts {
+            scheme: Some(_),
+            authority: Some(_),
+            path_and_query,
+            ..
+        }) => path_and_query.map_or(true, |path_and_query| path_and_query == "/"),
+        _ => false,
     }
 }
@@ -0,0 +1,324 @@
+use cashweb::{
+    bitcoin::{
+        bip32::{ChildNumber, ExtendedPublicKey},
+        transaction::{DecodeError as TransactionDecodeError, Transaction},
+        Decodable,
+    },
+    relay::stamp::{Stamp, StampType},
+    secp256k1::{
+        key::{PublicKey, SecretKey as PrivateKey},
+        Secp256k1,
+    },
+};
+use ring::digest::{digest, SHA256};
+use ripemd160::{Digest, Ripemd160};
+use thiserror::Error;
+
+/// Error associated with verification of stamps.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum StampError {
+    #[error("failed to decode transaction: {0}")]
+    Decode(TransactionDecodeError),
+    #[error("missing output")]
+    MissingOutput,
+    #[error(
+        "degenerate pubkey combination: destination public key and payload digest sum to the point \
+         at infinity, retry with a different payload"
+    )]
+    DegenerateCombination,
+    #[error("child number is too large")]
+    ChildNumberOverflow,
+    #[error("unsupported stamp type")]
+    UnsupportedStampType,
+    #[error("stamp type is none")]
+    NoneType,
+    #[error("insufficient stamp value: {0} < {1}")]
+    InsufficientValue(u64, u64),
+    #[error("stamp transaction rejected by node: {0}")]
+    Rejected(String),
+}
+
+/// Stamp types which are verified, other types are rejected as unsupported.
+pub const SUPPORTED_STAMP_TYPES: [StampType; 1] = [StampType::MessageCommitment];
+
+pub fn stamp_type_name(stamp_type: StampType) -> &'static str {
+    match stamp_type {
+        StampType::None => "none",
+        StampType::MessageCommitment => "message_commitment",
+    }
+}
+
+/// Calculate the HASH160 of a serialized public key.
+pub fn pubkey_hash(raw_public_key: &[u8]) -> Vec<u8> {
+    let sha256_digest = digest(&SHA256, raw_public_key);
+    Ripemd160::digest(sha256_digest.as_ref()).to_vec()
+}
+
+/// Calculate the minimum stamp value for stamp transactions of `tx_size` bytes, given the fee rate
+/// in satoshis per kilobyte.
+pub fn fee_rate_minimum(tx_size: usize, fee_rate: u64, multiplier: f64) -> u64 {
+    (tx_size as f64 * fee_rate as f64 / 1_000.0 * multiplier).ceil() as u64
+}
+
+/// Verify that the stamp covers the payload digest and that the outputs paying to the derived
+/// stamp keys carry at least `min_stamp_value` satoshis in total.
+///
+/// Outputs which don't pay to a derived stamp key are ignored.
+pub fn verify_stamp(
+    stamp: &Stamp,
+    payload_digest: &[u8; 32],
+    destination_public_key: &PublicKey,
+    min_stamp_value: u64,
+) -> Result<Vec<Transaction>, StampError> {
+    let stamp_type =
+        StampType::from_i32(stamp.stamp_type).ok_or(StampError::UnsupportedStampType)?;
+    if stamp_type == StampType::None {
+        return Err(StampError::NoneType);
+    }
+    if !SUPPORTED_STAMP_TYPES.contains(&stamp_type) {
+        return Err(StampError::UnsupportedStampType);
+    }
+
+    // Calculate master pubkey
+    let payload_secret_key = PrivateKey::from_slice(payload_digest).unwrap(); // This is safe
+    let payload_public_key =
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &payload_secret_key);
+
+    // Combining valid keys only fails when they are inverses of each other
+    let combined_key = destination_public_key
+        .combine(&payload_public_key)
+        .map_err(|_| StampError::DegenerateCombination)?;
+    let master_pk = ExtendedPublicKey::new_master(combined_key, *payload_digest);
+
+    // Calculate intermediate child
+    let context = Secp256k1::verification_only();
+    let intermediate_child = master_pk
+        .derive_public_path(
+            &context,
+            &[
+                ChildNumber::from_normal_index(44).unwrap(),
+                ChildNumber::from_normal_index(145).unwrap(),
+            ],
+        )
+        .unwrap(); // This is safe
+
+    let mut txs = Vec::with_capacity(stamp.stamp_outpoints.len());
+    let mut n_matched = 0;
+    let mut total_value: u64 = 0;
+    for (tx_num, outpoint) in stamp.stamp_outpoints.iter().enumerate() {
+        let tx =
+            Transaction::decode(&mut outpoint.stamp_tx.as_slice()).map_err(StampError::Decode)?;
+
+        // Calculate transaction child
+        let child_number = ChildNumber::from_normal_index(tx_num as u32)
+            .map_err(|_| StampError::ChildNumberOverflow)?;
+        let tx_child = intermediate_child
+            .derive_public_child(&context, child_number)
+            .unwrap(); // TODO: Double check this is safe
+
+        // Derive expected pubkey hashes, wallets may pay to either the compressed or the
+        // uncompressed serialization of the child key
+        let expected_hashes = (0..outpoint.vouts.len())
+            .map(|index| {
+                let child_number = ChildNumber::from_normal_index(index as u32)
+                    .map_err(|_| StampError::ChildNumberOverflow)?;
+                let child_key = tx_child
+                    .derive_public_child(&context, child_number)
+                    .unwrap(); // TODO: Double check this is safe
+                let child_public_key = child_key.get_public_key();
+                let compressed_hash = pubkey_hash(&child_public_key.serialize());
+                let uncompressed_hash = pubkey_hash(&child_public_key.serialize_uncompressed());
+                Ok(vec![compressed_hash, uncompressed_hash])
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+
+        // Sum the value of matching outputs, ignoring the rest
+        for output in &tx.outputs {
+            let script = &output.script;
+            if !script.is_p2pkh() {
+                continue;
+            }
+            let pubkey_hash = &script.as_bytes()[3..23]; // This is safe as we've checked it's a p2pkh
+            if expected_hashes
+                .iter()
+                .any(|expected_hash| &expected_hash[..] == pubkey_hash)
+            {
+                n_matched += 1;
+                total_value = total_value.saturating_add(output.value);
+            }
+        }
+
+        txs.push(tx);
+    }
+
+    if n_matched == 0 {
+        return Err(StampError::MissingOutput);
+    }
+
+    // Check value
+    if total_value < min_stamp_value {
+        return Err(StampError::InsufficientValue(total_value, min_stamp_value));
+    }
+
+    Ok(txs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cashweb::{
+        bitcoin::{
+            transaction::{Output, Script},
+            Encodable,
+        },
+        relay::stamp::{create_stamp_private_keys, StampOutpoints},
+    };
+
+    const PAYLOAD_DIGEST: [u8; 32] = [7; 32];
+
+    fn p2pkh_script(raw_public_key: &[u8]) -> Script {
+        let mut raw_script = vec![0x76, 0xa9, 0x14];
+        raw_script.extend_from_slice(&pubkey_hash(raw_public_key));
+        raw_script.extend_from_slice(&[0x88, 0xac]);
+        raw_script.into()
+    }
+
+    fn create_stamp(values: &[u64], extra_outputs: Vec<Output>) -> (Stamp, PublicKey) {
+        create_stamp_with(values, extra_outputs, |public_key| {
+            public_key.serialize().to_vec()
+        })
+    }
+
+    fn create_stamp_with(
+        values: &[u64],
+        extra_outputs: Vec<Output>,
+        serialize: fn(&PublicKey) -> Vec<u8>,
+    ) -> (Stamp, PublicKey) {
+        let context = Secp256k1::signing_only();
+        let destination_private_key = PrivateKey::from_slice(&[1; 32]).unwrap();
+        let destination_public_key = PublicKey::from_secret_key(&context, &destination_private_key);
+
+        let private_keys = create_stamp_private_keys(
+            destination_private_key,
+            &PAYLOAD_DIGEST,
+            vec![values.len() as u32],
+        )
+        .unwrap();
+        let mut outputs = extra_outputs;
+        outputs.extend(
+            private_keys[0]
+                .iter()
+                .zip(values)
+                .map(|(private_key, value)| Output {
+                    value: *value,
+                    script: p2pkh_script(&serialize(&PublicKey::from_secret_key(
+                        &context,
+                        private_key,
+                    ))),
+                }),
+        );
+        let tx = Transaction {
+            outputs,
+            ..Default::default()
+        };
+        let mut stamp_tx = Vec::with_capacity(tx.encoded_len());
+        tx.encode(&mut stamp_tx).unwrap();
+
+        let stamp = Stamp {
+            stamp_type: StampType::MessageCommitment as i32,
+            stamp_outpoints: vec![StampOutpoints {
+                stamp_tx,
+                vouts: (0..values.len() as u32).collect(),
+            }],
+        };
+        (stamp, destination_public_key)
+    }
+
+    #[test]
+    fn sufficient_value() {
+        let (stamp, destination_public_key) = create_stamp(&[1_000, 2_000], vec![]);
+        let txs = verify_stamp(&stamp, &PAYLOAD_DIGEST, &destination_public_key, 3_000).unwrap();
+        assert_eq!(txs.len(), 1);
+    }
+
+    #[test]
+    fn uncompressed_keys() {
+        let (stamp, destination_public_key) =
+            create_stamp_with(&[1_000, 2_000], vec![], |public_key| {
+                public_key.serialize_uncompressed().to_vec()
+            });
+        let txs = verify_stamp(&stamp, &PAYLOAD_DIGEST, &destination_public_key, 3_000).unwrap();
+        assert_eq!(txs.len(), 1);
+    }
+
+    #[test]
+    fn insufficient_value() {
+        let (stamp, destination_public_key) = create_stamp(&[1_000, 500], vec![]);
+        let err =
+            verify_stamp(&stamp, &PAYLOAD_DIGEST, &destination_public_key, 2_000).unwrap_err();
+        assert_eq!(err, StampError::InsufficientValue(1_500, 2_000));
+    }
+
+    #[test]
+    fn ignore_unrelated_outputs() {
+        let change_key = PublicKey::from_secret_key(
+            &Secp256k1::signing_only(),
+            &PrivateKey::from_slice(&[2; 32]).unwrap(),
+        );
+        let extra_outputs = vec![
+            Output {
+                value: 10_000,
+                script: vec![0x6a].into(),
+            },
+            Output {
+                value: 10_000,
+                script: p2pkh_script(&change_key.serialize()),
+            },
+        ];
+        let (stamp, destination_public_key) = create_stamp(&[1_000], extra_outputs);
+        let err =
+            verify_stamp(&stamp, &PAYLOAD_DIGEST, &destination_public_key, 2_000).unwrap_err();
+        assert_eq!(err, StampError::InsufficientValue(1_000, 2_000));
+    }
+
+    #[test]
+    fn fee_rate_scaling() {
+        assert_eq!(fee_rate_minimum(250, 1_000, 1.0), 250);
+        assert_eq!(fee_rate_minimum(250, 1_000, 2.5), 625);
+        assert_eq!(fee_rate_minimum(225, 1_001, 1.0), 226);
+        assert_eq!(fee_rate_minimum(0, 1_000, 10.0), 0);
+    }
+
+    #[test]
+    fn degenerate_combination() {
+        // The payload key is the negation of the destination key when the scalars sum to the order
+        let destination_public_key = PublicKey::from_secret_key(
+            &Secp256k1::signing_only(),
+            &PrivateKey::from_slice(&[
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 1,
+            ])
+            .unwrap(),
+        );
+        let payload_digest = [
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xfe, 0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c,
+            0xd0, 0x36, 0x41, 0x40,
+        ];
+        let stamp = Stamp {
+            stamp_type: StampType::MessageCommitment as i32,
+            stamp_outpoints: vec![],
+        };
+        let err = verify_stamp(&stamp, &payload_digest, &destination_public_key, 0).unwrap_err();
+        assert_eq!(err, StampError::DegenerateCombination);
+    }
+
+    #[test]
+    fn missing_output() {
+        let (stamp, destination_public_key) = create_stamp(&[], vec![]);
+        let err = verify_stamp(&stamp, &PAYLOAD_DIGEST, &destination_public_key, 0).unwrap_err();
+        assert_eq!(err, StampError::MissingOutput);
+    }
+}